@@ -2,18 +2,27 @@ mod linked_list;
 pub mod util;   // randomly bumping up vis
 
 mod bin;
+#[allow(dead_code)]
 mod bump;
+mod frame;
+mod slab;
 
-type AllocatorImpl = bump::Allocator;
+pub use self::frame::FrameAllocator;
+pub use self::slab::{SlabBox, SlabCache, SlabStats};
+
+type AllocatorImpl = bin::Allocator;
 
 #[cfg(test)]
 mod tests;
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
 use core::fmt;
+use core::ptr;
 
-use crate::console::kprintln;
-use crate::mutex::Mutex;
+use crate::mutex::{IrqMutex, Rank};
+use crate::param::PAGE_SIZE;
+use crate::FRAMES;
 use pi::atags::{Atag, Atags};
 
 /// `LocalAlloc` is an analogous trait to the standard library's `GlobalAlloc`,
@@ -23,8 +32,16 @@ pub trait LocalAlloc {
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
 }
 
+/// The number of frames the kernel heap takes from `FRAMES` at least when it
+/// runs out of memory.
+const HEAP_GROWTH: usize = 16;
+
 /// Thread-safe (locking) wrapper around a particular memory allocator.
-pub struct Allocator(Mutex<Option<AllocatorImpl>>);
+///
+/// The allocator serves memory from frames of `FRAMES`: it starts out with
+/// `HEAP_GROWTH` of them and takes more whenever a request does not fit.
+/// Frames are never given back.
+pub struct Allocator(IrqMutex<Option<AllocatorImpl>>);
 
 impl Allocator {
     /// Returns an uninitialized `Allocator`.
@@ -32,26 +49,40 @@ impl Allocator {
     /// The allocator must be initialized by calling `initialize()` before the
     /// first memory allocation. Failure to do will result in panics.
     pub const fn uninitialized() -> Self {
-        Allocator(Mutex::new(None))
+        Allocator(IrqMutex::ranked(None, Rank::Allocator))
     }
 
     /// Initializes the memory allocator.
     /// The caller should assure that the method is invoked only once during the
-    /// kernel initialization.
+    /// kernel initialization, after `FRAMES` is initialized.
     ///
     /// # Panics
     ///
-    /// Panics if the system's memory map could not be retrieved.
+    /// Panics if no frames could be allocated for the heap.
     pub unsafe fn initialize(&self) {
-        let (start, end) = memory_map().expect("failed to find memory map");
-        kprintln!("memory {:#0x} :: {:#0x}", start, end);
-        *self.0.lock() = Some(AllocatorImpl::new(start, end));
+        let start = FRAMES.alloc(HEAP_GROWTH, PAGE_SIZE).expect("no memory for the kernel heap");
+        *self.0.lock() = Some(AllocatorImpl::new(start, start + HEAP_GROWTH * PAGE_SIZE));
     }
 }
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0.lock().as_mut().expect("allocator uninitialized").alloc(layout)
+        let mut heap = self.0.lock();
+        let heap = heap.as_mut().expect("allocator uninitialized");
+
+        let ptr = heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        let count = max(util::align_up(layout.size(), PAGE_SIZE) / PAGE_SIZE, HEAP_GROWTH);
+        match FRAMES.alloc(count, layout.align()) {
+            Some(start) => {
+                heap.add_region(start, start + count * PAGE_SIZE);
+                heap.alloc(layout)
+            }
+            None => ptr::null_mut(),
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
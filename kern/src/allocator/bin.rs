@@ -1,44 +1,166 @@
 use core::alloc::Layout;
+use core::cmp::{max, min};
 use core::fmt;
 use core::ptr;
 
 use crate::allocator::linked_list::LinkedList;
 use crate::allocator::util::*;
 use crate::allocator::LocalAlloc;
-
-use crate::console::kprintln;
+use crate::param::PAGE_SIZE;
 
 /// A simple allocator that allocates based on size classes.
+///
+/// Requests of up to half a page are served from size classes:
 ///   bin 0 (2^3 bytes)    : handles allocations in (0, 2^3]
 ///   bin 1 (2^4 bytes)    : handles allocations in (2^3, 2^4]
 ///   ...
-///   bin 29 (2^22 bytes): handles allocations in (2^31, 2^32]
+///   bin 12 (2^15 bytes)  : handles allocations in (2^14, 2^15]
 ///
-///   map_to_bin(size) -> k
+/// where the size of a request is the larger of its size and its alignment.
+/// Every block of a bin is aligned to its own size, so any request that fits
+/// a bin is aligned as well. An empty bin is refilled by splitting a block of
+/// the next larger non-empty bin, or a fresh page, in halves. Freed blocks go
+/// back to their bin and are not merged again.
 ///
-
-const MAX_BINS: usize = 32;
-
+/// Larger requests are served in whole pages from a list of runs of free
+/// pages, first fit. Freed runs are merged with the free runs next to them,
+/// so that memory handed out in pages can be handed out again in any size.
 pub struct Allocator {
-    // FIXME: Add the necessary fields.
-    bins: [LinkedList; MAX_BINS],
-    current: usize,
-    end: usize,
+    /// The free blocks of each size class.
+    bins: [LinkedList; SMALL_BINS],
+    /// The runs of free pages. The first word of a run links it into the
+    /// list and the second holds its length in bytes.
+    runs: LinkedList,
 }
 
+/// The number of size classes: the classes go up to half a page.
+const SMALL_BINS: usize = (PAGE_SIZE.trailing_zeros() - 3) as usize;
+
 impl Allocator {
     /// Creates a new bin allocator that will allocate memory from the region
     /// starting at address `start` and ending at address `end`.
+    ///
+    /// The whole pages in the region become a run of free pages, and what is
+    /// left before and after them is cut into blocks for the bins.
     pub fn new(start: usize, end: usize) -> Allocator {
-        kprintln!("Using BIN allocator");
-        Allocator {
-            bins: [LinkedList::new(); MAX_BINS],
-            current: start,
-            end,
+        let mut allocator = Allocator { bins: [LinkedList::new(); SMALL_BINS], runs: LinkedList::new() };
+        unsafe { allocator.add_region(start, end) };
+        allocator
+    }
+
+    /// Adds the memory from address `start` to address `end` to the memory
+    /// this allocator allocates from, the same way `new` does. Whole pages
+    /// are merged with the free runs next to them.
+    ///
+    /// # Safety
+    ///
+    /// The region must not overlap any memory this allocator already owns.
+    pub unsafe fn add_region(&mut self, start: usize, end: usize) {
+        let first_page = align_up(start, PAGE_SIZE);
+        let last_page = align_down(end, PAGE_SIZE);
+        if first_page < last_page {
+            self.carve(start, first_page);
+            self.dealloc_pages(first_page, last_page - first_page);
+            self.carve(last_page, end);
+        } else {
+            self.carve(start, end);
         }
     }
+
+    /// Cuts `[start, end)` into the largest blocks that are aligned to their
+    /// size and fit a bin, and puts them on their bins. Bytes at the edges
+    /// that do not make a whole 8-byte block are left unused.
+    unsafe fn carve(&mut self, start: usize, end: usize) {
+        let mut addr = align_up(start, size_for_bin(0));
+        while addr + size_for_bin(0) <= end {
+            let bin = (0..SMALL_BINS)
+                .rev()
+                .find(|&bin| addr % size_for_bin(bin) == 0 && addr + size_for_bin(bin) <= end)
+                .unwrap();
+            self.bins[bin].push(addr as *mut usize);
+            addr += size_for_bin(bin);
+        }
+    }
+
+    /// Takes a block off `bin`, splitting a larger block or a fresh page if
+    /// the bin is empty. The upper halves split off go on the smaller bins.
+    unsafe fn alloc_small(&mut self, bin: usize) -> Option<usize> {
+        let (block, mut from) = match (bin..SMALL_BINS).find(|&b| !self.bins[b].is_empty()) {
+            Some(from) => (self.bins[from].pop()? as usize, from),
+            None => (self.alloc_pages(PAGE_SIZE, PAGE_SIZE)?, SMALL_BINS),
+        };
+
+        while from > bin {
+            from -= 1;
+            self.bins[from].push((block + size_for_bin(from)) as *mut usize);
+        }
+        Some(block)
+    }
+
+    /// Takes `len` bytes of pages aligned to `align` from the first run of
+    /// free pages that has room for them. What is left of the run before and
+    /// after them stays free.
+    unsafe fn alloc_pages(&mut self, len: usize, align: usize) -> Option<usize> {
+        let mut found = None;
+        for node in self.runs.iter_mut() {
+            let (run, run_len) = (node.value() as usize, run_length(node.value()));
+            let start = align_up(run, align);
+            if start < run + run_len && run + run_len - start >= len {
+                node.pop();
+                found = Some((run, run_len, start));
+                break;
+            }
+        }
+
+        let (run, run_len, start) = found?;
+        if start > run {
+            self.push_run(run, start - run);
+        }
+        if start + len < run + run_len {
+            self.push_run(start + len, run + run_len - (start + len));
+        }
+        Some(start)
+    }
+
+    /// Gives the pages `[addr, addr + len)` back, merged with the free runs
+    /// right before and after them.
+    unsafe fn dealloc_pages(&mut self, mut addr: usize, mut len: usize) {
+        // Popping a node leaves the iterator behind it stale, so the list is
+        // walked again after each merge. A run has at most two neighbours.
+        loop {
+            let mut merged = false;
+            for node in self.runs.iter_mut() {
+                let (run, run_len) = (node.value() as usize, run_length(node.value()));
+                if run + run_len == addr || addr + len == run {
+                    node.pop();
+                    addr = min(addr, run);
+                    len += run_len;
+                    merged = true;
+                    break;
+                }
+            }
+            if !merged {
+                break;
+            }
+        }
+        self.push_run(addr, len);
+    }
+
+    /// Puts the run of free pages `[addr, addr + len)` on the run list as it
+    /// is.
+    unsafe fn push_run(&mut self, addr: usize, len: usize) {
+        self.runs.push(addr as *mut usize);
+        *(addr as *mut usize).add(1) = len;
+    }
+}
+
+/// Returns the length in bytes of the free run starting at `run`.
+unsafe fn run_length(run: *mut usize) -> usize {
+    *run.add(1)
 }
 
+/// Returns the bin for requests of `size` bytes. Bins from `SMALL_BINS` on
+/// are served in pages.
 fn map_to_bin(size: usize) -> usize {
     let mut b = 3;
     while size > (0x1 << b) {
@@ -55,6 +177,13 @@ fn power_of_two(n: usize) -> bool {
     (n & (n - 1)) == 0
 }
 
+/// Returns the bin `layout` is served from, and the number of bytes of pages
+/// it takes if that is not a size class.
+fn classify(layout: &Layout) -> (usize, usize) {
+    let bin = map_to_bin(max(layout.size(), layout.align()));
+    (bin, align_up(layout.size(), PAGE_SIZE))
+}
+
 impl LocalAlloc for Allocator {
     /// Allocates memory. Returns a pointer meeting the size and alignment
     /// properties of `layout.size()` and `layout.align()`.
@@ -80,24 +209,11 @@ impl LocalAlloc for Allocator {
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
         assert!(power_of_two(layout.align()));
 
-        let bin = map_to_bin(core::cmp::max(layout.size(), layout.align()));
-        if bin >= MAX_BINS {
-            return ptr::null_mut();
-        }
-        for node in self.bins[bin].iter_mut() {
-            if node.value() as usize % layout.align() == 0 {
-                return node.pop() as *mut u8;
-            }
-        }
-
-        let alloc_size = size_for_bin(bin);
-        let start = align_up(self.current, layout.align());
-        if start + alloc_size > self.end {
-            return ptr::null_mut();
-        }
-
-        self.current = start + alloc_size;
-        return start as *mut u8;
+        let block = match classify(&layout) {
+            (bin, _) if bin < SMALL_BINS => self.alloc_small(bin),
+            (_, len) => self.alloc_pages(len, max(layout.align(), PAGE_SIZE)),
+        };
+        block.map(|block| block as *mut u8).unwrap_or(ptr::null_mut())
     }
 
     /// Deallocates the memory referenced by `ptr`.
@@ -116,18 +232,25 @@ impl LocalAlloc for Allocator {
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         assert!(power_of_two(layout.align()));
 
-        let bin = map_to_bin(core::cmp::max(layout.size(), layout.align()));
-        assert!(bin < MAX_BINS);
-        self.bins[bin].push(ptr as *mut usize);
+        match classify(&layout) {
+            (bin, _) if bin < SMALL_BINS => self.bins[bin].push(ptr as *mut usize),
+            (_, len) => self.dealloc_pages(ptr as usize, len),
+        }
     }
 }
 
-// FIXME: Implement `Debug` for `Allocator`.
 impl fmt::Debug for Allocator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut free = [0; SMALL_BINS];
+        for (count, bin) in free.iter_mut().zip(self.bins.iter()) {
+            *count = bin.iter().count();
+        }
+        let pages: usize = self.runs.iter().map(|run| unsafe { run_length(run) } / PAGE_SIZE).sum();
+
         f.debug_struct("Allocator")
-            .field("current", &self.current)
-            .field("end", &self.end)
+            .field("free blocks by bin", &free)
+            .field("free runs", &self.runs.iter().count())
+            .field("free pages", &pages)
             .finish()
     }
 }
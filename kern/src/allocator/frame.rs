@@ -0,0 +1,192 @@
+use core::cmp::{max, min};
+use core::fmt;
+use core::slice;
+
+use crate::allocator::memory_map;
+use crate::allocator::util::*;
+use crate::console::kprintln;
+use crate::mutex::{IrqMutex, Rank};
+use crate::param::PAGE_SIZE;
+
+/// Thread-safe (locking) wrapper around the physical page frame allocator.
+///
+/// Every page of memory outside of the kernel image is handed out from here,
+/// one or more `PAGE_SIZE` frames at a time: user pages, page tables, and the
+/// memory the kernel heap (`ALLOCATOR`) carves its objects from.
+pub struct FrameAllocator(IrqMutex<Option<Frames>>);
+
+impl FrameAllocator {
+    /// Returns an uninitialized `FrameAllocator`.
+    ///
+    /// The frame allocator must be initialized by calling `initialize()`
+    /// before the kernel heap is initialized.
+    pub const fn uninitialized() -> Self {
+        FrameAllocator(IrqMutex::ranked(None, Rank::Frames))
+    }
+
+    /// Initializes the frame allocator over the memory reported by
+    /// `memory_map()`. The caller should assure that the method is invoked
+    /// only once during the kernel initialization.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the system's memory map could not be retrieved.
+    pub unsafe fn initialize(&self) {
+        let (start, end) = memory_map().expect("failed to find memory map");
+        kprintln!("memory {:#0x} :: {:#0x}", start, end);
+        *self.0.lock() = Some(Frames::new(start, end));
+    }
+
+    /// Allocates `count` contiguous frames starting at an address aligned to
+    /// `align` and returns that address. Every frame has a reference count
+    /// of one. The frames are not zeroed.
+    ///
+    /// Returns `None` if there is no such run of free frames.
+    pub fn alloc(&self, count: usize, align: usize) -> Option<usize> {
+        self.critical(|frames| frames.alloc(count, align))
+    }
+
+    /// Takes another reference to the frame at `addr`, so that it stays
+    /// allocated until it is released once more.
+    pub fn share(&self, addr: usize) {
+        self.critical(|frames| frames.share(addr))
+    }
+
+    /// Drops a reference to each of the `count` frames starting at `addr`.
+    /// Frames no longer referenced become free.
+    pub fn release(&self, addr: usize, count: usize) {
+        self.critical(|frames| frames.release(addr, count))
+    }
+
+    fn critical<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Frames) -> R,
+    {
+        f(self.0.lock().as_mut().expect("frame allocator uninitialized"))
+    }
+}
+
+impl fmt::Debug for FrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.lock().as_ref() {
+            Some(frames) => write!(f, "{:?}", frames),
+            None => write!(f, "Not yet initialized"),
+        }
+    }
+}
+
+/// The page frames of a region of physical memory and their reference
+/// counts. A frame is free while its count is zero, so the counts double as
+/// the allocation bitmap.
+pub struct Frames {
+    /// The address of the first frame.
+    base: usize,
+    /// The reference count of every frame, stored at the start of the region.
+    refs: &'static mut [u16],
+    /// No frame before this index is free.
+    first_free: usize,
+    /// The number of free frames.
+    free: usize,
+}
+
+impl Frames {
+    /// Returns the frames of the region starting at address `start` and
+    /// ending at address `end`. The reference counts are kept at `start`,
+    /// and the frames are the whole pages after them.
+    pub unsafe fn new(start: usize, end: usize) -> Frames {
+        let table = align_up(start, core::mem::align_of::<u16>());
+        let count = end.saturating_sub(table) / PAGE_SIZE;
+        let base = align_up(table + count * 2, PAGE_SIZE);
+        let count = min(count, end.saturating_sub(base) / PAGE_SIZE);
+
+        let refs = slice::from_raw_parts_mut(table as *mut u16, count);
+        for r in refs.iter_mut() {
+            *r = 0;
+        }
+        Frames { base, refs, first_free: 0, free: count }
+    }
+
+    /// Returns the address of frame `index`.
+    fn addr(&self, index: usize) -> usize {
+        self.base + index * PAGE_SIZE
+    }
+
+    /// Returns the index of the frame at `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addr` is not the address of one of these frames.
+    fn index(&self, addr: usize) -> usize {
+        assert!(addr >= self.base && addr % PAGE_SIZE == 0, "{:#x} is not a frame", addr);
+        let index = (addr - self.base) / PAGE_SIZE;
+        assert!(index < self.refs.len(), "{:#x} is not a frame", addr);
+        index
+    }
+
+    /// Takes the first run of `count` free frames whose address is aligned to
+    /// `align`.
+    pub fn alloc(&mut self, count: usize, align: usize) -> Option<usize> {
+        assert!(count > 0 && align.is_power_of_two());
+        let align = max(align, PAGE_SIZE);
+
+        let mut index = self.first_free;
+        while index + count <= self.refs.len() {
+            let addr = self.addr(index);
+            if addr % align != 0 {
+                index = (align_up(addr, align) - self.base) / PAGE_SIZE;
+                continue;
+            }
+
+            match self.refs[index..index + count].iter().rposition(|&r| r != 0) {
+                Some(used) => index += used + 1,
+                None => {
+                    for r in self.refs[index..index + count].iter_mut() {
+                        *r = 1;
+                    }
+                    self.free -= count;
+                    if index == self.first_free {
+                        self.first_free = self.refs[index..]
+                            .iter()
+                            .position(|&r| r == 0)
+                            .map_or(self.refs.len(), |i| index + i);
+                    }
+                    return Some(addr);
+                }
+            }
+        }
+        None
+    }
+
+    /// Takes another reference to the allocated frame at `addr`.
+    pub fn share(&mut self, addr: usize) {
+        let index = self.index(addr);
+        let r = &mut self.refs[index];
+        assert!(*r != 0, "sharing free frame {:#x}", addr);
+        *r = r.checked_add(1).expect("frame reference count overflow");
+    }
+
+    /// Drops a reference to each of the `count` frames starting at `addr`.
+    pub fn release(&mut self, addr: usize, count: usize) {
+        let first = self.index(addr);
+        for index in first..first + count {
+            let r = &mut self.refs[index];
+            assert!(*r != 0, "releasing free frame {:#x}", self.base + index * PAGE_SIZE);
+            *r -= 1;
+            if *r == 0 {
+                self.free += 1;
+                self.first_free = min(self.first_free, index);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Frames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frames")
+            .field("base", &format_args!("{:#x}", self.base))
+            .field("frames", &self.refs.len())
+            .field("free", &self.free)
+            .field("shared", &self.refs.iter().filter(|&&r| r > 1).count())
+            .finish()
+    }
+}
@@ -0,0 +1,282 @@
+use core::cmp::max;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{self, align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, Unique};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::allocator::linked_list::LinkedList;
+use crate::allocator::util::align_up;
+use crate::mutex::{IrqMutex, Rank};
+use crate::param::{NCORES, PAGE_SIZE};
+use crate::percore;
+use crate::FRAMES;
+
+/// The number of free objects a core keeps for itself.
+const MAGAZINE_SIZE: usize = 16;
+
+/// A cache of objects of type `T`.
+///
+/// Objects are cut out of slabs, whole frames taken from `FRAMES`, all of
+/// the same size, so kernel objects that come and go all the time do not
+/// fragment the kernel heap. Each core keeps a magazine of free objects it
+/// allocates from and frees to without touching the shared depot. Half a
+/// magazine is moved at a time when it runs empty or full.
+///
+/// Slabs are never given back to `FRAMES`, and a `T` must fit in one.
+pub struct SlabCache<T> {
+    name: &'static str,
+    /// The initial value of the objects allocated with `alloc()`.
+    ctor: Option<fn() -> T>,
+    magazines: [IrqMutex<Magazine>; NCORES],
+    depot: IrqMutex<Depot>,
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+    /// The number of allocations that went to the depot.
+    misses: AtomicUsize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Returns an empty cache named `name`, for objects only allocated with
+    /// `boxed()`.
+    pub const fn new(name: &'static str) -> SlabCache<T> {
+        SlabCache::build(name, None)
+    }
+
+    /// Returns an empty cache named `name` whose `alloc()` initializes
+    /// objects with `ctor`.
+    pub const fn with_ctor(name: &'static str, ctor: fn() -> T) -> SlabCache<T> {
+        SlabCache::build(name, Some(ctor))
+    }
+
+    const fn build(name: &'static str, ctor: Option<fn() -> T>) -> SlabCache<T> {
+        SlabCache {
+            name,
+            // Indexes out of bounds, failing the evaluation of the `static`
+            // the cache is built in, if a `T` does not fit in a slab.
+            ctor: [ctor][(size_of::<T>() > PAGE_SIZE) as usize],
+            magazines: [
+                IrqMutex::ranked(Magazine::new(), Rank::Magazine),
+                IrqMutex::ranked(Magazine::new(), Rank::Magazine),
+                IrqMutex::ranked(Magazine::new(), Rank::Magazine),
+                IrqMutex::ranked(Magazine::new(), Rank::Magazine),
+            ],
+            depot: IrqMutex::ranked(Depot::new(), Rank::Slab),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the size of the objects in the slabs: at least a word, so that
+    /// free objects can be linked, and a multiple of the alignment of `T`.
+    fn object_size() -> usize {
+        let align = max(align_of::<T>(), align_of::<usize>());
+        align_up(max(size_of::<T>(), size_of::<usize>()), align)
+    }
+
+    /// Allocates an object initialized by the constructor of the cache.
+    /// Returns `None` if memory is exhausted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache has no constructor.
+    pub fn alloc(&'static self) -> Option<SlabBox<T>> {
+        let ctor = self.ctor.expect("slab cache without a constructor");
+        self.boxed(ctor())
+    }
+
+    /// Moves `value` into an object of the cache. Returns `None` if memory is
+    /// exhausted.
+    pub fn boxed(&'static self, value: T) -> Option<SlabBox<T>> {
+        let addr = self.take()?;
+        unsafe {
+            let ptr = addr as *mut T;
+            ptr::write(ptr, value);
+            Some(SlabBox { ptr: Unique::new_unchecked(ptr), cache: self })
+        }
+    }
+
+    /// Takes a free object from the current core's magazine, refilling it
+    /// from the depot if it is empty.
+    fn take(&self) -> Option<usize> {
+        let mut magazine = self.magazines[percore::getcpu()].lock();
+        if magazine.len == 0 {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.depot.lock().refill(&mut magazine, Self::object_size())?;
+        }
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        Some(magazine.pop())
+    }
+
+    /// Puts the free object at `addr` on the current core's magazine,
+    /// flushing half of it to the depot if it is full.
+    fn put(&self, addr: usize) {
+        let mut magazine = self.magazines[percore::getcpu()].lock();
+        if magazine.len == MAGAZINE_SIZE {
+            self.depot.lock().flush(&mut magazine);
+        }
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        magazine.push(addr);
+    }
+
+    /// Returns the statistics of the cache.
+    pub fn stats(&self) -> SlabStats {
+        let allocs = self.allocs.load(Ordering::Relaxed);
+        let frees = self.frees.load(Ordering::Relaxed);
+        SlabStats {
+            name: self.name,
+            size: Self::object_size(),
+            slabs: self.depot.lock().slabs,
+            in_use: allocs.saturating_sub(frees),
+            allocs,
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The statistics of a `SlabCache`.
+#[derive(Debug, Copy, Clone)]
+pub struct SlabStats {
+    pub name: &'static str,
+    /// The size of an object in bytes.
+    pub size: usize,
+    /// The number of slabs (frames) taken.
+    pub slabs: usize,
+    /// The number of objects allocated and not freed.
+    pub in_use: usize,
+    /// The number of allocations so far.
+    pub allocs: usize,
+    /// The number of allocations that found the magazine empty.
+    pub misses: usize,
+}
+
+/// The free objects a core keeps for itself.
+struct Magazine {
+    objects: [usize; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine { objects: [0; MAGAZINE_SIZE], len: 0 }
+    }
+
+    fn push(&mut self, addr: usize) {
+        self.objects[self.len] = addr;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> usize {
+        self.len -= 1;
+        self.objects[self.len]
+    }
+}
+
+/// The free objects of a cache that are in no magazine.
+pub struct Depot {
+    free: LinkedList,
+    /// The number of slabs taken.
+    slabs: usize,
+}
+
+impl Depot {
+    pub const fn new() -> Depot {
+        Depot { free: LinkedList::new(), slabs: 0 }
+    }
+
+    /// Cuts the slab `[start, start + PAGE_SIZE)` into objects of `size`
+    /// bytes and adds them to the free objects.
+    pub unsafe fn grow(&mut self, start: usize, size: usize) {
+        for addr in (start..start + PAGE_SIZE - size + 1).step_by(size).rev() {
+            self.free.push(addr as *mut usize);
+        }
+        self.slabs += 1;
+    }
+
+    /// Moves up to half a magazine of free objects of `size` bytes to
+    /// `magazine`, taking a new slab if there are none. Returns `None` if
+    /// nothing could be moved because no slab could be taken.
+    fn refill(&mut self, magazine: &mut Magazine, size: usize) -> Option<()> {
+        if self.free.is_empty() {
+            let slab = FRAMES.alloc(1, PAGE_SIZE)?;
+            unsafe { self.grow(slab, size) };
+        }
+        let before = magazine.len;
+        while magazine.len < MAGAZINE_SIZE / 2 {
+            match self.pop() {
+                Some(addr) => magazine.push(addr),
+                None => break,
+            }
+        }
+        if magazine.len == before {
+            return None;
+        }
+        Some(())
+    }
+
+    /// Moves half of `magazine` back to the free objects.
+    fn flush(&mut self, magazine: &mut Magazine) {
+        while magazine.len > MAGAZINE_SIZE / 2 {
+            unsafe { self.push(magazine.pop()) };
+        }
+    }
+
+    /// Takes a free object, if there is one.
+    pub fn pop(&mut self) -> Option<usize> {
+        self.free.pop().map(|addr| addr as usize)
+    }
+
+    /// Gives the object at `addr` back.
+    pub unsafe fn push(&mut self, addr: usize) {
+        self.free.push(addr as *mut usize)
+    }
+}
+
+/// An object of type `T` in a `SlabCache`, freed to the cache when dropped.
+pub struct SlabBox<T: 'static> {
+    ptr: Unique<T>,
+    cache: &'static SlabCache<T>,
+}
+
+impl<T> SlabBox<T> {
+    /// Moves the object out of the cache and frees it.
+    pub fn into_inner(b: SlabBox<T>) -> T {
+        let value = unsafe { ptr::read(b.ptr.as_ptr()) };
+        b.cache.put(b.ptr.as_ptr() as usize);
+        mem::forget(b);
+        value
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        self.cache.put(self.ptr.as_ptr() as usize);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
@@ -77,6 +77,7 @@ mod allocator {
     use core::alloc::Layout;
 
     use crate::allocator::{bin, bump, LocalAlloc};
+    use crate::param::PAGE_SIZE;
 
     macro_rules! test_allocators {
         (@$kind:ident, $name:ident, $mem:expr, |$info:pat| $block:expr) => {
@@ -194,6 +195,21 @@ mod allocator {
         test_layouts!(layouts, start, end, a);
     });
 
+    test_allocators!(bin_alloc_pages, bump_alloc_pages, 32 * PAGE_SIZE, |(start, end, a)| {
+        let layouts = [
+            layout!(PAGE_SIZE, PAGE_SIZE),
+            layout!(PAGE_SIZE + 1, 8),
+            layout!(16, 4 * PAGE_SIZE),
+            layout!(3 * PAGE_SIZE, 2 * PAGE_SIZE),
+            layout!(PAGE_SIZE / 2, PAGE_SIZE / 2),
+            layout!(40000, 8),
+            layout!(16, 16),
+        ];
+
+        // Requests of a page or more are served in whole pages.
+        test_layouts!(layouts, start, end, a);
+    });
+
     fn scribble(ptr: *mut u8, size: usize) {
         unsafe {
             ::core::ptr::write_bytes(ptr, 0xAF, size);
@@ -281,6 +297,215 @@ mod allocator {
             }
         }
     });
+
+    test_allocators!(@bin, bin_dealloc_pages, 8 * PAGE_SIZE + 4096, |(_, _, mut a)| {
+        let page = layout!(PAGE_SIZE, PAGE_SIZE);
+        let mut pages = vec![];
+        loop {
+            let ptr = a.alloc(page.clone());
+            if ptr.is_null() {
+                break;
+            }
+            assert!(ptr as usize % PAGE_SIZE == 0, "{:x} is not page aligned", ptr as usize);
+            scribble(ptr, PAGE_SIZE);
+            pages.push(ptr);
+        }
+        assert!(pages.len() >= 7, "only {} pages", pages.len());
+
+        // freed pages are merged again, whatever order they are freed in
+        let n = pages.len();
+        for &ptr in pages.iter().step_by(2).chain(pages.iter().skip(1).step_by(2)) {
+            a.dealloc(ptr, page.clone());
+        }
+
+        let run = layout!(n * PAGE_SIZE, PAGE_SIZE);
+        let ptr = a.alloc(run.clone());
+        assert!(!ptr.is_null(), "{} freed pages were not merged", n);
+        scribble(ptr, n * PAGE_SIZE);
+        a.dealloc(ptr, run);
+    });
+
+    test_allocators!(@bin, bin_split_pages, 4 * PAGE_SIZE, |(_, _, mut a)| {
+        let page = layout!(PAGE_SIZE, PAGE_SIZE);
+        let mut pages = vec![];
+        loop {
+            let ptr = a.alloc(page.clone());
+            if ptr.is_null() {
+                break;
+            }
+            pages.push(ptr);
+        }
+        let n = pages.len();
+        for ptr in pages {
+            a.dealloc(ptr, page.clone());
+        }
+
+        // pages handed out whole are split up for small requests later
+        let small = layout!(1024, 1024);
+        let mut blocks = 0;
+        loop {
+            let ptr = a.alloc(small.clone());
+            if ptr.is_null() {
+                break;
+            }
+            assert!(ptr as usize % 1024 == 0, "{:x} is not aligned to 1024", ptr as usize);
+            scribble(ptr, 1024);
+            blocks += 1;
+        }
+        assert!(blocks >= n * PAGE_SIZE / 1024, "only {} blocks from {} pages", blocks, n);
+    });
+
+    test_allocators!(@bin, bin_dealloc_reuse, 2 * PAGE_SIZE, |(_, _, mut a)| {
+        let layout = layout!(48, 8);
+
+        // exhaust the allocator, then make sure everything freed is handed
+        // out again
+        let mut ptrs = vec![];
+        loop {
+            let ptr = a.alloc(layout.clone());
+            if ptr.is_null() {
+                break;
+            }
+            scribble(ptr, layout.size());
+            ptrs.push(ptr);
+        }
+        let count = ptrs.len();
+        assert!(count > 0);
+
+        for ptr in ptrs {
+            a.dealloc(ptr, layout.clone());
+        }
+        for _ in 0..count {
+            let ptr = a.alloc(layout.clone());
+            assert!(!ptr.is_null());
+            scribble(ptr, layout.size());
+        }
+    });
+}
+
+mod frame {
+    extern crate alloc;
+    use alloc::raw_vec::RawVec;
+
+    use crate::allocator::frame::Frames;
+    use crate::param::PAGE_SIZE;
+
+    macro_rules! test_frames {
+        ($name:ident, $pages:expr, |$info:pat| $block:expr) => {
+            #[test]
+            fn $name() {
+                // room for the pages, the reference counts and the alignment
+                let len = ($pages + 2) * PAGE_SIZE;
+                let mem: RawVec<u8> = RawVec::with_capacity(len);
+                let start = mem.ptr() as usize;
+                let end = start + len;
+
+                let frames = unsafe { Frames::new(start, end) };
+                let $info = (start, end, frames);
+                $block
+            }
+        };
+    }
+
+    /// Allocates single frames until there are none left.
+    fn exhaust(frames: &mut Frames) -> Vec<usize> {
+        let mut all = vec![];
+        while let Some(addr) = frames.alloc(1, PAGE_SIZE) {
+            all.push(addr);
+        }
+        all
+    }
+
+    test_frames!(frames_alloc_release, 8, |(start, end, mut frames)| {
+        let all = exhaust(&mut frames);
+        assert!(all.len() >= 8, "only {} frames", all.len());
+        for (i, &addr) in all.iter().enumerate() {
+            assert!(addr % PAGE_SIZE == 0, "{:x} is not page aligned", addr);
+            assert!(addr >= start && addr + PAGE_SIZE <= end, "{:x} out of bounds", addr);
+            assert!(!all[..i].contains(&addr), "{:x} allocated twice", addr);
+        }
+
+        frames.release(all[3], 1);
+        assert_eq!(frames.alloc(1, PAGE_SIZE), Some(all[3]));
+        assert_eq!(frames.alloc(1, PAGE_SIZE), None);
+    });
+
+    test_frames!(frames_share, 4, |(_, _, mut frames)| {
+        let all = exhaust(&mut frames);
+        frames.share(all[0]);
+
+        // a shared frame stays allocated until its last reference is gone
+        frames.release(all[0], 1);
+        assert_eq!(frames.alloc(1, PAGE_SIZE), None);
+        frames.release(all[0], 1);
+        assert_eq!(frames.alloc(1, PAGE_SIZE), Some(all[0]));
+    });
+
+    test_frames!(frames_contiguous, 16, |(_, _, mut frames)| {
+        let all = exhaust(&mut frames);
+        let n = all.len();
+
+        // free every other frame: no two free frames are next to each other
+        for &addr in all.iter().step_by(2) {
+            frames.release(addr, 1);
+        }
+        assert_eq!(frames.alloc(2, PAGE_SIZE), None);
+
+        for &addr in all.iter().skip(1).step_by(2) {
+            frames.release(addr, 1);
+        }
+        let run = frames.alloc(4, 4 * PAGE_SIZE).expect("no aligned run of 4 frames");
+        assert!(run % (4 * PAGE_SIZE) == 0, "{:x} is not aligned", run);
+        frames.release(run, 4);
+
+        let run = frames.alloc(n, PAGE_SIZE).expect("freed frames are not contiguous");
+        assert_eq!(run, all[0]);
+    });
+
+    #[test]
+    #[should_panic]
+    fn frames_double_release() {
+        let mem: RawVec<u8> = RawVec::with_capacity(4 * PAGE_SIZE);
+        let start = mem.ptr() as usize;
+        let mut frames = unsafe { Frames::new(start, start + 4 * PAGE_SIZE) };
+
+        let addr = frames.alloc(1, PAGE_SIZE).expect("no frame");
+        frames.release(addr, 1);
+        frames.release(addr, 1);
+    }
+}
+
+mod slab {
+    extern crate alloc;
+    use alloc::raw_vec::RawVec;
+
+    use crate::allocator::slab::Depot;
+    use crate::param::PAGE_SIZE;
+
+    #[test]
+    fn depot_grow() {
+        let mem: RawVec<u8> = RawVec::with_capacity(2 * PAGE_SIZE);
+        let slab = crate::allocator::util::align_up(mem.ptr() as usize, PAGE_SIZE);
+
+        // an odd size that leaves the end of the slab unused
+        let size = 816;
+        let mut depot = Depot::new();
+        unsafe { depot.grow(slab, size) };
+
+        let mut objects = vec![];
+        while let Some(addr) = depot.pop() {
+            assert!(addr >= slab && addr + size <= slab + PAGE_SIZE, "{:x} out of the slab", addr);
+            assert_eq!((addr - slab) % size, 0, "{:x} is not an object", addr);
+            assert!(!objects.contains(&addr), "{:x} handed out twice", addr);
+            objects.push(addr);
+        }
+        assert_eq!(objects.len(), PAGE_SIZE / size);
+        assert_eq!(objects[0], slab);
+
+        unsafe { depot.push(objects[5]) };
+        assert_eq!(depot.pop(), Some(objects[5]));
+        assert_eq!(depot.pop(), None);
+    }
 }
 
 mod linked_list {
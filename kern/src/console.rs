@@ -2,17 +2,35 @@ use core::fmt;
 use pi::uart::MiniUart;
 use shim::io;
 
-use crate::mutex::Mutex;
+use crate::mutex::{IrqMutex, Rank};
+use crate::sync::Condvar;
+
+/// The number of bytes of console input that are buffered.
+const INPUT_BUF: usize = 64;
+
+/// The byte Ctrl-C sends.
+pub const CTRL_C: u8 = 0x03;
 
 /// A global singleton allowing read/write access to the console.
+///
+/// Input is moved from the UART into a small buffer as it is looked for, so
+/// that a Ctrl-C can be seen, and kept aside, before the bytes typed ahead
+/// of it are read.
 pub struct Console {
     inner: Option<MiniUart>,
+    /// Input taken off the UART but not read yet, as a ring buffer of `len`
+    /// bytes starting at `head`.
+    buf: [u8; INPUT_BUF],
+    head: usize,
+    len: usize,
+    /// Whether a Ctrl-C arrived since `take_interrupt()` was last called.
+    interrupt: bool,
 }
 
 impl Console {
     /// Creates a new instance of `Console`.
     const fn new() -> Console {
-        Console { inner: None }
+        Console { inner: None, buf: [0; INPUT_BUF], head: 0, len: 0, interrupt: false }
     }
 
     /// Initializes the console if it's not already initialized.
@@ -30,9 +48,48 @@ impl Console {
         self.inner.as_mut().unwrap()
     }
 
-    /// Reads a byte from the UART device, blocking until a byte is available.
+    /// Moves the bytes waiting in the UART into the input buffer, recording
+    /// a Ctrl-C instead of buffering it. Bytes that do not fit are dropped,
+    /// so that a Ctrl-C typed after them is not held up.
+    fn fill(&mut self) {
+        while self.inner().has_byte() {
+            let b = self.inner().read_byte();
+            if b == CTRL_C {
+                self.interrupt = true;
+            } else if self.len < INPUT_BUF {
+                self.buf[(self.head + self.len) % INPUT_BUF] = b;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Reads a byte of input, blocking until a byte is available. Ctrl-C is
+    /// never returned; see `take_interrupt()`.
     pub fn read_byte(&mut self) -> u8 {
-        self.inner().read_byte()
+        while !self.has_byte() {}
+        let b = self.buf[self.head];
+        self.head = (self.head + 1) % INPUT_BUF;
+        self.len -= 1;
+        b
+    }
+
+    /// Returns `true` if a byte can be read without blocking.
+    pub fn has_byte(&mut self) -> bool {
+        self.fill();
+        self.len > 0
+    }
+
+    /// Returns `true` if a byte can be read or a Ctrl-C arrived: whether
+    /// somebody waiting for input has something to look at.
+    pub fn has_input(&mut self) -> bool {
+        self.fill();
+        self.len > 0 || self.interrupt
+    }
+
+    /// Returns `true` if a Ctrl-C arrived since this was last called.
+    pub fn take_interrupt(&mut self) -> bool {
+        self.fill();
+        core::mem::replace(&mut self.interrupt, false)
     }
 
     /// Writes the byte `byte` to the UART device.
@@ -49,7 +106,12 @@ impl fmt::Write for Console {
 
 impl io::Read for Console {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner().read(buf)
+        let mut n = 0;
+        while n < buf.len() && (n == 0 || self.has_byte()) {
+            buf[n] = self.read_byte();
+            n += 1;
+        }
+        Ok(n)
     }
 }
 
@@ -64,7 +126,12 @@ impl io::Write for Console {
 }
 
 /// Global `Console` singleton.
-pub static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
+pub static CONSOLE: IrqMutex<Console> = IrqMutex::ranked(Console::new(), Rank::Console);
+
+/// Notified when a byte or a Ctrl-C arrives on the console, for kernel
+/// threads waiting for input. The scheduler checks for input as it polls for
+/// events.
+pub static CONSOLE_INPUT: Condvar = Condvar::new();
 
 /// Internal function called by the `kprint[ln]!` macros.
 #[doc(hidden)]
@@ -1,27 +1,53 @@
 pub mod sd;
 
-use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
 use core::fmt::{self, Debug};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use shim::io;
 use shim::ioerr;
 use shim::path::Path;
 
 pub use fat32::traits;
+use fat32::traits::SectorBuf;
 use fat32::vfat::{Dir, Entry, File, VFat, VFatHandle};
 
 use self::sd::Sd;
-use crate::mutex::Mutex;
+use crate::allocator::{SlabBox, SlabCache};
+use crate::percore;
+use crate::process::Event;
+use crate::sync::{RwLock, Semaphore};
 
 #[derive(Clone)]
-pub struct PiVFatHandle(Rc<Mutex<VFat<Self>>>);
+pub struct PiVFatHandle(Arc<VFatLock>);
 
-// These impls are *unsound*. We should use `Arc` instead of `Rc` to implement
-// `Sync` and `Send` trait for `PiVFatHandle`. However, `Arc` uses atomic memory
-// access, which requires MMU to be initialized on ARM architecture. Since we
-// have enabled only one core of the board, these unsound impls will not cause
-// any immediate harm for now. We will fix this in the future.
-unsafe impl Send for PiVFatHandle {}
-unsafe impl Sync for PiVFatHandle {}
+/// The lock around the file system. `gate` is its only guard: kernel threads
+/// sleep on it in `down()`, and may be preempted while holding it.
+///
+/// System call handlers cannot sleep, and must not spin on a lock whose
+/// holder may be preempted, so they take `gate` up front with
+/// `FileSystem::try_enter()`, or block their process until it is released
+/// (see `FileSystem::waiter()`). While a handler holds `gate`, `owner` is its
+/// core's number plus one, and `lock()` on that core goes straight to `vfat`.
+/// Handlers use the file system outside of the scheduler's critical section,
+/// since `up()` wakes sleepers through the scheduler.
+struct VFatLock {
+    gate: Semaphore,
+    owner: AtomicUsize,
+    vfat: UnsafeCell<VFat<PiVFatHandle>>,
+}
+
+// `vfat` is only touched by the holder of `gate`.
+unsafe impl Send for VFatLock {}
+unsafe impl Sync for VFatLock {}
+
+impl VFatLock {
+    /// Returns the value of `owner` for a handler running on this core.
+    fn owner_tag() -> usize {
+        percore::getcpu() + 1
+    }
+}
 
 impl Debug for PiVFatHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -32,38 +58,160 @@ impl Debug for PiVFatHandle {
 use crate::console::kprintln;
 
 impl VFatHandle for PiVFatHandle {
+    type Sector = Sector;
+
     fn new(val: VFat<PiVFatHandle>) -> Self {
         kprintln!("vfat: {:?}", val);
-        PiVFatHandle(Rc::new(Mutex::new(val)))
+        PiVFatHandle(Arc::new(VFatLock {
+            gate: Semaphore::new(1),
+            owner: AtomicUsize::new(0),
+            vfat: UnsafeCell::new(val),
+        }))
     }
 
     fn lock<R>(&self, f: impl FnOnce(&mut VFat<PiVFatHandle>) -> R) -> R {
-        f(&mut self.0.lock())
+        let lock = &*self.0;
+        if lock.owner.load(Ordering::SeqCst) == VFatLock::owner_tag() {
+            return f(unsafe { &mut *lock.vfat.get() });
+        }
+
+        lock.gate.down();
+        let result = f(unsafe { &mut *lock.vfat.get() });
+        lock.gate.up();
+        result
+    }
+}
+
+/// The size of a sector of the SD card, and of the usual FAT32 partition.
+const SECTOR_SIZE: usize = 512;
+
+/// The data of a cached sector, aligned for the SD card driver.
+#[repr(C, align(4))]
+pub struct SectorData([u8; SECTOR_SIZE]);
+
+fn zeroed_sector() -> SectorData {
+    SectorData([0; SECTOR_SIZE])
+}
+
+/// The cache of the sectors the file system keeps in memory.
+pub static SECTORS: SlabCache<SectorData> = SlabCache::with_ctor("sector", zeroed_sector);
+
+/// A cached sector: an object of `SECTORS` if the partition has 512-byte
+/// sectors, and a heap buffer otherwise.
+pub enum Sector {
+    Slab(SlabBox<SectorData>),
+    Heap(Vec<u8>),
+}
+
+impl SectorBuf for Sector {
+    fn new(size: usize) -> Option<Sector> {
+        match size {
+            SECTOR_SIZE => SECTORS.alloc().map(Sector::Slab),
+            _ => <Vec<u8> as SectorBuf>::new(size).map(Sector::Heap),
+        }
+    }
+}
+
+impl AsRef<[u8]> for Sector {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Sector::Slab(data) => &data.0[..],
+            Sector::Heap(buf) => buf.as_slice(),
+        }
+    }
+}
+
+impl AsMut<[u8]> for Sector {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self {
+            Sector::Slab(data) => &mut data.0[..],
+            Sector::Heap(buf) => buf.as_mut_slice(),
+        }
+    }
+}
+
+impl Debug for Sector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sector({} bytes)", self.as_ref().len())
+    }
+}
+
+/// A process blocked by a system call handler until the file system lock is
+/// released, counted as a waiter on the lock as long as this lives.
+pub struct Waiter(PiVFatHandle);
+
+impl Waiter {
+    /// Returns the event the release of the lock wakes.
+    pub fn event(&self) -> Event {
+        (self.0).0.gate.event()
+    }
+
+    /// Returns `true` if the lock is free.
+    pub fn ready(&self) -> bool {
+        (self.0).0.gate.available()
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        (self.0).0.gate.unwatch();
     }
 }
-pub struct FileSystem(Mutex<Option<PiVFatHandle>>);
+
+pub struct FileSystem(RwLock<Option<PiVFatHandle>>);
 
 impl FileSystem {
     /// Returns an uninitialized `FileSystem`.
     ///
-    /// The file system must be initialized by calling `initialize()` before the
-    /// first memory allocation. Failure to do will result in panics.
+    /// The file system must be initialized by calling `initialize()` before it
+    /// is first used. Failure to do will result in panics.
     pub const fn uninitialized() -> Self {
-        FileSystem(Mutex::new(None))
+        FileSystem(RwLock::new(None))
     }
 
     /// Initializes the file system.
     /// The caller should assure that the method is invoked only once during the
-    /// kernel initialization.
+    /// kernel initialization, once the MMU is on.
     ///
     /// # Panics
     ///
     /// Panics if the underlying disk or file sytem failed to initialize.
     pub unsafe fn initialize(&self) {
-        let mut handle = self.0.lock();
+        let mut handle = self.0.write();
         let sd = Sd::new().expect("SD card initialize failure");
         *handle = Some(VFat::<PiVFatHandle>::from(sd).expect("MBR and FAT partition read failed"));
     }
+
+    fn handle(&self) -> PiVFatHandle {
+        self.0.read().as_ref().expect("file system used before initialization").clone()
+    }
+
+    /// Runs `f` on behalf of a system call handler, holding the file system
+    /// lock, and returns its result. Returns `None` without running `f` if a
+    /// kernel thread or another core holds the lock.
+    ///
+    /// `f` must not block or switch away from the calling process.
+    pub fn try_enter<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        let handle = self.handle();
+        let lock = &*handle.0;
+        if !lock.gate.try_down() {
+            return None;
+        }
+
+        lock.owner.store(VFatLock::owner_tag(), Ordering::SeqCst);
+        let result = f();
+        lock.owner.store(0, Ordering::SeqCst);
+        lock.gate.up();
+        Some(result)
+    }
+
+    /// Returns a `Waiter` for a system call handler that found the lock held
+    /// in `try_enter()`. The next release of the lock wakes its `event()`.
+    pub fn waiter(&self) -> Waiter {
+        let handle = self.handle();
+        handle.0.gate.watch();
+        Waiter(handle)
+    }
 }
 
 // FIXME: Implement `fat32::traits::FileSystem` for `&FileSystem`
@@ -73,7 +221,7 @@ impl fat32::traits::FileSystem for &FileSystem {
     type Entry = Entry<PiVFatHandle>;
 
     fn open<P: AsRef<Path>>(self, path: P) -> io::Result<Self::Entry> {
-        let handle = self.0.lock();
+        let handle = self.0.read();
         handle.as_ref().unwrap().open(path)
     }
 }
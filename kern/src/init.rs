@@ -1,13 +1,14 @@
 use aarch64::*;
 
 use core::mem::zeroed;
-use core::ptr::write_volatile;
+use core::ptr::{read_volatile, write_volatile};
 
 mod oom;
 mod panic;
 
 use crate::kmain;
 use crate::param::*;
+use crate::{SCHEDULER, VMM};
 
 global_asm!(include_str!("init/vectors.s"));
 
@@ -28,6 +29,16 @@ pub unsafe extern "C" fn _start() -> ! {
     unreachable!()
 }
 
+/// The entry point of cores 1-3. The firmware parks them in a loop on their
+/// spin-table slot and jumps here once `initialize_app_cores()` writes this
+/// address to it.
+#[no_mangle]
+pub unsafe extern "C" fn start2() -> ! {
+    let core = MPIDR_EL1.get_value(MPIDR_EL1::Aff0) as usize;
+    SP.set(kern_stack_top(core));
+    kinit2()
+}
+
 unsafe fn zeros_bss() {
     extern "C" {
         static mut __bss_beg: u64;
@@ -109,3 +120,49 @@ unsafe fn kinit() -> ! {
     switch_to_el1();
     kmain();
 }
+
+#[no_mangle]
+unsafe fn kinit2() -> ! {
+    switch_to_el2();
+    switch_to_el1();
+    kmain2()
+}
+
+unsafe fn kmain2() -> ! {
+    // Tell core 0 this core is up. The MMU is still off, so this goes
+    // straight to memory.
+    let spinning = SPINNING_BASE.add(affinity());
+    write_volatile(spinning, 0);
+
+    VMM.setup();
+    SCHEDULER.start()
+}
+
+/// Cleans and invalidates the data cache line holding `addr`, so that a
+/// core running with its MMU (and cache) off sees what this core wrote there,
+/// and this core sees what such a core wrote.
+unsafe fn flush_dcache_line(addr: usize) {
+    asm!("dc civac, $0
+          dsb sy" :: "r"(addr) :: "volatile");
+}
+
+/// Wakes cores 1-3 up at `start2()` and waits until each of them has
+/// acknowledged by clearing its spin-table slot.
+pub unsafe fn initialize_app_cores() {
+    for core in 1..NCORES {
+        let spinning = SPINNING_BASE.add(core);
+        write_volatile(spinning, start2 as usize);
+        flush_dcache_line(spinning as usize);
+    }
+    asm::sev();
+
+    for core in 1..NCORES {
+        let spinning = SPINNING_BASE.add(core);
+        loop {
+            flush_dcache_line(spinning as usize);
+            if read_volatile(spinning) == 0 {
+                break;
+            }
+        }
+    }
+}
@@ -1,9 +1,16 @@
 use core::panic::PanicInfo;
 
-use crate::console::kprintln;
+use crate::console::{kprintln, CONSOLE};
+use crate::percore;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Whatever locks this core holds, the message has to get out.
+    unsafe {
+        percore::forget_locks();
+        CONSOLE.force_unlock();
+    }
+
     kprintln!("======================= PANIC ====================");
     if let Some(s) = info.payload().downcast_ref::<&str>() {
         kprintln!("Message: {:?}", s);
@@ -24,8 +24,10 @@ pub mod console;
 pub mod fs;
 pub mod mutex;
 pub mod param;
+pub mod percore;
 pub mod process;
 pub mod shell;
+pub mod sync;
 pub mod traps;
 pub mod vm;
 
@@ -55,7 +57,7 @@ fn uart_loop() -> ! {
     }
 }
 
-use allocator::Allocator;
+use allocator::{Allocator, FrameAllocator};
 use fs::FileSystem;
 use process::GlobalScheduler;
 use traps::irq::Irq;
@@ -63,6 +65,7 @@ use vm::VMManager;
 
 #[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: Allocator = Allocator::uninitialized();
+pub static FRAMES: FrameAllocator = FrameAllocator::uninitialized();
 pub static FILESYSTEM: FileSystem = FileSystem::uninitialized();
 pub static SCHEDULER: GlobalScheduler = GlobalScheduler::uninitialized();
 pub static VMM: VMManager = VMManager::uninitialized();
@@ -77,13 +80,18 @@ fn kmain() -> ! {
     //     kprintln!("{:#?}", tag);
     // }
     unsafe {
+        FRAMES.initialize();
         ALLOCATOR.initialize();
-        FILESYSTEM.initialize();
         IRQ.initialize();
         kprintln!("initializing VMM");
         VMM.initialize();
+        FILESYSTEM.initialize();
         kprintln!("initializing scheduler");
         SCHEDULER.initialize();
+        kprintln!("starting cores 1-{}", param::NCORES - 1);
+        #[cfg(not(test))]
+        init::initialize_app_cores();
+        VMM.wait();
         kprintln!("starting scheduler");
         SCHEDULER.start();
     }
@@ -1,13 +1,126 @@
 use core::cell::UnsafeCell;
 use core::fmt;
 use core::ops::{Deref, DerefMut, Drop};
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
 
+use aarch64::DAIF;
+
+use crate::percore;
+
+/// The place of a lock in the kernel's lock order. A core may only take a
+/// lock of a higher rank than every ranked lock it holds, so taking
+/// `SCHEDULER` and then `CONSOLE` is fine but not the other way around.
+/// Locks of rank `None` are left out of the order.
+///
+/// The order is checked in debug builds only.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rank {
+    None = 0,
+    Irq,
+    Scheduler,
+    Vm,
+    Console,
+    Magazine,
+    Slab,
+    Allocator,
+    Frames,
+}
+
+/// The value of `owner` while nobody holds the lock.
+const NO_OWNER: usize = usize::max_value();
+
+/// The spinlock underneath `Mutex` and `IrqMutex`.
+struct RawLock {
+    lock: AtomicBool,
+    owner: AtomicUsize,
+    rank: Rank,
+}
+
+impl RawLock {
+    const fn new(rank: Rank) -> RawLock {
+        RawLock { lock: AtomicBool::new(false), owner: AtomicUsize::new(NO_OWNER), rank }
+    }
+
+    /// Takes the lock if it is free and returns whether it did.
+    ///
+    /// Until the current core's MMU is on, its memory accesses bypass the
+    /// cache and atomic read-modify-write instructions do not work, so the
+    /// lock is taken with a plain load and store. Only core 0 runs at that
+    /// point.
+    fn try_acquire(&self) -> bool {
+        let acquired = if percore::is_mmu_ready() {
+            !self.lock.compare_and_swap(false, true, Ordering::Acquire)
+        } else if !self.lock.load(Ordering::Relaxed) {
+            self.lock.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        };
+
+        if acquired {
+            self.owner.store(percore::getcpu(), Ordering::Relaxed);
+            #[cfg(debug_assertions)]
+            {
+                if self.rank != Rank::None {
+                    percore::add_held_rank(self.rank as usize);
+                }
+            }
+        }
+        acquired
+    }
+
+    /// Spins until the lock is taken.
+    fn acquire(&self) {
+        #[cfg(debug_assertions)]
+        self.check();
+
+        while !self.try_acquire() {
+            spin_loop_hint();
+        }
+    }
+
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if self.rank != Rank::None {
+                percore::remove_held_rank(self.rank as usize);
+            }
+        }
+
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.lock.store(false, Ordering::Release);
+    }
+
+    /// Panics if the current core already holds this lock, which would spin
+    /// forever, or holds a lock of the same or a higher rank.
+    #[cfg(debug_assertions)]
+    fn check(&self) {
+        let this = percore::getcpu();
+        if self.lock.load(Ordering::Relaxed) && self.owner.load(Ordering::Relaxed) == this {
+            panic!("core {} takes a {:?} lock it already holds", this, self.rank);
+        }
+
+        let held = percore::held_ranks();
+        if self.rank != Rank::None && held >> self.rank as usize != 0 {
+            panic!(
+                "lock order violation: core {} takes a {:?} lock holding ranks {:#b}",
+                this, self.rank, held
+            );
+        }
+    }
+}
+
+/// A spinlock.
+///
+/// While a core holds one, the process running on it is not preempted (see
+/// `percore::preemptible()`), so that whatever would run next on the core
+/// cannot end up spinning on the lock forever. Data that interrupt handlers
+/// touch must be behind an `IrqMutex` instead.
 #[repr(align(32))]
 pub struct Mutex<T> {
     data: UnsafeCell<T>,
-    lock: AtomicBool,
-    owner: AtomicUsize,
+    raw: RawLock,
 }
 
 unsafe impl<T: Send> Send for Mutex<T> {}
@@ -22,43 +135,38 @@ unsafe impl<'a, T: Sync> Sync for MutexGuard<'a, T> {}
 
 impl<T> Mutex<T> {
     pub const fn new(val: T) -> Mutex<T> {
+        Mutex::ranked(val, Rank::None)
+    }
+
+    /// Returns a mutex with place `rank` in the lock order.
+    pub const fn ranked(val: T, rank: Rank) -> Mutex<T> {
         Mutex {
-            lock: AtomicBool::new(false),
-            owner: AtomicUsize::new(usize::max_value()),
+            raw: RawLock::new(rank),
             data: UnsafeCell::new(val),
         }
     }
 }
 
 impl<T> Mutex<T> {
-    // Once MMU/cache is enabled, do the right thing here. For now, we don't
-    // need any real synchronization.
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
-        let this = 0;
-        if !self.lock.load(Ordering::Relaxed) || self.owner.load(Ordering::Relaxed) == this {
-            self.lock.store(true, Ordering::Relaxed);
-            self.owner.store(this, Ordering::Relaxed);
+        if self.raw.try_acquire() {
+            percore::lock_taken();
             Some(MutexGuard { lock: &self })
         } else {
             None
         }
     }
 
-    // Once MMU/cache is enabled, do the right thing here. For now, we don't
-    // need any real synchronization.
     #[inline(never)]
     pub fn lock(&self) -> MutexGuard<T> {
-        // Wait until we can "aquire" the lock, then "acquire" it.
-        loop {
-            match self.try_lock() {
-                Some(guard) => return guard,
-                None => continue,
-            }
-        }
+        self.raw.acquire();
+        percore::lock_taken();
+        MutexGuard { lock: &self }
     }
 
     fn unlock(&self) {
-        self.lock.store(false, Ordering::Relaxed);
+        self.raw.release();
+        percore::lock_released();
     }
 }
 
@@ -90,3 +198,98 @@ impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
         }
     }
 }
+
+/// A spinlock that masks IRQs on the current core while it is held, for data
+/// that interrupt handlers touch: an IRQ handler taking the lock can then
+/// never interrupt the code holding it on the same core.
+#[repr(align(32))]
+pub struct IrqMutex<T> {
+    data: UnsafeCell<T>,
+    raw: RawLock,
+}
+
+unsafe impl<T: Send> Send for IrqMutex<T> {}
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    lock: &'a IrqMutex<T>,
+    /// The `DAIF` value to restore once the lock is released.
+    daif: u64,
+}
+
+impl<'a, T> !Send for IrqMutexGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for IrqMutexGuard<'a, T> {}
+
+impl<T> IrqMutex<T> {
+    /// Returns an IRQ-safe mutex with place `rank` in the lock order.
+    pub const fn ranked(val: T, rank: Rank) -> IrqMutex<T> {
+        IrqMutex {
+            raw: RawLock::new(rank),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let daif = unsafe { mask_irqs() };
+        if self.raw.try_acquire() {
+            Some(IrqMutexGuard { lock: &self, daif })
+        } else {
+            unsafe { DAIF.set(daif) };
+            None
+        }
+    }
+
+    #[inline(never)]
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let daif = unsafe { mask_irqs() };
+        self.raw.acquire();
+        IrqMutexGuard { lock: &self, daif }
+    }
+
+    /// Releases the lock whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// The holder keeps its guard, so this is only fit for getting a panic
+    /// message out.
+    pub unsafe fn force_unlock(&self) {
+        self.raw.release();
+    }
+}
+
+/// Masks IRQs and returns the previous value of `DAIF`.
+unsafe fn mask_irqs() -> u64 {
+    let daif = DAIF.get();
+    aarch64::cli();
+    daif
+}
+
+impl<'a, T: 'a> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+        unsafe { DAIF.set(self.daif) };
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("IrqMutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("IrqMutex").field("data", &"<locked>").finish(),
+        }
+    }
+}
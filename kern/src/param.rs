@@ -21,8 +21,19 @@ const_assert_eq!(USER_IMG_BASE, ((1 << USER_MASK_BITS) - 1) << (64 - USER_MASK_B
 pub const USER_STACK_BASE: usize = core::usize::MAX & PAGE_MASK;
 pub const USER_MAX_VM_SIZE: usize = 0x4000_0000;
 const_assert_eq!(USER_IMG_BASE.wrapping_add(USER_MAX_VM_SIZE), 0);
+/// The upper half of the user window is handed out by `mmap`; the image and
+/// the heap live below it.
+pub const USER_MMAP_BASE: usize = USER_IMG_BASE + USER_MAX_VM_SIZE / 2;
 pub const KERN_STACK_BASE: usize = 0x80_000;
+/// The size of each core's kernel stack. Core `n`'s stack sits right below
+/// core `n - 1`'s, starting from `KERN_STACK_BASE`.
+pub const KERN_STACK_SIZE: usize = PAGE_SIZE;
 
-/// The `tick` time.
-// FIXME: When you're ready, change this to something more reasonable.
+/// Returns the initial stack pointer of core `core`.
+pub const fn kern_stack_top(core: usize) -> usize {
+    KERN_STACK_BASE - core * KERN_STACK_SIZE
+}
+
+/// The `tick` time: the time slice of the round-robin policy and the
+/// longest slice any policy hands out.
 pub const TICK: Duration = Duration::from_millis(400);
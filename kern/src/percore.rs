@@ -0,0 +1,173 @@
+//! State that each core keeps for itself: whether its MMU is on, the
+//! spinlocks it holds, the CPU time of the process it runs, its generic
+//! timer, which drives the core's scheduler ticks, and the mailbox other
+//! cores interrupt it through.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use aarch64::*;
+use pi::local_interrupt::LocalController;
+
+use crate::param::NCORES;
+
+/// Only the owning core touches its `PerCore`, so plain loads and stores are
+/// enough, which also work before the MMU is on.
+struct PerCore {
+    /// Whether the MMU, and with it the data cache, is on.
+    mmu_ready: AtomicBool,
+    /// The number of `Mutex`es held.
+    locks: AtomicUsize,
+    /// Bit `n` is set while a lock of `Rank` `n` is held.
+    ranks: AtomicUsize,
+    /// The counter value when CPU time was last charged.
+    mark: AtomicU64,
+    /// The user and kernel time charged since `take_cpu_times()`, in
+    /// counter ticks.
+    user: AtomicU64,
+    kernel: AtomicU64,
+}
+
+impl PerCore {
+    const fn new() -> PerCore {
+        PerCore {
+            mmu_ready: AtomicBool::new(false),
+            locks: AtomicUsize::new(0),
+            ranks: AtomicUsize::new(0),
+            mark: AtomicU64::new(0),
+            user: AtomicU64::new(0),
+            kernel: AtomicU64::new(0),
+        }
+    }
+}
+
+static PER_CORE_DATA: [PerCore; NCORES] = [PerCore::new(), PerCore::new(), PerCore::new(), PerCore::new()];
+
+fn this_core() -> &'static PerCore {
+    &PER_CORE_DATA[getcpu()]
+}
+
+/// Returns the index of the core this runs on.
+#[inline(always)]
+pub fn getcpu() -> usize {
+    affinity()
+}
+
+/// Returns `true` once the current core has turned its MMU on. Until then
+/// its memory accesses bypass the cache, so atomic instructions cannot be
+/// used on shared memory.
+pub fn is_mmu_ready() -> bool {
+    this_core().mmu_ready.load(Ordering::Relaxed)
+}
+
+/// Records that the current core turned its MMU on.
+pub fn set_mmu_ready() {
+    this_core().mmu_ready.store(true, Ordering::Relaxed);
+}
+
+/// Records that the current core took a `Mutex`.
+pub fn lock_taken() {
+    let locks = &this_core().locks;
+    locks.store(locks.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+}
+
+/// Records that the current core released a `Mutex`.
+pub fn lock_released() {
+    let locks = &this_core().locks;
+    locks.store(locks.load(Ordering::Relaxed) - 1, Ordering::Relaxed);
+}
+
+/// Returns `true` if the current core holds no `Mutex`, so the process
+/// running on it may be switched away from.
+pub fn preemptible() -> bool {
+    this_core().locks.load(Ordering::Relaxed) == 0
+}
+
+/// Returns the ranks of the locks the current core holds, as a bit mask.
+pub fn held_ranks() -> usize {
+    this_core().ranks.load(Ordering::Relaxed)
+}
+
+/// Records that the current core took a lock of rank `rank`.
+pub fn add_held_rank(rank: usize) {
+    let ranks = &this_core().ranks;
+    ranks.store(ranks.load(Ordering::Relaxed) | 1 << rank, Ordering::Relaxed);
+}
+
+/// Records that the current core released its lock of rank `rank`.
+pub fn remove_held_rank(rank: usize) {
+    let ranks = &this_core().ranks;
+    ranks.store(ranks.load(Ordering::Relaxed) & !(1 << rank), Ordering::Relaxed);
+}
+
+/// Forgets every lock the current core holds, so that the panic handler can
+/// print without tripping over the lock diagnostics.
+pub fn forget_locks() {
+    this_core().locks.store(0, Ordering::Relaxed);
+    this_core().ranks.store(0, Ordering::Relaxed);
+}
+
+/// Charges the time since the last charge to user time if `user` is set and
+/// to kernel time otherwise. Called when an exception is taken, with `user`
+/// set if it was taken from EL0, and when it returns.
+pub fn account(user: bool) {
+    let this = this_core();
+    let now = unsafe { CNTPCT_EL0.get() };
+    let elapsed = now.wrapping_sub(this.mark.load(Ordering::Relaxed));
+    let bucket = if user { &this.user } else { &this.kernel };
+    bucket.store(bucket.load(Ordering::Relaxed) + elapsed, Ordering::Relaxed);
+    this.mark.store(now, Ordering::Relaxed);
+}
+
+/// Returns the user and kernel time charged on the current core since the
+/// last call and starts over.
+pub fn take_cpu_times() -> (Duration, Duration) {
+    account(false);
+    let this = this_core();
+    let freq = unsafe { CNTFRQ_EL0.get() };
+    let to_duration = |ticks: u64| Duration::from_micros((ticks as u128 * 1_000_000 / freq as u128) as u64);
+    let user = to_duration(this.user.load(Ordering::Relaxed));
+    let kernel = to_duration(this.kernel.load(Ordering::Relaxed));
+    this.user.store(0, Ordering::Relaxed);
+    this.kernel.store(0, Ordering::Relaxed);
+    (user, kernel)
+}
+
+/// Routes the generic timer of the current core to its IRQ line.
+pub fn enable_local_timer() {
+    LocalController::new(getcpu()).enable_local_timer();
+}
+
+/// Makes the generic timer of the current core raise an interrupt `t` from
+/// now.
+pub fn local_tick_in(t: Duration) {
+    unsafe {
+        let ticks = CNTFRQ_EL0.get() * t.as_micros() as u64 / 1_000_000;
+        CNTP_TVAL_EL0.set(ticks);
+        CNTP_CTL_EL0.set(CNTP_CTL_EL0::ENABLE);
+    }
+}
+
+/// Stops the generic timer of the current core.
+pub fn stop_local_tick() {
+    unsafe { CNTP_CTL_EL0.set(0) };
+}
+
+/// The mailbox the cores interrupt each other through.
+const IPI_MAILBOX: usize = 0;
+
+/// Routes the IPI mailbox of the current core to its IRQ line.
+pub fn enable_ipi() {
+    LocalController::new(getcpu()).enable_mailbox(IPI_MAILBOX);
+}
+
+/// Sends an inter-processor interrupt to `core`, waking it up if it is
+/// waiting for interrupts.
+pub fn send_ipi(core: usize) {
+    LocalController::new(getcpu()).send(core, IPI_MAILBOX, 1);
+}
+
+/// Acknowledges the inter-processor interrupts sent to the current core.
+pub fn take_ipi() {
+    LocalController::new(getcpu()).take_mailbox(IPI_MAILBOX);
+}
@@ -1,10 +1,22 @@
+pub mod elf;
+pub mod fd;
+pub mod pipe;
+pub mod policy;
+pub mod signal;
 mod process;
 mod scheduler;
+mod space;
 mod stack;
 mod state;
+mod stats;
 
-pub use self::process::{Id, Process};
-pub use self::scheduler::GlobalScheduler;
+pub use self::fd::{Descriptor, Fd, FdTable};
+pub use self::policy::Policy;
+pub use self::process::{Id, Process, PROCESSES, TRAP_FRAMES};
+pub use self::scheduler::{GlobalScheduler, Reservation, IDLE_PID, INIT_PID};
+pub use self::signal::Signals;
+pub use self::space::AddressSpace;
 pub use self::stack::Stack;
-pub use self::state::State;
+pub use self::state::{Event, State, WakeFn};
+pub use self::stats::Stats;
 pub use crate::param::TICK;
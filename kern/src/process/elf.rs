@@ -0,0 +1,277 @@
+use core::mem::size_of;
+use core::ptr;
+
+use shim::const_assert_size;
+
+use crate::vm::PagePerm;
+use kernel_api::{OsError, OsResult};
+
+/// The four magic bytes at the start of every ELF file.
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+
+/// Program header type of a loadable segment.
+pub const PT_LOAD: u32 = 1;
+
+/// Segment permission flags (`p_flags`).
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+/// The ELF64 file header (ref: System V ABI, "ELF Header").
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+const_assert_size!(ElfHeader, 64);
+
+/// An ELF64 program header describing one segment of the image.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+const_assert_size!(ProgramHeader, 56);
+
+impl ProgramHeader {
+    /// Returns `true` if this segment should be mapped into memory.
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    /// Returns the page permission matching this segment's `p_flags`.
+    pub fn perm(&self) -> PagePerm {
+        PagePerm::from_elf_flags(self.flags)
+    }
+}
+
+/// A validated view over an ELF64 AArch64 executable held in memory.
+pub struct Elf<'a> {
+    header: ElfHeader,
+    data: &'a [u8],
+}
+
+/// Returns `true` if `data` starts with the ELF magic bytes.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= ELF_MAGIC.len() && data[..ELF_MAGIC.len()] == ELF_MAGIC
+}
+
+/// Reads a `T` from `data` at byte offset `offset`, if it fits.
+fn read_at<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
+
+impl<'a> Elf<'a> {
+    /// Parses and validates the ELF header in `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::IoErrorInvalidData` if `data` is not a little-endian,
+    /// 64-bit AArch64 executable or if its program header table does not fit
+    /// in `data`.
+    pub fn parse(data: &'a [u8]) -> OsResult<Elf<'a>> {
+        let header: ElfHeader = read_at(data, 0).ok_or(OsError::IoErrorInvalidData)?;
+        let ident = &header.ident;
+
+        if ident[..4] != ELF_MAGIC
+            || ident[4] != ELFCLASS64
+            || ident[5] != ELFDATA2LSB
+            || ident[6] != EV_CURRENT
+            || header.kind != ET_EXEC
+            || header.machine != EM_AARCH64
+            || header.phentsize as usize != size_of::<ProgramHeader>()
+        {
+            return Err(OsError::IoErrorInvalidData);
+        }
+
+        let table_size = header.phnum as u64 * size_of::<ProgramHeader>() as u64;
+        match header.phoff.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => Ok(Elf { header, data }),
+            _ => Err(OsError::IoErrorInvalidData),
+        }
+    }
+
+    /// Returns the virtual address of the program's entry point.
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    /// Returns an iterator over all of the program headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.header.phoff as usize;
+        (0..self.header.phnum as usize)
+            .filter_map(move |i| read_at(data, phoff + i * size_of::<ProgramHeader>()))
+    }
+
+    /// Returns the bytes of `ph` stored in the file (`p_filesz` bytes at
+    /// `p_offset`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::IoErrorInvalidData` if the segment lies outside of
+    /// the file or if `p_filesz > p_memsz`.
+    pub fn segment_data(&self, ph: &ProgramHeader) -> OsResult<&'a [u8]> {
+        if ph.filesz > ph.memsz {
+            return Err(OsError::IoErrorInvalidData);
+        }
+        match ph.offset.checked_add(ph.filesz) {
+            Some(end) if end <= self.data.len() as u64 => Ok(&self.data[ph.offset as usize..end as usize]),
+            _ => Err(OsError::IoErrorInvalidData),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an image holding a valid header with `phnum` zeroed program
+    /// headers right after it.
+    fn image(phnum: u16) -> Vec<u8> {
+        let mut ident = [0u8; 16];
+        ident[..4].copy_from_slice(&ELF_MAGIC);
+        ident[4] = ELFCLASS64;
+        ident[5] = ELFDATA2LSB;
+        ident[6] = EV_CURRENT;
+
+        let header = ElfHeader {
+            ident,
+            kind: ET_EXEC,
+            machine: EM_AARCH64,
+            version: EV_CURRENT as u32,
+            entry: 0x1000,
+            phoff: size_of::<ElfHeader>() as u64,
+            shoff: 0,
+            flags: 0,
+            ehsize: size_of::<ElfHeader>() as u16,
+            phentsize: size_of::<ProgramHeader>() as u16,
+            phnum,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        };
+
+        let len = size_of::<ElfHeader>() + phnum as usize * size_of::<ProgramHeader>();
+        let mut data = vec![0u8; len];
+        unsafe { ptr::write_unaligned(data.as_mut_ptr() as *mut ElfHeader, header) };
+        data
+    }
+
+    fn set_phoff(data: &mut [u8], phoff: u64) {
+        unsafe {
+            let header = data.as_mut_ptr() as *mut ElfHeader;
+            let mut h = ptr::read_unaligned(header);
+            h.phoff = phoff;
+            ptr::write_unaligned(header, h);
+        }
+    }
+
+    fn segment(offset: u64, filesz: u64, memsz: u64) -> ProgramHeader {
+        ProgramHeader {
+            kind: PT_LOAD,
+            flags: PF_R,
+            offset,
+            vaddr: 0x1000,
+            paddr: 0,
+            filesz,
+            memsz,
+            align: 0x1000,
+        }
+    }
+
+    #[test]
+    fn parses_valid_header() {
+        let data = image(2);
+        let elf = Elf::parse(&data).expect("valid header");
+        assert_eq!(elf.entry(), 0x1000);
+        assert_eq!(elf.program_headers().count(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut data = image(0);
+        data[1] = b'X';
+        assert!(!is_elf(&data));
+        assert_eq!(Elf::parse(&data).err(), Some(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let data = image(0);
+        for len in &[0, 4, 16, size_of::<ElfHeader>() - 1] {
+            assert_eq!(Elf::parse(&data[..*len]).err(), Some(OsError::IoErrorInvalidData));
+        }
+    }
+
+    #[test]
+    fn rejects_program_headers_past_end() {
+        // One header short of the declared table.
+        let data = image(2);
+        let short = &data[..data.len() - 1];
+        assert_eq!(Elf::parse(short).err(), Some(OsError::IoErrorInvalidData));
+
+        // A table starting past the end of the file.
+        let mut data = image(1);
+        let len = data.len() as u64;
+        set_phoff(&mut data, len);
+        assert_eq!(Elf::parse(&data).err(), Some(OsError::IoErrorInvalidData));
+
+        // A `phoff` so large that adding the table size overflows.
+        set_phoff(&mut data, u64::max_value() - 8);
+        assert_eq!(Elf::parse(&data).err(), Some(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn segment_bounds() {
+        let data = image(1);
+        let elf = Elf::parse(&data).unwrap();
+        let len = data.len() as u64;
+
+        assert_eq!(elf.segment_data(&segment(0, len, len)).map(|s| s.len()), Ok(data.len()));
+        assert_eq!(elf.segment_data(&segment(1, len, len)).err(), Some(OsError::IoErrorInvalidData));
+        assert_eq!(elf.segment_data(&segment(0, 8, 4)).err(), Some(OsError::IoErrorInvalidData));
+    }
+
+    #[test]
+    fn rejects_segment_offset_overflow() {
+        let data = image(1);
+        let elf = Elf::parse(&data).unwrap();
+        let ph = segment(u64::max_value(), 1, 1);
+        assert_eq!(elf.segment_data(&ph).err(), Some(OsError::IoErrorInvalidData));
+        let ph = segment(1, u64::max_value(), u64::max_value());
+        assert_eq!(elf.segment_data(&ph).err(), Some(OsError::IoErrorInvalidData));
+    }
+}
@@ -0,0 +1,329 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use fat32::traits::{Dir as DirTrait, Entry as EntryTrait, File as FileTrait, FileSystem, Metadata, Timestamp};
+use fat32::vfat::{Dir, Entry, File};
+use shim::io::{Read, Seek, SeekFrom};
+use shim::path::Path;
+
+use crate::console::CONSOLE;
+use crate::fs::PiVFatHandle;
+use crate::mutex::Mutex;
+use crate::process::pipe::PipeEnd;
+use crate::process::Event;
+use crate::FILESYSTEM;
+use kernel_api::*;
+
+/// Type alias for a file descriptor number.
+pub type Fd = u64;
+
+/// The maximum number of file descriptors a process may have open at once.
+pub const MAX_FDS: usize = 32;
+
+/// What a file descriptor refers to.
+#[derive(Debug)]
+pub enum Descriptor {
+    /// The UART console.
+    Console,
+    /// A regular file. The FAT32 driver is read-only, so the file can only be
+    /// read and seeked.
+    File(File<PiVFatHandle>),
+    /// A directory, read with `read_dir()`. `pos` is the index of the next
+    /// entry to return.
+    Dir { dir: Dir<PiVFatHandle>, pos: usize },
+    /// One end of a pipe.
+    Pipe(PipeEnd),
+}
+
+impl Descriptor {
+    /// Opens the file or directory at the absolute path `path`.
+    pub fn open(path: &Path) -> OsResult<Descriptor> {
+        Ok(match FILESYSTEM.open(path)? {
+            Entry::File_(file) => Descriptor::File(file),
+            Entry::Dir_(dir) => Descriptor::Dir { dir, pos: 0 },
+        })
+    }
+
+    /// Reads up to `buf.len()` bytes into `buf`.
+    ///
+    /// Returns `Ok(None)` if no data is available yet and the caller should
+    /// block, and `Ok(Some(0))` at end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> OsResult<Option<usize>> {
+        match self {
+            Descriptor::Console => {
+                let mut console = CONSOLE.lock();
+                if !buf.is_empty() && !console.has_byte() {
+                    return Ok(None);
+                }
+
+                let mut read = 0;
+                while read < buf.len() && console.has_byte() {
+                    buf[read] = console.read_byte();
+                    read += 1;
+                }
+                Ok(Some(read))
+            }
+            Descriptor::File(file) => Ok(Some(file.read(buf)?)),
+            Descriptor::Dir { .. } => Err(OsError::InvalidArgument),
+            Descriptor::Pipe(end) => end.read(buf),
+        }
+    }
+
+    /// Writes `buf` and returns the number of bytes written.
+    ///
+    /// Returns `Ok(None)` if nothing can be written yet and the caller should
+    /// block.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoAccess` for files and directories, which are
+    /// read-only.
+    pub fn write(&mut self, buf: &[u8]) -> OsResult<Option<usize>> {
+        match self {
+            Descriptor::Console => {
+                let mut console = CONSOLE.lock();
+                for &b in buf.iter() {
+                    if b == b'\n' {
+                        console.write_byte(b'\r');
+                    }
+                    console.write_byte(b);
+                }
+                Ok(Some(buf.len()))
+            }
+            Descriptor::File(_) | Descriptor::Dir { .. } => Err(OsError::NoAccess),
+            Descriptor::Pipe(end) => end.write(buf),
+        }
+    }
+
+    /// Returns the event a process blocked reading or writing this
+    /// descriptor waits for, or `None` if it never blocks.
+    pub fn event(&self) -> Option<Event> {
+        match self {
+            Descriptor::Console => Some(Event::Console),
+            Descriptor::Pipe(end) => Some(Event::Pipe(end.id())),
+            Descriptor::File(_) | Descriptor::Dir { .. } => None,
+        }
+    }
+
+    /// Moves the file offset to `offset` relative to `whence` (one of
+    /// `SEEK_SET`, `SEEK_CUR` or `SEEK_END`) and returns the new offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `whence` is unknown or the
+    /// descriptor is not seekable, and `OsError::IoErrorInvalidInput` if the
+    /// new offset lies outside of the file.
+    pub fn seek(&mut self, offset: i64, whence: u64) -> OsResult<u64> {
+        let pos = match whence {
+            SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return Err(OsError::InvalidArgument),
+        };
+
+        match self {
+            Descriptor::File(file) => Ok(file.seek(pos)?),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    /// Returns information about the object this descriptor refers to.
+    pub fn stat(&self) -> Stat {
+        match self {
+            Descriptor::Console => Stat { flags: STAT_CONSOLE, ..Stat::default() },
+            Descriptor::Pipe(_) => Stat { flags: STAT_PIPE, ..Stat::default() },
+            Descriptor::File(file) => stat_of(&file.metadata, file.size(), false),
+            Descriptor::Dir { dir, .. } => stat_of(&dir.metadata, 0, true),
+        }
+    }
+
+    /// Returns up to `max` entries of a directory, continuing after the
+    /// entries returned by earlier calls. An empty vector means the end of
+    /// the directory was reached.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if this is not a directory.
+    pub fn read_dir(&mut self, max: usize) -> OsResult<Vec<DirEntry>> {
+        match self {
+            Descriptor::Dir { dir, pos } => {
+                let entries: Vec<DirEntry> = dir.entries()?.skip(*pos).take(max).map(|e| dir_entry(&e)).collect();
+                *pos += entries.len();
+                Ok(entries)
+            }
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+}
+
+/// Returns information about the file or directory at the absolute path
+/// `path`.
+pub fn stat_path(path: &Path) -> OsResult<Stat> {
+    Ok(entry_stat(&FILESYSTEM.open(path)?))
+}
+
+fn entry_stat(entry: &Entry<PiVFatHandle>) -> Stat {
+    let size = entry.as_file().map(|f| f.size()).unwrap_or(0);
+    stat_of(entry.metadata(), size, entry.is_dir())
+}
+
+/// Converts a directory entry to a `DirEntry`, truncating its name to
+/// `NAME_MAX` bytes.
+fn dir_entry(entry: &Entry<PiVFatHandle>) -> DirEntry {
+    let name = entry.name();
+    let mut len = core::cmp::min(name.len(), NAME_MAX);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let mut dirent = DirEntry { name_len: len as u64, stat: entry_stat(entry), ..DirEntry::default() };
+    dirent.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    dirent
+}
+
+fn datetime<T: Timestamp>(ts: T) -> DateTime {
+    DateTime {
+        year: ts.year() as u16,
+        month: ts.month(),
+        day: ts.day(),
+        hour: ts.hour(),
+        minute: ts.minute(),
+        second: ts.second(),
+    }
+}
+
+/// Converts the metadata of a directory entry of `size` bytes to a `Stat`.
+pub fn stat_of<M: Metadata>(metadata: &M, size: u64, is_dir: bool) -> Stat {
+    let mut flags = 0;
+    if is_dir {
+        flags |= STAT_DIR;
+    }
+    if metadata.read_only() {
+        flags |= STAT_READ_ONLY;
+    }
+    if metadata.hidden() {
+        flags |= STAT_HIDDEN;
+    }
+
+    Stat {
+        size,
+        flags,
+        created: datetime(metadata.created()),
+        accessed: datetime(metadata.accessed()),
+        modified: datetime(metadata.modified()),
+    }
+}
+
+/// The file descriptors of a process, indexed by descriptor number.
+///
+/// Descriptors are shared: `dup2()` and `clone()` make several descriptor
+/// numbers, possibly in different processes, refer to the same `Descriptor`
+/// and its file offset. A `Descriptor` is closed when its last reference is.
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Arc<Mutex<Descriptor>>>>,
+}
+
+impl FdTable {
+    /// Returns a table with the console installed as standard input, output
+    /// and error (descriptors 0, 1 and 2).
+    pub fn new() -> FdTable {
+        let console = Arc::new(Mutex::new(Descriptor::Console));
+        let mut fds = Vec::new();
+        for _ in 0..3 {
+            fds.push(Some(console.clone()));
+        }
+        FdTable { fds }
+    }
+
+    /// Installs `desc` at the lowest free descriptor number and returns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoMemory` if `MAX_FDS` descriptors are already open.
+    pub fn insert(&mut self, desc: Descriptor) -> OsResult<Fd> {
+        let desc = Some(Arc::new(Mutex::new(desc)));
+        match self.fds.iter().position(|d| d.is_none()) {
+            Some(fd) => {
+                self.fds[fd] = desc;
+                Ok(fd as Fd)
+            }
+            None if self.fds.len() < MAX_FDS => {
+                self.fds.push(desc);
+                Ok((self.fds.len() - 1) as Fd)
+            }
+            None => Err(OsError::NoMemory),
+        }
+    }
+
+    /// Returns the descriptor `fd`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `fd` is not open.
+    pub fn get(&self, fd: Fd) -> OsResult<&Mutex<Descriptor>> {
+        self.fds
+            .get(fd as usize)
+            .and_then(|d| d.as_ref())
+            .map(|d| &**d)
+            .ok_or(OsError::InvalidArgument)
+    }
+
+    /// Returns a new reference to the descriptor `fd`, which keeps it open
+    /// while the caller uses it outside of the scheduler's critical section.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `fd` is not open.
+    pub fn shared(&self, fd: Fd) -> OsResult<Arc<Mutex<Descriptor>>> {
+        self.fds.get(fd as usize).cloned().flatten().ok_or(OsError::InvalidArgument)
+    }
+
+    /// Makes `new` refer to the same descriptor as `old`, closing whatever
+    /// `new` referred to before.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `old` is not open or `new` is not
+    /// below `MAX_FDS`.
+    pub fn dup2(&mut self, old: Fd, new: Fd) -> OsResult<()> {
+        let desc = self.fds.get(old as usize).cloned().flatten().ok_or(OsError::InvalidArgument)?;
+        let new = new as usize;
+        if new >= MAX_FDS {
+            return Err(OsError::InvalidArgument);
+        }
+
+        if self.fds.len() <= new {
+            self.fds.resize(new + 1, None);
+        }
+        self.fds[new] = Some(desc);
+        Ok(())
+    }
+
+    /// Closes the descriptor `fd`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `fd` is not open.
+    pub fn remove(&mut self, fd: Fd) -> OsResult<()> {
+        self.fds
+            .get_mut(fd as usize)
+            .and_then(|d| d.take())
+            .map(|_| ())
+            .ok_or(OsError::InvalidArgument)
+    }
+
+    /// Returns the events of the open descriptors (see
+    /// `Descriptor::event()`), which closing them may cause.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.fds.iter().flatten().filter_map(|d| d.lock().event()).collect();
+        events.sort();
+        events.dedup();
+        events
+    }
+
+    /// Closes every descriptor.
+    pub fn clear(&mut self) {
+        self.fds.clear();
+    }
+}
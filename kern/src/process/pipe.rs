@@ -0,0 +1,109 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp;
+
+use crate::mutex::Mutex;
+use kernel_api::{OsError, OsResult};
+
+/// The number of bytes a pipe buffers before writers block.
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// The buffer shared by the two ends of a pipe.
+#[derive(Debug)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+/// One end of a pipe.
+///
+/// The pipe counts its open ends: once every write end is dropped, readers
+/// see end of file, and once every read end is dropped, writes fail.
+#[derive(Debug)]
+pub struct PipeEnd {
+    pipe: Arc<Mutex<Pipe>>,
+    write: bool,
+}
+
+/// Creates a pipe and returns its (read end, write end).
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buf: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+    }));
+
+    let reader = PipeEnd { pipe: pipe.clone(), write: false };
+    let writer = PipeEnd { pipe, write: true };
+    (reader, writer)
+}
+
+impl PipeEnd {
+    /// Returns an ID of the pipe, the same for both of its ends.
+    pub fn id(&self) -> usize {
+        &*self.pipe as *const Mutex<Pipe> as usize
+    }
+
+    /// Reads up to `buf.len()` buffered bytes into `buf`.
+    ///
+    /// Returns `Ok(None)` if the pipe is empty but still has writers, and
+    /// `Ok(Some(0))` once it is empty and every write end is closed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if this is the write end.
+    pub fn read(&self, buf: &mut [u8]) -> OsResult<Option<usize>> {
+        if self.write {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let mut pipe = self.pipe.lock();
+        if pipe.buf.is_empty() && !buf.is_empty() {
+            return Ok(if pipe.writers == 0 { Some(0) } else { None });
+        }
+
+        let n = cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(Some(n))
+    }
+
+    /// Writes as much of `buf` as fits into the pipe.
+    ///
+    /// Returns `Ok(None)` if the pipe is full.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if this is the read end and
+    /// `OsError::IoError` if every read end is closed.
+    pub fn write(&self, buf: &[u8]) -> OsResult<Option<usize>> {
+        if !self.write {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let mut pipe = self.pipe.lock();
+        if pipe.readers == 0 {
+            return Err(OsError::IoError);
+        }
+
+        let n = cmp::min(buf.len(), PIPE_CAPACITY - pipe.buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Ok(None);
+        }
+        pipe.buf.extend(buf[..n].iter());
+        Ok(Some(n))
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock();
+        if self.write {
+            pipe.writers -= 1;
+        } else {
+            pipe.readers -= 1;
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::fmt;
+use core::time::Duration;
+
+use crate::param::TICK;
+use crate::process::{Id, Process};
+use kernel_api::{NICE_MAX, NICE_MIN};
+
+/// Why a process is put on the run queue.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Enqueue {
+    /// The process was just created.
+    New,
+    /// The process was preempted at the end of its time slice, or its
+    /// priority changed while it was ready.
+    Requeued,
+    /// The process was blocked or stopped and is ready again.
+    Woken,
+    /// The process was taken off another core's run queue.
+    Migrated,
+}
+
+/// The per-process state the scheduling policies keep in `Process::sched`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Entity {
+    /// The MLFQ level; `0` is the highest priority.
+    pub level: usize,
+    /// The CPU time used at `level` since the process got there.
+    pub used: Duration,
+    /// The MLFQ boost period `level` was set in.
+    pub epoch: u64,
+    /// The CFS virtual runtime, in nanoseconds.
+    pub vruntime: u64,
+}
+
+/// A scheduling policy: decides the order ready processes run in and how
+/// long each runs.
+///
+/// The scheduler calls `enqueue()` whenever a process becomes ready,
+/// `pick_next()` to take the next process to run off the run queue,
+/// `start()` when that process starts running and `charge()` when it stops.
+/// A ready process that is removed is passed to `remove()`.
+///
+/// Each core has a policy of its own. To balance the load, a core takes a
+/// process off another core's run queue with `steal()` and passes it to its
+/// own policy's `enqueue()` as `Enqueue::Migrated`.
+pub trait Policy: fmt::Debug + Send {
+    /// Returns the name of the policy, as accepted by `by_name()`.
+    fn name(&self) -> &'static str;
+
+    /// Puts the ready process `p` on the run queue.
+    fn enqueue(&mut self, p: &mut Process, why: Enqueue);
+
+    /// Takes the ready process `p` off the run queue.
+    fn remove(&mut self, p: &Process);
+
+    /// Takes the process to run next off the run queue and returns its ID.
+    fn pick_next(&mut self, now: Duration) -> Option<Id>;
+
+    /// Takes the process that would run last off the run queue and returns
+    /// its ID, so that another core can run it.
+    fn steal(&mut self) -> Option<Id>;
+
+    /// Returns the number of processes on the run queue.
+    fn len(&self) -> usize;
+
+    /// Returns the time slice of `p`, which is about to run.
+    fn start(&mut self, p: &mut Process) -> Duration;
+
+    /// Charges `p` for running `ran` since `start()`.
+    fn charge(&mut self, p: &mut Process, ran: Duration);
+}
+
+/// Returns a new policy by name: `rr`, `mlfq` or `cfs`.
+pub fn by_name(name: &str) -> Option<Box<dyn Policy>> {
+    match name {
+        "rr" => Some(Box::new(RoundRobin::new())),
+        "mlfq" => Some(Box::new(Mlfq::new())),
+        "cfs" => Some(Box::new(Cfs::new())),
+        _ => None,
+    }
+}
+
+/// Clamps `nice` to `NICE_MIN..=NICE_MAX`.
+pub fn clamp_nice(nice: i64) -> i64 {
+    max(NICE_MIN, min(NICE_MAX, nice))
+}
+
+/// The weight of a process with nice value `0`.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// The weight of each nice value from `NICE_MIN` to `NICE_MAX`. Neighbouring
+/// values differ by about 1.25x, so each nice step moves a CPU-bound
+/// process's share by about 10%.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904, 3906, 3121,
+    2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87, 70, 56, 45, 36, 29,
+    23, 18, 15,
+];
+
+/// Returns the weight of a process with nice value `nice`.
+pub fn weight(nice: i64) -> u64 {
+    NICE_TO_WEIGHT[(clamp_nice(nice) - NICE_MIN) as usize]
+}
+
+/// Round robin: processes run in turn for `TICK` each, whatever their
+/// priority.
+#[derive(Debug)]
+pub struct RoundRobin {
+    queue: VecDeque<Id>,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin { queue: VecDeque::new() }
+    }
+}
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn enqueue(&mut self, p: &mut Process, _why: Enqueue) {
+        self.queue.push_back(p.id());
+    }
+
+    fn remove(&mut self, p: &Process) {
+        self.queue.retain(|&id| id != p.id());
+    }
+
+    fn pick_next(&mut self, _now: Duration) -> Option<Id> {
+        self.queue.pop_front()
+    }
+
+    fn steal(&mut self) -> Option<Id> {
+        self.queue.pop_back()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn start(&mut self, _p: &mut Process) -> Duration {
+        TICK
+    }
+
+    fn charge(&mut self, _p: &mut Process, _ran: Duration) {}
+}
+
+/// The number of MLFQ levels.
+const MLFQ_LEVELS: usize = 4;
+
+/// The CPU time a process may use at the top MLFQ level before it is moved
+/// down. Each level below doubles it.
+const MLFQ_ALLOTMENT: Duration = Duration::from_millis(20);
+
+/// How often every process is moved back up to its base level, so that
+/// CPU-bound processes are not starved by a stream of interactive ones and
+/// processes that turned interactive regain their priority.
+const MLFQ_BOOST: Duration = Duration::from_secs(1);
+
+/// A multilevel feedback queue.
+///
+/// Processes on a higher level always run first; processes on one level
+/// take turns. A process that uses up the allotment of its level, over any
+/// number of turns, moves down a level, so CPU-bound processes sink while
+/// processes that mostly block stay on top. A process starts at, and is
+/// periodically boosted back to, the base level of its nice value: the top
+/// level for nice values up to `4`, one level lower for each further 5.
+#[derive(Debug)]
+pub struct Mlfq {
+    /// The (ID, base level) of the ready processes of each level.
+    queues: Vec<VecDeque<(Id, usize)>>,
+    epoch: u64,
+    last_boost: Duration,
+}
+
+impl Mlfq {
+    pub fn new() -> Mlfq {
+        Mlfq {
+            queues: (0..MLFQ_LEVELS).map(|_| VecDeque::new()).collect(),
+            epoch: 0,
+            last_boost: Duration::from_secs(0),
+        }
+    }
+
+    fn base_level(nice: i64) -> usize {
+        max(nice, 0) as usize * MLFQ_LEVELS / (NICE_MAX as usize + 1)
+    }
+
+    fn allotment(level: usize) -> Duration {
+        MLFQ_ALLOTMENT * (1u32 << level)
+    }
+
+    /// Moves `p` to its base level if a boost happened since its level was
+    /// set.
+    fn sync(&self, p: &mut Process) {
+        if p.sched.epoch != self.epoch {
+            p.sched.level = Self::base_level(p.nice);
+            p.sched.used = Duration::from_secs(0);
+            p.sched.epoch = self.epoch;
+        }
+    }
+
+    /// Moves every ready process to its base level and starts a new boost
+    /// period.
+    fn boost(&mut self, now: Duration) {
+        let ready: Vec<(Id, usize)> = self.queues.iter_mut().flat_map(|queue| queue.drain(..)).collect();
+        for (id, base) in ready {
+            self.queues[base].push_back((id, base));
+        }
+        self.epoch += 1;
+        self.last_boost = now;
+    }
+}
+
+impl Policy for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+
+    fn enqueue(&mut self, p: &mut Process, why: Enqueue) {
+        if why == Enqueue::Migrated {
+            // The level was set under the other core's boost periods.
+            p.sched.epoch = self.epoch;
+        }
+        self.sync(p);
+        let base = Self::base_level(p.nice);
+        if why == Enqueue::New || p.sched.level < base {
+            p.sched.level = base;
+            p.sched.used = Duration::from_secs(0);
+        }
+        self.queues[p.sched.level].push_back((p.id(), base));
+    }
+
+    fn remove(&mut self, p: &Process) {
+        for queue in self.queues.iter_mut() {
+            queue.retain(|&(id, _)| id != p.id());
+        }
+    }
+
+    fn pick_next(&mut self, now: Duration) -> Option<Id> {
+        if now - self.last_boost >= MLFQ_BOOST {
+            self.boost(now);
+        }
+        self.queues.iter_mut().find_map(|queue| queue.pop_front()).map(|(id, _)| id)
+    }
+
+    fn steal(&mut self) -> Option<Id> {
+        self.queues.iter_mut().rev().find_map(|queue| queue.pop_back()).map(|(id, _)| id)
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn start(&mut self, p: &mut Process) -> Duration {
+        self.sync(p);
+        Self::allotment(p.sched.level) - p.sched.used
+    }
+
+    fn charge(&mut self, p: &mut Process, ran: Duration) {
+        self.sync(p);
+        p.sched.used += ran;
+        if p.sched.used >= Self::allotment(p.sched.level) {
+            p.sched.level = min(p.sched.level + 1, MLFQ_LEVELS - 1);
+            p.sched.used = Duration::from_secs(0);
+        }
+    }
+}
+
+/// The period in which every ready process should get to run once under
+/// `Cfs`, as long as there are few enough of them.
+const CFS_LATENCY: Duration = Duration::from_millis(48);
+
+/// The shortest time slice `Cfs` hands out.
+const CFS_MIN_SLICE: Duration = Duration::from_millis(6);
+
+/// A completely fair scheduler.
+///
+/// Each process accumulates virtual runtime: the CPU time it used, scaled
+/// down by its weight (see `weight()`). The process with the least virtual
+/// runtime runs next, for a share of `CFS_LATENCY` proportional to its
+/// weight, so over time processes get CPU time in proportion to their
+/// weights. A process that wakes up after blocking for a long time is
+/// credited at most half a period, so it runs soon without monopolizing the
+/// CPU.
+#[derive(Debug)]
+pub struct Cfs {
+    /// The weight of each ready process, ordered by (virtual runtime, ID).
+    queue: BTreeMap<(u64, Id), u64>,
+    /// The total weight of the ready processes.
+    load: u64,
+    /// A lower bound of the virtual runtimes of the ready and running
+    /// processes that never decreases.
+    min_vruntime: u64,
+}
+
+impl Cfs {
+    pub fn new() -> Cfs {
+        Cfs { queue: BTreeMap::new(), load: 0, min_vruntime: 0 }
+    }
+}
+
+impl Policy for Cfs {
+    fn name(&self) -> &'static str {
+        "cfs"
+    }
+
+    fn enqueue(&mut self, p: &mut Process, why: Enqueue) {
+        match why {
+            // Virtual runtimes on another core are not comparable to ours.
+            Enqueue::New | Enqueue::Migrated => p.sched.vruntime = self.min_vruntime,
+            Enqueue::Woken => {
+                let credit = CFS_LATENCY.as_nanos() as u64 / 2;
+                p.sched.vruntime = max(p.sched.vruntime, self.min_vruntime.saturating_sub(credit));
+            }
+            Enqueue::Requeued => (),
+        }
+
+        let weight = weight(p.nice);
+        self.queue.insert((p.sched.vruntime, p.id()), weight);
+        self.load += weight;
+    }
+
+    fn remove(&mut self, p: &Process) {
+        if let Some(weight) = self.queue.remove(&(p.sched.vruntime, p.id())) {
+            self.load -= weight;
+        }
+    }
+
+    fn pick_next(&mut self, _now: Duration) -> Option<Id> {
+        let key = *self.queue.keys().next()?;
+        self.load -= self.queue.remove(&key).unwrap();
+        self.min_vruntime = max(self.min_vruntime, key.0);
+        Some(key.1)
+    }
+
+    fn steal(&mut self) -> Option<Id> {
+        let key = *self.queue.keys().next_back()?;
+        self.load -= self.queue.remove(&key).unwrap();
+        Some(key.1)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn start(&mut self, p: &mut Process) -> Duration {
+        let weight = weight(p.nice);
+        let share = CFS_LATENCY.as_nanos() as u64 * weight / (self.load + weight);
+        max(CFS_MIN_SLICE, min(TICK, Duration::from_nanos(share)))
+    }
+
+    fn charge(&mut self, p: &mut Process, ran: Duration) {
+        p.sched.vruntime += ran.as_nanos() as u64 * NICE_0_WEIGHT / weight(p.nice);
+        let leftmost = self.queue.keys().next().map(|&(vruntime, _)| vruntime);
+        let current = leftmost.map(|v| min(v, p.sched.vruntime)).unwrap_or(p.sched.vruntime);
+        self.min_vruntime = max(self.min_vruntime, current);
+    }
+}
@@ -1,11 +1,17 @@
-use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
 use shim::io;
-use shim::path::Path;
+use shim::path::{Component, Path, PathBuf};
 use core::fmt;
+use core::time::Duration;
 use aarch64::regs::SPSR_EL1;
 
+use crate::allocator::{SlabBox, SlabCache};
+use crate::mutex::{Mutex, MutexGuard};
 use crate::param::*;
-use crate::process::{Stack, State};
+use crate::process::elf;
+use crate::process::policy::Entity;
+use crate::process::{AddressSpace, Event, FdTable, Reservation, Signals, Stack, State, Stats};
 use crate::traps::TrapFrame;
 use crate::vm::*;
 use kernel_api::{OsError, OsResult};
@@ -16,22 +22,94 @@ use io::Read;
 /// Type alias for the type of a process ID.
 pub type Id = u64;
 
-/// A structure that represents the complete state of a process.
+/// The cache the scheduler keeps processes in.
+pub static PROCESSES: SlabCache<Process> = SlabCache::new("process");
+
+/// The cache of the saved trap frames of processes.
+pub static TRAP_FRAMES: SlabCache<TrapFrame> = SlabCache::with_ctor("trap frame", TrapFrame::default);
+
+/// The `SPSR.M` value for EL1 using `SP_EL0` (EL1t), the mode kernel threads
+/// run in so that the exception stack stays separate from theirs.
+const SPSR_EL1_M_EL1T: u64 = 0b0100;
+
+/// The first code a kernel thread runs: calls `entry` and exits the thread.
+extern "C" fn kernel_thread_start(entry: extern "C" fn()) -> ! {
+    entry();
+    kernel_api::syscall::exit(0)
+}
+
+/// A structure that represents the complete state of a process: one thread
+/// of execution, scheduled on its own.
+///
+/// A user process starts as a single thread, its main thread. Threads
+/// created with `thread_create` are `Process`es of their own that share the
+/// `AddressSpace` of the main thread. Kernel threads have no address space
+/// and run at EL1 with only the kernel page table.
 pub struct Process {
     /// The saved trap frame of a process.
-    pub context: Box<TrapFrame>,
-    /// The memory allocation used for the process's stack.
-    // pub stack: Stack,
-    /// The page table describing the Virtual Memory of the process
-    pub vmap: UserPageTable,
+    pub context: SlabBox<TrapFrame>,
+    /// The memory allocation used for the stack of a kernel thread.
+    pub stack: Option<Stack>,
+    /// The user address space, shared by the threads of a process. `None`
+    /// for kernel threads.
+    pub space: Option<Arc<Mutex<AddressSpace>>>,
+    /// The ID of the main thread if this is a thread created with
+    /// `thread_create`, `None` for main threads and kernel threads.
+    pub thread_of: Option<Id>,
+    /// The start of the user stack `thread_create` mapped for this thread.
+    pub thread_stack: Option<usize>,
     /// The scheduling state of the process.
     pub state: State,
+    /// The nice value, from `NICE_MIN` to `NICE_MAX`. Lower values mean a
+    /// higher priority.
+    pub nice: i64,
+    /// The length of the current time slice, set by the scheduling policy
+    /// each time the process is scheduled in.
+    pub slice: Duration,
+    /// The state the scheduling policy keeps for this process.
+    pub sched: Entity,
+    /// The CPU reservation of a real-time process, which the scheduler runs
+    /// instead of leaving it to the policy. `None` for normal processes.
+    pub rt: Option<Reservation>,
+    /// The core whose run queue this process is on while it is ready, and
+    /// the core it runs or last ran on otherwise.
+    pub core: usize,
+    /// The ID of the parent process. `None` for processes started by the
+    /// kernel, which are reclaimed as soon as they exit.
+    pub parent: Option<Id>,
+    /// The child this process is blocked on in `wait`, if any. `Some(0)`
+    /// waits for any child.
+    pub wait_target: Option<Id>,
+    /// The (ID, exit status) of a child reaped on behalf of this process
+    /// while it was blocked in `wait`.
+    pub reaped: Option<(Id, u64)>,
+    /// The event whose wait queue this process is blocked on, if any.
+    pub blocked_on: Option<Event>,
+    /// The time at which the blocked system call of this process times out,
+    /// if it does. The process is on the scheduler's timer queue until then.
+    pub deadline: Option<Duration>,
+    /// The exit status of a main thread whose process another thread exited
+    /// while it ran on another core. It becomes a `Zombie` once that core
+    /// switches away from it.
+    pub exiting: Option<u64>,
+    /// The open file descriptors.
+    pub files: FdTable,
+    /// The absolute path of the working directory.
+    pub cwd: PathBuf,
+    /// Whether the system calls of this process are logged to the console.
+    pub trace: bool,
+    /// Pending and blocked signals and the registered signal actions.
+    pub signals: Signals,
+    /// CPU time and scheduling statistics.
+    pub stats: Stats,
 }
 
 impl fmt::Debug for Process {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Process")
             .field("id", &self.context.tpidr)
+            .field("thread_of", &self.thread_of)
+            .field("parent", &self.parent)
             .field("tf", &self.context)
             .field("state", &self.state)
             .finish()
@@ -45,20 +123,163 @@ impl Process {
     /// If enough memory could not be allocated to start the process, returns
     /// `None`. Otherwise returns `Some` of the new `Process`.
     pub fn new() -> OsResult<Process> {
-        let vmap = UserPageTable::new();
-        let mut context = Box::new(TrapFrame::default());
+        let space = AddressSpace::new();
+        let mut context = TRAP_FRAMES.alloc().ok_or(OsError::NoMemory)?;
         context.sp = Self::get_stack_top().as_u64();
-        kprintln!("stack pointer {:0x}", context.sp);
         context.elr = Self::get_image_base().as_u64();
         context.ttbr[0] = VMM.get_baddr().as_u64();
-        context.ttbr[1] = vmap.get_baddr().as_u64();
+        context.ttbr[1] = space.vmap.get_baddr().as_u64();
         context.spsr = SPSR_EL1::F | SPSR_EL1::A | SPSR_EL1::D;
 
-        Ok(Process {
+        Ok(Process::with_context(context, Some(Arc::new(Mutex::new(space)))))
+    }
+
+    /// Creates a kernel thread that calls `entry` on a fresh kernel stack.
+    /// The thread runs at EL1 with `SP_EL0` as its stack pointer and only
+    /// the kernel page table, and exits with status `0` if `entry` returns.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoMemory` if the stack could not be allocated.
+    pub fn kernel_thread(entry: extern "C" fn()) -> OsResult<Process> {
+        let stack = Stack::new().ok_or(OsError::NoMemory)?;
+        let mut context = TRAP_FRAMES.alloc().ok_or(OsError::NoMemory)?;
+        context.sp = stack.top().as_u64();
+        context.elr = kernel_thread_start as usize as u64;
+        context.xregs[0] = entry as usize as u64;
+        context.ttbr[0] = VMM.get_baddr().as_u64();
+        context.ttbr[1] = VMM.get_baddr().as_u64();
+        context.spsr = SPSR_EL1::F | SPSR_EL1::A | SPSR_EL1::D | SPSR_EL1_M_EL1T;
+
+        let mut process = Process::with_context(context, None);
+        process.stack = Some(stack);
+        Ok(process)
+    }
+
+    /// Creates a thread of `self` that starts at `entry` with `arg0` and
+    /// `arg1` in `x0` and `x1`, on a new user stack mapped in the shared
+    /// address space. The thread inherits the nice value, the working
+    /// directory and a copy of the descriptor table; the descriptors
+    /// themselves are shared.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` for kernel threads and the errors
+    /// of `AddressSpace::mmap()` if the stack cannot be mapped.
+    pub fn create_thread(&self, entry: u64, arg0: u64, arg1: u64) -> OsResult<Process> {
+        let space = self.space.clone().ok_or(OsError::InvalidArgument)?;
+        let stack = space.lock().mmap(0, Stack::SIZE, PagePerm::RW, false)?;
+
+        let mut context = TRAP_FRAMES.boxed(*self.context).ok_or(OsError::NoMemory)?;
+        context.elr = entry;
+        context.sp = (stack + Stack::SIZE) as u64;
+        context.xregs = [0; 32];
+        context.xregs[0] = arg0;
+        context.xregs[1] = arg1;
+        context.qregs = [0; 32];
+
+        let mut thread = Process::with_context(context, Some(space));
+        thread.thread_of = Some(self.main_thread());
+        thread.thread_stack = Some(stack);
+        thread.nice = self.nice;
+        thread.cwd = self.cwd.clone();
+        thread.files = self.files.clone();
+        Ok(thread)
+    }
+
+    /// Returns a `Ready` process with `context` and `space` and otherwise
+    /// default state.
+    fn with_context(context: SlabBox<TrapFrame>, space: Option<Arc<Mutex<AddressSpace>>>) -> Process {
+        Process {
             context,
-            vmap,
+            stack: None,
+            space,
+            thread_of: None,
+            thread_stack: None,
             state: State::Ready,
-        })
+            nice: 0,
+            slice: Duration::from_secs(0),
+            sched: Entity::default(),
+            rt: None,
+            core: 0,
+            parent: None,
+            wait_target: None,
+            reaped: None,
+            blocked_on: None,
+            deadline: None,
+            exiting: None,
+            files: FdTable::new(),
+            cwd: PathBuf::from("/"),
+            trace: false,
+            signals: Signals::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Returns the ID of this process.
+    pub fn id(&self) -> Id {
+        self.context.tpidr
+    }
+
+    /// Returns the ID of the main thread of this process: its own ID unless
+    /// it was created with `thread_create`.
+    pub fn main_thread(&self) -> Id {
+        self.thread_of.unwrap_or(self.id())
+    }
+
+    /// Locks and returns the address space of this process.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` for kernel threads, which have no user
+    /// address space.
+    pub fn space(&self) -> OsResult<MutexGuard<AddressSpace>> {
+        self.space.as_ref().map(|space| space.lock()).ok_or(OsError::BadAddress)
+    }
+
+    /// Returns a new reference to the address space of this process, for use
+    /// outside of the scheduler's critical section.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` for kernel threads, which have no user
+    /// address space.
+    pub fn shared_space(&self) -> OsResult<Arc<Mutex<AddressSpace>>> {
+        self.space.clone().ok_or(OsError::BadAddress)
+    }
+
+    /// Resolves `path` against the working directory and returns the
+    /// resulting absolute path with `.` and `..` components removed.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut resolved = PathBuf::from("/");
+        for component in self.cwd.join(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                _ => (),
+            }
+        }
+        resolved
+    }
+
+    /// Sends signal `sig` to this process. The signal is acted on the next
+    /// time the process returns to user mode. Signals sent to a zombie are
+    /// discarded. Waking the process so that this happens is up to the
+    /// scheduler (see `Scheduler::signal()`).
+    pub fn signal(&mut self, sig: u64) {
+        if !self.is_zombie() {
+            self.signals.raise(sig);
+        }
+    }
+
+    /// Returns `true` if this process has exited but has not been reaped.
+    pub fn is_zombie(&self) -> bool {
+        match self.state {
+            State::Zombie(_) => true,
+            _ => false,
+        }
     }
 
     /// Load a program stored in the given path by calling `do_load()` method.
@@ -70,45 +291,59 @@ impl Process {
     /// `spsr` - `F`, `A`, `D` bit should be set.
     ///
     /// Returns Os Error if do_load fails.
-    /// Creates a process and open a file with given path.
-    /// Allocates one page for stack with read/write permission, and N pages with read/write/execute
-    /// permission to load file's contents.
     pub fn load<P: AsRef<Path>>(path: P) -> OsResult<Process> {
+        let proc = Process::do_load(&path)?;
+        kprintln!("loaded {:?}, entry {:#x}", path.as_ref(), proc.context.elr);
+        Ok(proc)
+    }
+
+    /// Creates a process and open a file with given path.
+    ///
+    /// ELF64 executables are mapped segment by segment (see `AddressSpace::load_elf()`).
+    /// Any other file is treated as a flat image and copied byte-for-byte to
+    /// `USER_IMG_BASE` with read/write/execute permission. In both cases, the
+    /// stack is allocated with read/write permission.
+    fn do_load<P: AsRef<Path>>(path: P) -> OsResult<Process> {
         let mut file = FILESYSTEM.open(&path)?.into_file().ok_or(OsError::IoErrorInvalidInput)?;
 
-        let mut addr = USER_IMG_BASE;
-        let mut proc = Process::new()?;
-        let mut page = proc.vmap.alloc(addr.into(), PagePerm::RWX);
+        let mut image = vec![0u8; file.size() as usize];
         let mut total = 0;
-        loop {
-            match file.read(&mut page) {
+        while total < image.len() {
+            match file.read(&mut image[total..]) {
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(e) => return Err(e.into()),
-                Ok(n) => {
-                    total += n;
-                    if n == 0 {
-                        // there's a boundary condition if the file ends at a page boundary
-                        // we will end up allocating a page but not using it, we should de-allocate
-                        // in that case
-                        break;
-                    }
-                    if n == page.len() {
-                        addr += PAGE_SIZE;
-                        page = proc.vmap.alloc(addr.into(), PagePerm::RWX);
-                    } else {
-                        page = &mut page[n..];
-                    }
-                }
+                Ok(0) => return Err(OsError::IoErrorEof),
+                Ok(n) => total += n,
             }
         }
-        kprintln!("read {} bytes for file {:?}", total, path.as_ref());
-        // allocate stack
-        for addr in (Self::get_stack_base().as_usize() .. Self::get_stack_top().as_usize()).step_by(PAGE_SIZE) {
-            let _ = proc.vmap.alloc(addr.into(), PagePerm::RW);
-        }
 
+        let mut proc = Process::new()?;
+        let entry = {
+            let mut space = proc.space()?;
+            let entry = if elf::is_elf(&image) {
+                space.load_elf(&image)?
+            } else {
+                space.load_flat(&image)?;
+                Self::get_image_base().as_u64()
+            };
+
+            // allocate stack
+            for addr in (Self::get_stack_base().as_usize() .. Self::get_stack_top().as_usize()).step_by(PAGE_SIZE) {
+                space.vmap.alloc(addr.into(), PagePerm::RW)?;
+            }
+            entry
+        };
+
+        proc.context.elr = entry;
         Ok(proc)
-        // unimplemented!();
+    }
+
+    /// Unmaps the user stack of a thread created with `thread_create`, which
+    /// has exited.
+    pub fn release_thread_stack(&mut self) {
+        if let (Some(stack), Some(space)) = (self.thread_stack.take(), self.space.as_ref()) {
+            let _ = space.lock().munmap(stack, Stack::SIZE);
+        }
     }
 
     /// Returns the highest `VirtualAddr` that is supported by this system.
@@ -139,33 +374,66 @@ impl Process {
         // unimplemented!();
     }
 
-    /// Returns `true` if this process is ready to be scheduled.
-    ///
-    /// This functions returns `true` only if one of the following holds:
-    ///
-    ///   * The state is currently `Ready`.
-    ///
-    ///   * An event being waited for has arrived.
-    ///
-    ///     If the process is currently waiting, the corresponding event
-    ///     function is polled to determine if the event being waiting for has
-    ///     occured. If it has, the state is switched to `Ready` and this
-    ///     function returns `true`.
+    /// Returns `true` if this process is ready to run, waiting on a run
+    /// queue.
+    pub fn is_ready(&self) -> bool {
+        match self.state {
+            State::Ready => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if this process is blocked.
+    pub fn is_blocked(&self) -> bool {
+        match self.state {
+            State::Blocked(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Changes the state of this process to `state`, counting the transition
+    /// in its statistics.
+    pub fn set_state(&mut self, state: State) {
+        self.stats.entered(&state);
+        self.state = state;
+    }
+
+    /// Runs the wake function of this blocked process, with `expired` set if
+    /// its deadline passed, and makes the process `Ready` if the function
+    /// finished the blocked system call. Returns whether it did.
     ///
-    /// Returns `false` in all other cases.
-    pub fn is_ready(&mut self) -> bool {
+    /// The process is left blocked, and `false` is returned, if it is not
+    /// blocked or the event it waits for has not really occurred.
+    pub fn resume(&mut self, expired: bool) -> bool {
         // temporarily replace so we can avoid borrowing self multiple times :((
         let mut state = core::mem::replace(&mut self.state, State::Ready);
-
-        let is_ready = match state {
-            State::Ready => true,
-            State::Waiting(ref mut f) => f(self),
+        let resumed = match state {
+            State::Blocked(ref mut f) => f(self, expired),
             _ => false,
         };
 
-        if !is_ready {
-            core::mem::replace(&mut self.state, state);
+        if resumed {
+            self.blocked_on = None;
+            self.deadline = None;
+            self.stats.entered(&self.state);
+        } else {
+            self.state = state;
+        }
+        resumed
+    }
+
+    /// Fails the blocked system call of this process with
+    /// `OsError::Interrupted` and makes the process `Ready` if a pending
+    /// signal should interrupt it. Returns whether it did.
+    pub fn interrupt(&mut self) -> bool {
+        if !self.is_blocked() || !self.signals.interrupts() {
+            return false;
         }
-        is_ready
+        self.wait_target = None;
+        self.blocked_on = None;
+        self.deadline = None;
+        self.context.xregs[7] = OsError::Interrupted as u64;
+        self.set_state(State::Ready);
+        true
     }
 }
@@ -1,54 +1,72 @@
 use alloc::boxed::Box;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use core::fmt;
+use core::time::Duration;
 
 use aarch64::*;
 
-use crate::mutex::Mutex;
-use crate::param::{PAGE_MASK, PAGE_SIZE, TICK, USER_IMG_BASE, KERN_STACK_BASE};
-use crate::process::{Id, Process, State};
+use crate::allocator::SlabBox;
+use crate::mutex::{IrqMutex, Rank};
+use crate::param::NCORES;
+use crate::percore;
+use crate::process::policy::{self, Enqueue, Mlfq};
+use crate::process::{Event, Id, Policy, Process, State, WakeFn, PROCESSES};
 use crate::traps::TrapFrame;
-use crate::{VMM, IRQ};
+use crate::IRQ;
 use crate::shell;
-use crate::console::kprintln;
+use crate::console::{kprintln, CONSOLE, CONSOLE_INPUT};
 
-use pi::interrupt as intr;
-use kernel_api::syscall;
+use pi::local_interrupt::LocalInterrupt;
+use kernel_api::{OsError, OsResult, ProcInfo, SCHED_DEADLINE, SCHED_NORMAL, SIGCONT, SIGKILL};
+
+/// The ID of the init process: the first process added to the scheduler.
+/// Orphaned processes are re-parented to it.
+pub const INIT_PID: Id = 1;
+
+/// How soon a tick that found the running kernel thread holding a spinlock
+/// is retried.
+const PREEMPT_RETRY: Duration = Duration::from_millis(1);
+
+/// How often a core checks for console input while somebody waits for it.
+const CONSOLE_POLL: Duration = Duration::from_millis(10);
+
+/// The share of a core, in parts per million, that real-time processes may
+/// reserve. The rest is left to normal processes and to the kernel.
+const RT_UTIL_MAX: u64 = 900_000;
+
+/// The smallest budget a real-time process may reserve per period.
+const RT_BUDGET_MIN: Duration = Duration::from_millis(1);
 
 /// Process scheduler for the entire machine.
 #[derive(Debug)]
-pub struct GlobalScheduler(Mutex<Option<Scheduler>>);
-
-#[no_mangle]
-extern "C" fn roflcopter() {
-    // unsafe { asm!("brk 1" :::: "volatile"); }
-    // unsafe { asm!("brk 2" :::: "volatile"); }
-    shell::shell("user0> ");
-    // unsafe { asm!("brk 3" :::: "volatile"); }
-    // loop { shell::shell("user1> "); }
-}
+pub struct GlobalScheduler(IrqMutex<Option<Scheduler>>);
 
-#[no_mangle]
-extern "C" fn print1() {
+/// The idle task of a core, run when no process is ready. It waits for
+/// interrupts, one of which switches away from it.
+extern "C" fn idle() {
     loop {
-        kprintln!("one");
-        pi::timer::spin_sleep(core::time::Duration::from_millis(500));
+        aarch64::wfi();
     }
 }
 
-#[no_mangle]
-extern "C" fn print2() {
+/// The kernel shell, run as a kernel thread next to the user processes.
+extern "C" fn kernel_shell() {
     loop {
-        kprintln!("I am two");
-        let _ = syscall::sleep(core::time::Duration::from_millis(2000));
+        shell::shell("> ");
     }
 }
 
+/// Interrupts the job the kernel shell waits for when Ctrl-C is typed.
+extern "C" fn console_watcher() {
+    shell::watch_console()
+}
+
 impl GlobalScheduler {
     /// Returns an uninitialized wrapper around a local scheduler.
     pub const fn uninitialized() -> GlobalScheduler {
-        GlobalScheduler(Mutex::new(None))
+        GlobalScheduler(IrqMutex::ranked(None, Rank::Scheduler))
     }
 
     /// Enter a critical region and execute the provided closure with the
@@ -67,6 +85,16 @@ impl GlobalScheduler {
         self.critical(|scheduler| scheduler.add(process))
     }
 
+    /// Executes the provided closure with the process whose ID is `id`.
+    ///
+    /// Returns `OsError::NoEntry` if there is no such process.
+    pub fn with_process<F, R>(&self, id: Id, f: F) -> OsResult<R>
+    where
+        F: FnOnce(&mut Process) -> R,
+    {
+        self.critical(|scheduler| scheduler.find_mut(id).map(f).ok_or(OsError::NoEntry))
+    }
+
     /// Performs a context switch using `tf` by setting the state of the current
     /// process to `new_state`, saving `tf` into the current process, and
     /// restoring the next process's trap frame into `tf`. For more details, see
@@ -82,48 +110,189 @@ impl GlobalScheduler {
         self.switch_to(tf)
     }
 
+    /// Wakes the processes whose deadline has passed, restores the next
+    /// ready process's trap frame into `tf` and returns its ID. If no
+    /// process is ready for this core, its idle task is switched to instead
+    /// and `IDLE_PID` is returned. See the documentation on
+    /// `Scheduler::switch_to()`.
     pub fn switch_to(&self, tf: &mut TrapFrame) -> Id {
-        loop {
-            let rtn = self.critical(|scheduler| scheduler.switch_to(tf));
-            if let Some(id) = rtn {
-                return id;
+        let now = pi::timer::current_time();
+        self.critical(|scheduler| {
+            scheduler.poll(now);
+            scheduler.switch_to(tf, now)
+        })
+    }
+
+    /// Terminates the currently running process with exit status `status`
+    /// and returns that process's ID.
+    /// For more details, see the documentaion on `Scheduler::exit()`.
+    #[must_use]
+    pub fn exit(&self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        self.critical(|scheduler| scheduler.exit(status, tf))
+    }
+
+    /// Terminates the currently running thread with exit status `status`
+    /// and returns its ID. For more details, see the documentation on
+    /// `Scheduler::exit_thread()`.
+    #[must_use]
+    pub fn exit_thread(&self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        self.critical(|scheduler| scheduler.exit_thread(status, tf))
+    }
+
+    /// Reaps an exited child process of the process running in `tf`. See the
+    /// documentation on `Scheduler::reap()`.
+    pub fn reap(&self, child: Id, tf: &TrapFrame) -> OsResult<Option<(Id, u64)>> {
+        self.critical(|scheduler| scheduler.reap(tf.tpidr, child, false))
+    }
+
+    /// Reaps the exited thread `thread` created by the thread running in
+    /// `tf`. See the documentation on `Scheduler::reap()`.
+    pub fn join(&self, thread: Id, tf: &TrapFrame) -> OsResult<Option<(Id, u64)>> {
+        self.critical(|scheduler| scheduler.reap(tf.tpidr, thread, true))
+    }
+
+    /// Handles a timer tick: wakes the processes whose deadline has passed
+    /// and preempts the process running in `tf` in favor of the next ready
+    /// one.
+    ///
+    /// A kernel thread holding a `Mutex` is not preempted, since the next
+    /// process on this core could spin on that lock forever; the tick is
+    /// retried shortly instead.
+    pub fn tick(&self, tf: &mut TrapFrame) -> Id {
+        if !percore::preemptible() {
+            percore::local_tick_in(PREEMPT_RETRY);
+            return tf.tpidr;
+        }
+
+        self.critical(|scheduler| {
+            scheduler.poll(pi::timer::current_time());
+            scheduler.balance(percore::getcpu());
+        });
+        self.switch(State::Ready, tf)
+    }
+
+    /// Handles an inter-processor interrupt: switches to the next process if
+    /// the core is idle or the process running in `tf` was removed while it
+    /// ran, say because another of its threads exited the process, and
+    /// schedules out a main thread whose process was exited that way.
+    /// Otherwise, a process was queued on the core, which may need its timer
+    /// again to share the core.
+    pub fn reschedule(&self, tf: &mut TrapFrame) {
+        let core = percore::getcpu();
+        let now = pi::timer::current_time();
+        let (switch, exiting) = self.critical(|scheduler| {
+            if scheduler.cores[core].current != Some(tf.tpidr) {
+                return (true, false);
+            }
+            if scheduler.find_mut(tf.tpidr).map_or(false, |p| p.exiting.is_some()) {
+                return (true, true);
             }
-            aarch64::wfi();
+            scheduler.arm(now);
+            (false, false)
+        });
+        if exiting {
+            self.switch(State::Ready, tf);
+        } else if switch {
+            self.switch_to(tf);
         }
     }
 
-    /// Kills currently running process and returns that process's ID.
-    /// For more details, see the documentaion on `Scheduler::kill()`.
-    #[must_use]
-    pub fn kill(&self, tf: &mut TrapFrame) -> Option<Id> {
-        self.critical(|scheduler| scheduler.kill(tf))
+    /// Blocks the process running in `tf` until `wake` finishes its system
+    /// call, and switches to the next process. See the documentation on
+    /// `Scheduler::block()`.
+    pub fn block(&self, event: Option<Event>, deadline: Option<Duration>, wake: WakeFn, tf: &mut TrapFrame) -> Id {
+        self.critical(|scheduler| scheduler.block(event, deadline, wake, tf));
+        self.switch_to(tf)
     }
 
-    /// Starts executing processes in user space using timer interrupt based
-    /// preemptive scheduling. This method should not return under normal conditions.
-    pub fn start(&self) -> ! {
-        let mut controller = intr::Controller::new();
-        controller.enable(intr::Interrupt::Timer1);
+    /// Wakes up to `n` processes blocked on `event` and returns how many
+    /// were woken. See the documentation on `Scheduler::wake()`.
+    pub fn wake(&self, event: Event, n: usize) -> usize {
+        self.critical(|scheduler| scheduler.wake(event, n))
+    }
 
-        pi::timer::tick_in(TICK);
-        IRQ.register(intr::Interrupt::Timer1, Box::new(|tf: &mut TrapFrame| {
-            pi::timer::tick_in(TICK);
-            crate::SCHEDULER.switch(State::Ready, tf);
-        }));
+    /// Sets the nice value of the process `id` to `nice`. See the
+    /// documentation on `Scheduler::set_nice()`.
+    pub fn set_nice(&self, id: Id, nice: i64) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.set_nice(id, nice))
+    }
 
-        // we need to bootstrap the first process; at the very least we need to
-        // "eret" to EL0 with _some_ reasonable PC
-        let tf_ptr = self.critical(|s| {
-            let tf_box_ref: &Box<_> = &s.processes.front().unwrap().context;
-            &*(*tf_box_ref) as *const TrapFrame as usize
+    /// Makes the process `id` a real-time process with a reservation of
+    /// `budget` every `period`, or a normal one for `None`. See the
+    /// documentation on `Scheduler::set_reservation()`.
+    pub fn set_reservation(&self, id: Id, reservation: Option<(Duration, Duration)>) -> OsResult<()> {
+        let now = pi::timer::current_time();
+        self.critical(|scheduler| scheduler.set_reservation(id, reservation, now))
+    }
+
+    /// Returns information about every process. See the documentation on
+    /// `Scheduler::procinfo()`.
+    pub fn procinfo(&self) -> Vec<ProcInfo> {
+        let now = pi::timer::current_time();
+        self.critical(|scheduler| scheduler.procinfo(now))
+    }
+
+    /// Returns the name of the scheduling policy in use.
+    pub fn policy(&self) -> &'static str {
+        self.critical(|scheduler| scheduler.cores[0].policy.name())
+    }
+
+    /// Replaces the scheduling policy of every core with the policy named
+    /// `name`. See the documentation on `Scheduler::set_policy()`.
+    pub fn set_policy(&self, name: &str) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.set_policy(name))
+    }
+
+    /// Sends signal `sig` to the process `id`. See the documentation on
+    /// `Scheduler::signal()`.
+    pub fn signal(&self, id: Id, sig: u64) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.signal(id, sig))
+    }
+
+    /// Blocks the process running in `tf` on the futex word at physical
+    /// address `key` and switches to the next process, unless the word no
+    /// longer holds `expected`. See the documentation on
+    /// `Scheduler::futex_wait()`.
+    pub fn futex_wait(
+        &self,
+        key: usize,
+        expected: u32,
+        deadline: Option<Duration>,
+        wake: WakeFn,
+        tf: &mut TrapFrame,
+    ) -> OsResult<()> {
+        self.critical(|scheduler| scheduler.futex_wait(key, expected, deadline, wake, tf))?;
+        self.switch_to(tf);
+        Ok(())
+    }
+
+    /// Starts executing processes on the current core using timer interrupt
+    /// based preemptive scheduling. Every core calls this once it is set up.
+    /// This method should not return under normal conditions.
+    pub fn start(&self) -> ! {
+        IRQ.register_local(LocalInterrupt::CntPnsIrq, |tf: &mut TrapFrame| {
+            crate::SCHEDULER.tick(tf);
         });
+        IRQ.register_local(LocalInterrupt::Mailbox0, |tf: &mut TrapFrame| {
+            percore::take_ipi();
+            crate::SCHEDULER.reschedule(tf);
+        });
+        percore::enable_local_timer();
+        percore::enable_ipi();
+
+        // we need to bootstrap the first process; at the very least we need to
+        // "eret" to EL0 with _some_ reasonable PC. The frame is restored from
+        // this stack frame, which is never returned to, so the stack pointer
+        // is left right above it and the rest of this core's stack is free
+        // for exceptions.
+        let mut tf = TrapFrame::default();
+        self.switch_to(&mut tf);
+        let tf_ptr = &tf as *const TrapFrame as usize;
         unsafe {
             asm!("mov sp, $0" :: "r"(tf_ptr) :: "volatile");
             asm!("bl context_restore");
             asm!("ldp x28, x29, [SP], #16");
             asm!("ldp lr, xzr, [SP], #16");
-            // "N" prefix so we force using a 64-bit register for moving
-            asm!("mov sp, $0" ::"N"(KERN_STACK_BASE):"x0":"volatile");
             asm!("eret");
         }
         loop {
@@ -131,168 +300,1020 @@ impl GlobalScheduler {
         }
     }
 
-    /// Initializes the scheduler and add userspace processes to the Scheduler
+    /// Initializes the scheduler with the kernel shell and the console
+    /// watcher as its first kernel threads.
     pub unsafe fn initialize(&self) {
         let mut scheduler = Scheduler::new();
+        let shell = Process::kernel_thread(kernel_shell).expect("failed to create the kernel shell");
+        scheduler.add(shell).expect("failed to add the kernel shell");
+        let watcher = Process::kernel_thread(console_watcher).expect("failed to create the console watcher");
+        scheduler.add(watcher).expect("failed to add the console watcher");
+        *self.0.lock() = Some(scheduler);
+    }
+}
 
-        // {
-        //     let mut p = Process::new().unwrap();
-        //     p.context.elr = roflcopter as u64;
-        //     scheduler.add(p).unwrap();
-        // }
-        // {
-        //     let mut p = Process::new().unwrap();
-        //     self.test_phase_3(&mut p);
-
-        //     kprintln!("allocating a process now");
-        //     kprintln!("Kernel page table: {:#?}", VMM.debug_table());
-        //     kprintln!("User page table: {:#?}", p.vmap);
-
-        //     scheduler.add(p).unwrap();
-        // }
-        {
-            let mut p = Process::load("/bin/sleep.bin").unwrap();
-            scheduler.add(p).unwrap();
-        }
-        {
-            let mut p = Process::load("/bin/fib.bin").unwrap();
-            // kprintln!("Kernel page table: {:#?}", VMM.debug_table());
-            // kprintln!("User page table: {:#?}", p.vmap);
-            scheduler.add(p).unwrap();
-        }
-        {
-            let mut p = Process::load("/bin/sleep.bin").unwrap();
-            scheduler.add(p).unwrap();
-        }
-        {
-            let mut p = Process::load("/bin/sleep.bin").unwrap();
-            scheduler.add(p).unwrap();
-        }
-        // {
-        //     let mut p = Process::load("/bin/fib.bin").unwrap();
-        //     kprintln!("allocating another process now");
-        //     scheduler.add(p).unwrap();
-        // }
+/// The ID the idle tasks run with. No process has it.
+pub const IDLE_PID: Id = 0;
 
-        *self.0.lock() = Some(scheduler);
+/// The CPU reservation of a real-time (`SCHED_DEADLINE`) process: `budget`
+/// of CPU time in every `period`, scheduled earliest deadline first.
+///
+/// A process that uses up its budget is throttled until its period ends, so
+/// it cannot take more than its share however long it runs.
+#[derive(Debug, Copy, Clone)]
+pub struct Reservation {
+    /// The core the process was admitted on. It only runs there.
+    pub core: usize,
+    pub budget: Duration,
+    pub period: Duration,
+    /// The end of the current period.
+    pub deadline: Duration,
+    /// The budget left in the current period.
+    pub remaining: Duration,
+    /// The number of periods that ended before the process got its budget
+    /// while it still wanted to run.
+    pub misses: u64,
+}
+
+impl Reservation {
+    fn new(core: usize, budget: Duration, period: Duration, now: Duration) -> Reservation {
+        Reservation { core, budget, period, deadline: now + period, remaining: budget, misses: 0 }
     }
 
-    // The following method may be useful for testing Phase 3:
-    //
-    // * A method to load a extern function to the user process's page table.
-    //
-    pub fn test_phase_3(&self, proc: &mut Process){
-        use crate::vm::{PagePerm};
+    /// Returns the share of a core the reservation takes, in parts per
+    /// million.
+    fn util(&self) -> u64 {
+        (self.budget.as_nanos() * 1_000_000 / self.period.as_nanos()) as u64
+    }
 
-        let page = proc.vmap.alloc(USER_IMG_BASE.into(), PagePerm::RWX);
-        let text = unsafe {
-            core::slice::from_raw_parts(test_user_process as *const u8, 24)
-        };
+    /// Starts a new period at `now` with the full budget.
+    fn renew(&mut self, now: Duration) {
+        self.deadline = now + self.period;
+        self.remaining = self.budget;
+    }
+
+    /// Counts a deadline miss and starts a new period if the current one
+    /// ended by `now` with budget left, which the process, being ready or
+    /// running, still wanted to use.
+    fn expire(&mut self, now: Duration) {
+        if now >= self.deadline && self.remaining > Duration::from_secs(0) {
+            self.misses += 1;
+            self.renew(now);
+        }
+    }
 
-        page[0..24].copy_from_slice(text);
+    /// Charges the process for running `ran` up to `now`.
+    fn charge(&mut self, ran: Duration, now: Duration) {
+        self.remaining = self.remaining.checked_sub(ran).unwrap_or_default();
+        self.expire(now);
+    }
+
+    /// Handles the process waking up at `now`. If the budget left would
+    /// take it over its share of the core before the deadline, a new period
+    /// starts, so that a process which blocked cannot save up budget and
+    /// crowd out the others later.
+    fn wake(&mut self, now: Duration) {
+        let left = self.deadline.checked_sub(now).unwrap_or_default();
+        if self.remaining.as_nanos() * self.period.as_nanos() > left.as_nanos() * self.budget.as_nanos() {
+            self.renew(now);
+        }
+    }
+}
+
+/// The scheduling state of one core.
+#[derive(Debug)]
+struct Core {
+    /// The scheduling policy, which keeps the core's run queue of `Ready`
+    /// normal processes.
+    policy: Box<dyn Policy>,
+    /// The (deadline, ID) of the `Ready` real-time processes with budget
+    /// left, earliest deadline first. They run before any normal process.
+    rt: BTreeSet<(Duration, Id)>,
+    /// The share of the core reserved by the real-time processes admitted
+    /// on it, in parts per million.
+    rt_util: u64,
+    /// The process running on the core, if any. `None` while the idle task
+    /// runs.
+    current: Option<Id>,
+    /// When `current` was scheduled in.
+    switched_at: Duration,
+    /// The idle task. It keeps no state, so its trap frame is never saved:
+    /// it starts afresh each time it is switched to.
+    idle: Process,
+}
+
+impl Core {
+    fn new(policy: Box<dyn Policy>) -> Core {
+        let mut task = Process::kernel_thread(idle).expect("failed to create idle task");
+        task.context.tpidr = IDLE_PID;
+        Core { policy, rt: BTreeSet::new(), rt_util: 0, current: None, switched_at: Duration::from_secs(0), idle: task }
+    }
+
+    /// Returns the number of processes the core has to run: the ready ones
+    /// and the running one.
+    fn load(&self) -> usize {
+        self.policy.len() + self.rt.len() + self.current.is_some() as usize
     }
 }
 
 #[derive(Debug)]
 pub struct Scheduler {
-    processes: VecDeque<Process>,
+    /// Every process, by ID.
+    processes: BTreeMap<Id, SlabBox<Process>>,
+    /// The scheduling state of each core.
+    cores: Vec<Core>,
     last_id: Option<Id>,
+    /// The wait queues of events. A queue may hold stale IDs of processes
+    /// that timed out, were interrupted or exited; they are skipped when the
+    /// event is woken.
+    waiters: BTreeMap<Event, VecDeque<Id>>,
+    /// The (deadline, ID) of the blocked processes that have a deadline,
+    /// earliest first.
+    timers: BTreeSet<(Duration, Id)>,
+    /// The (end of period, ID) of the `Ready` real-time processes that used
+    /// up their budget, earliest first. They are queued again with a new
+    /// budget when their period ends.
+    throttled: BTreeSet<(Duration, Id)>,
 }
 
 impl Scheduler {
     /// Returns a new `Scheduler` with an empty queue.
     fn new() -> Scheduler {
         Scheduler {
-            processes: VecDeque::new(),
+            processes: BTreeMap::new(),
+            cores: (0..NCORES).map(|_| Core::new(Box::new(Mlfq::new()))).collect(),
             last_id: None,
+            waiters: BTreeMap::new(),
+            timers: BTreeSet::new(),
+            throttled: BTreeSet::new(),
         }
     }
 
     /// Adds a process to the scheduler's queue and returns that process's ID if
     /// a new process can be scheduled. The process ID is newly allocated for
     /// the process and saved in its `trap_frame`. If no further processes can
-    /// be scheduled, returns `None`.
+    /// be scheduled, returns `None`. The process is queued on the core with
+    /// the least load.
     ///
     /// It is the caller's responsibility to ensure that the first time `switch`
     /// is called, that process is executing on the CPU.
-    fn add(&mut self, mut process: Process) -> Option<Id> {
-        self.last_id = match self.last_id {
-            None => Some(1),
-            Some(lid) => lid.checked_add(1)
+    fn add(&mut self, process: Process) -> Option<Id> {
+        let id = match self.last_id {
+            None => 1,
+            Some(lid) => lid.checked_add(1)?,
         };
-        process.context.tpidr = self.last_id?;
-        self.processes.push_back(process);
+        // The ID is only used up once the process is in the table.
+        let mut process = PROCESSES.boxed(process)?;
+        self.last_id = Some(id);
+        process.context.tpidr = id;
+        process.set_state(State::Ready);
+        process.core = (0..NCORES).min_by_key(|&core| self.cores[core].load()).unwrap();
+        self.cores[process.core].policy.enqueue(&mut process, Enqueue::New);
+        self.kick(process.core);
+        self.processes.insert(id, process);
 
-        self.last_id
+        Some(id)
     }
 
     /// Finds the currently running process, sets the current process's state
-    /// to `new_state`, prepares the context switch on `tf` by saving `tf`
-    /// into the current process, and push the current process back to the
-    /// end of `processes` queue.
+    /// to `new_state` and prepares the context switch on `tf` by saving `tf`
+    /// into the current process. The process is charged for the time it ran,
+    /// against its budget if it is a real-time process and by the policy
+    /// otherwise, and in its statistics. If scheduled out as `Ready`, it goes
+    /// back on its run queue.
     ///
-    /// If the `processes` queue is empty or there is no current process,
+    /// A main thread whose process was exited by another thread while it ran
+    /// (see `exit()`) becomes a `Zombie` instead, whatever `new_state` is.
+    ///
+    /// If there is no current process or it became a `Zombie` that way,
     /// returns `false`. Otherwise, returns `true`.
     fn schedule_out(&mut self, new_state: State, tf: &mut TrapFrame) -> bool {
-        // kprintln!("trying to schedule out PID ({}) with state {:?}", tf.tpidr, new_state);
-        if let Some(pos) = self.processes.iter().position(|p| p.context.tpidr == tf.tpidr) {
-            // what if the status of this process is not running?
-            let mut proc = self.processes.remove(pos).unwrap();
-            proc.state = new_state;
-            *proc.context = *tf;
-            self.processes.push_back(proc);
-            true
-        } else {
-            false
-        }
-    }
-
-    /// Finds the next process to switch to, brings the next process to the
-    /// front of the `processes` queue, changes the next process's state to
-    /// `Running`, and performs context switch by restoring the next process`s
-    /// trap frame into `tf`.
+        debug_assert!(percore::preemptible(), "process {} scheduled out holding a spinlock", tf.tpidr);
+
+        let core = &mut self.cores[percore::getcpu()];
+        if core.current != Some(tf.tpidr) {
+            return false;
+        }
+        core.current = None;
+
+        let now = pi::timer::current_time();
+        let ran = now.checked_sub(core.switched_at).unwrap_or_default();
+        let proc = match self.processes.get_mut(&tf.tpidr) {
+            Some(proc) => proc,
+            None => return false,
+        };
+
+        match proc.rt.as_mut() {
+            Some(rt) => rt.charge(ran, now),
+            None => core.policy.charge(proc, ran),
+        }
+        let (user, kernel) = percore::take_cpu_times();
+        proc.stats.charge(user, kernel);
+        *proc.context = *tf;
+        if let Some(status) = proc.exiting.take() {
+            proc.set_state(State::Zombie(status));
+            self.bury(tf.tpidr, status);
+            return false;
+        }
+        proc.set_state(new_state);
+        if let State::Ready = proc.state {
+            match proc.rt {
+                Some(_) => self.enqueue_rt(tf.tpidr, now),
+                None => core.policy.enqueue(proc, Enqueue::Requeued),
+            }
+        }
+        true
+    }
+
+    /// Takes the next process to run on the current core off its run queue
+    /// and changes its state to `Running`: the ready real-time process with
+    /// the earliest deadline, if any, and otherwise the process the policy
+    /// picks. If both run queues are empty, a process is stolen from the
+    /// busiest other core.
     ///
-    /// If there is no process to switch to, returns `None`. Otherwise, returns
-    /// `Some` of the next process`s process ID.
-    fn switch_to(&mut self, tf: &mut TrapFrame) -> Option<Id> {
-        // kprintln!("Finding a process to switch to...");
-        let pos = self.processes.iter_mut().position(|p| p.is_ready())?;
-        let mut proc = self.processes.remove(pos).unwrap();
-        proc.state = State::Running;
-        *tf = *proc.context;
-        self.processes.push_front(proc);
-        Some(tf.tpidr)
-    }
-
-    /// Kills currently running process by scheduling out the current process
-    /// as `Dead` state. Removes the dead process from the queue, drop the
-    /// dead process's instance, and returns the dead process's process ID.
-    fn kill(&mut self, tf: &mut TrapFrame) -> Option<Id> {
-        kprintln!("[scheduler] attempting to kill process {} #processes = {}", tf.tpidr, self.processes.len());
-        if self.schedule_out(State::Dead, tf) {
-            self.processes.pop_back()?;
-            Some(tf.tpidr)
-        } else {
-            None
+    /// A real-time process runs for what is left of its budget.
+    fn next(&mut self, now: Duration) -> Option<&mut Process> {
+        let core = percore::getcpu();
+        let id = match self.cores[core].rt.iter().next() {
+            Some(&(deadline, id)) => {
+                self.cores[core].rt.remove(&(deadline, id));
+                id
+            }
+            None => loop {
+                let id = match self.cores[core].policy.pick_next(now) {
+                    Some(id) => id,
+                    None => {
+                        self.steal(core)?;
+                        continue;
+                    }
+                };
+                if self.processes.contains_key(&id) {
+                    break id;
+                }
+            },
+        };
+
+        let c = &mut self.cores[core];
+        let proc = self.processes.get_mut(&id)?;
+        proc.slice = match proc.rt.as_mut() {
+            Some(rt) => {
+                rt.expire(now);
+                rt.remaining
+            }
+            None => c.policy.start(proc),
+        };
+        proc.set_state(State::Running);
+        proc.core = core;
+        c.current = Some(id);
+        c.switched_at = now;
+        Some(&mut **proc)
+    }
+
+    /// Moves the process that would run last on the busiest other core to
+    /// the run queue of `core` and returns its ID. Returns `None` if no
+    /// other core has a process waiting.
+    fn steal(&mut self, core: usize) -> Option<Id> {
+        let victim = (0..NCORES)
+            .filter(|&c| c != core && self.cores[c].policy.len() > 0)
+            .max_by_key(|&c| self.cores[c].load())?;
+        let id = self.cores[victim].policy.steal()?;
+        if let Some(proc) = self.processes.get_mut(&id) {
+            proc.core = core;
+            self.cores[core].policy.enqueue(proc, Enqueue::Migrated);
         }
+        Some(id)
     }
-}
 
-pub extern "C" fn test_user_process() -> ! {
-    loop {
-        let ms = 10000;
-        let error: u64;
-        let elapsed_ms: u64;
+    /// Evens out the load of `core` and the busiest core: if that one has at
+    /// least two more processes to run, one of its ready processes is moved
+    /// to `core`.
+    fn balance(&mut self, core: usize) {
+        let busiest = (0..NCORES).max_by_key(|&c| self.cores[c].load()).unwrap();
+        if self.cores[busiest].load() >= self.cores[core].load() + 2 {
+            self.steal(core);
+        }
 
-        unsafe {
-            asm!("mov x0, $2
-              svc 1
-              mov $0, x0
-              mov $1, x7"
-                 : "=r"(elapsed_ms), "=r"(error)
-                 : "r"(ms)
-                 : "x0", "x7"
-                 : "volatile");
+        // Idle cores sleep until they are interrupted, so one is woken to
+        // take over a process this core has waiting.
+        if self.cores[core].policy.len() > 0 {
+            if let Some(idle) = (0..NCORES).find(|&c| self.cores[c].load() == 0) {
+                self.kick(idle);
+            }
+        }
+    }
+
+    /// Returns the core a process that last ran on `core` is queued on when
+    /// it becomes ready: `core` itself, unless that core is busy and another
+    /// one is idle.
+    fn place(&self, core: usize) -> usize {
+        if self.cores[core].load() == 0 {
+            return core;
+        }
+        (0..NCORES).find(|&c| self.cores[c].load() == 0).unwrap_or(core)
+    }
+
+    /// Tells `core` that a process was just queued on it: another core is
+    /// interrupted if it is idle, runs its only process without a timer or
+    /// has real-time processes ready, one of which may need to preempt the
+    /// running process, and the current core re-arms its timer.
+    fn kick(&self, core: usize) {
+        let c = &self.cores[core];
+        if core == percore::getcpu() {
+            self.arm(pi::timer::current_time());
+        } else if c.current.is_none() || c.policy.len() == 1 || !c.rt.is_empty() {
+            percore::send_ipi(core);
+        }
+    }
+
+    /// Queues the ready real-time process `id` on the core it was admitted
+    /// on, or, if it used up its budget, throttles it until its period ends.
+    fn enqueue_rt(&mut self, id: Id, now: Duration) {
+        let proc = match self.processes.get_mut(&id) {
+            Some(proc) => proc,
+            None => return,
+        };
+        let rt = match proc.rt.as_mut() {
+            Some(rt) => rt,
+            None => return,
+        };
+
+        if rt.remaining == Duration::from_secs(0) {
+            if rt.deadline > now {
+                self.throttled.insert((rt.deadline, id));
+                return;
+            }
+            rt.renew(now);
+        }
+        let (core, deadline) = (rt.core, rt.deadline);
+        proc.core = core;
+        self.cores[core].rt.insert((deadline, id));
+        self.kick(core);
+    }
+
+    /// Arms the timer of the current core for the next time the scheduler
+    /// has work to do on it at `now`: the end of the running process's time
+    /// slice if another process waits for the core, the earliest deadline on
+    /// the timer queue, the earliest end of period of a throttled real-time
+    /// process of this core, and the next `CONSOLE_POLL` while somebody
+    /// waits for console input, which raises no interrupt. If there is none,
+    /// the timer is stopped, so a core that is idle or has a single process
+    /// to run is left alone.
+    ///
+    /// A real-time process runs until its budget is used up, even if it is
+    /// alone, and is preempted right away by one with an earlier deadline,
+    /// as is a normal process by any real-time process.
+    fn arm(&self, now: Duration) {
+        let cpu = percore::getcpu();
+        let core = &self.cores[cpu];
+        let zero = Duration::from_secs(0);
+        let first_rt = core.rt.iter().next().map(|&(deadline, _)| deadline);
+        let slice = match core.current.and_then(|id| self.processes.get(&id)) {
+            Some(proc) => {
+                let left = (core.switched_at + proc.slice).checked_sub(now).unwrap_or_default();
+                match (proc.rt, first_rt) {
+                    (Some(rt), Some(first)) if first < rt.deadline => Some(zero),
+                    (Some(_), _) => Some(left),
+                    (None, Some(_)) => Some(zero),
+                    (None, None) if core.policy.len() > 0 => Some(left),
+                    (None, None) => None,
+                }
+            }
+            None => None,
+        };
+        let deadline = self.timers.iter().next().map(|&(deadline, _)| deadline.checked_sub(now).unwrap_or_default());
+        let replenish = self
+            .throttled
+            .iter()
+            .find(|&&(_, id)| self.processes.get(&id).and_then(|p| p.rt).map(|rt| rt.core) == Some(cpu))
+            .map(|&(end, _)| end.checked_sub(now).unwrap_or_default());
+        let console = if self.awaits_console() { Some(CONSOLE_POLL) } else { None };
+
+        match [slice, deadline, replenish, console].iter().flatten().min() {
+            Some(&t) => percore::local_tick_in(t),
+            None => percore::stop_local_tick(),
+        }
+    }
+
+    /// Schedules in the next process (see `next()`), performs context switch
+    /// by restoring the next process`s trap frame into `tf` and arms the
+    /// core's timer (see `arm()`). Returns the next process`s process ID.
+    ///
+    /// If there is no process to switch to, switches to the core's idle task
+    /// and returns `IDLE_PID`.
+    fn switch_to(&mut self, tf: &mut TrapFrame, now: Duration) -> Id {
+        // Whatever ran since the last process was scheduled out, say the idle
+        // task, is not charged to anybody.
+        percore::take_cpu_times();
+        *tf = match self.next(now) {
+            Some(proc) => *proc.context,
+            None => *self.cores[percore::getcpu()].idle.context,
+        };
+        self.arm(now);
+        tf.tpidr
+    }
+
+    /// Puts the process `id`, which was just made `Ready`, on the run queue
+    /// and takes it off the timer queue if it had `deadline`. A real-time
+    /// process goes back on its own core.
+    fn ready(&mut self, id: Id, deadline: Option<Duration>) {
+        if let Some(deadline) = deadline {
+            self.timers.remove(&(deadline, id));
+        }
+        let core = match self.processes.get_mut(&id).map(|p| &mut **p) {
+            Some(Process { rt: Some(rt), .. }) => {
+                let now = pi::timer::current_time();
+                rt.wake(now);
+                self.enqueue_rt(id, now);
+                return;
+            }
+            Some(proc) => proc.core,
+            None => return,
+        };
+        let core = self.place(core);
+        if let Some(proc) = self.processes.get_mut(&id) {
+            proc.core = core;
+            self.cores[core].policy.enqueue(proc, Enqueue::Woken);
+        }
+        self.kick(core);
+    }
+
+    /// Sets the nice value of the process `id` to `nice`, clamped to
+    /// `NICE_MIN..=NICE_MAX`. A ready process is queued again so that the
+    /// policy sees its new priority. The nice value of a real-time process
+    /// only matters once it is a normal process again.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoEntry` if there is no such process.
+    fn set_nice(&mut self, id: Id, nice: i64) -> OsResult<()> {
+        let proc = self.processes.get_mut(&id).ok_or(OsError::NoEntry)?;
+        match proc.state {
+            State::Ready if proc.rt.is_none() => {
+                let queue = &mut self.cores[proc.core].policy;
+                queue.remove(proc);
+                proc.nice = policy::clamp_nice(nice);
+                queue.enqueue(proc, Enqueue::Requeued);
+            }
+            _ => proc.nice = policy::clamp_nice(nice),
+        }
+        Ok(())
+    }
+
+    /// Replaces the scheduling policy of every core with a new instance of
+    /// the policy named `name` (see `policy::by_name()`) and queues every
+    /// ready normal process on its core's new policy as if it were new.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if there is no such policy.
+    fn set_policy(&mut self, name: &str) -> OsResult<()> {
+        for core in self.cores.iter_mut() {
+            core.policy = policy::by_name(name).ok_or(OsError::InvalidArgument)?;
+        }
+        for proc in self.processes.values_mut().filter(|p| p.rt.is_none()) {
+            if let State::Ready = proc.state {
+                self.cores[proc.core].policy.enqueue(proc, Enqueue::New);
+            }
+        }
+        kprintln!("[scheduler] using the {} policy", name);
+        Ok(())
+    }
+
+    /// Schedules out the process running in `tf` as `Blocked` with the wake
+    /// function `wake`, on the wait queue of `event` and, until `deadline`,
+    /// on the timer queue. The process is woken by `wake()` on `event`, by
+    /// the timer when `deadline` passes, or by a signal.
+    ///
+    /// If the process waits for `event`, `wake` is polled once first, and
+    /// the process is made ready instead of queued if that finishes its
+    /// system call. Callers look for the event before entering the critical
+    /// section, so it may occur in between, while nobody is queued to be
+    /// woken by it: a child exiting right after `sys_wait` found none, or a
+    /// writer filling a pipe right after a read found it empty. Polling
+    /// under the lock `wake()` takes closes that window.
+    fn block(&mut self, event: Option<Event>, deadline: Option<Duration>, wake: WakeFn, tf: &mut TrapFrame) -> bool {
+        let id = tf.tpidr;
+        if !self.schedule_out(State::Blocked(wake), tf) {
+            return false;
+        }
+        if event.is_none() || !self.resume(id, false) {
+            self.queue_blocked(id, event, deadline);
+        }
+        true
+    }
+
+    /// Puts the process `id`, which was just scheduled out as `Blocked`, on
+    /// the wait queue of `event` and, until `deadline`, on the timer queue.
+    fn queue_blocked(&mut self, id: Id, event: Option<Event>, deadline: Option<Duration>) {
+        if let Some(proc) = self.processes.get_mut(&id) {
+            proc.blocked_on = event;
+            proc.deadline = deadline;
+        }
+        if let Some(event) = event {
+            self.waiters.entry(event).or_insert_with(VecDeque::new).push_back(id);
+        }
+        if let Some(deadline) = deadline {
+            self.timers.insert((deadline, id));
+        }
+    }
+
+    /// Runs the wake function of the blocked process `id` and puts it on the
+    /// run queue if that finished its system call. Returns whether it did.
+    fn resume(&mut self, id: Id, expired: bool) -> bool {
+        let (deadline, resumed) = match self.processes.get_mut(&id) {
+            Some(proc) => (proc.deadline, proc.resume(expired)),
+            None => return false,
+        };
+        if resumed {
+            self.ready(id, deadline);
+        }
+        resumed
+    }
+
+    /// Wakes up to `n` processes blocked on `event`, in the order they
+    /// blocked, and returns how many were woken.
+    ///
+    /// A process whose wake function finds that the event did not occur for
+    /// it (say, another reader took the data first) stays on the queue. Since
+    /// the readers and writers of a pipe share one event, waking one can let
+    /// another make progress, so the queue is walked again as long as
+    /// somebody was woken.
+    fn wake(&mut self, event: Event, n: usize) -> usize {
+        let mut woken = 0;
+        while woken < n {
+            let queue = match self.waiters.remove(&event) {
+                Some(queue) => queue,
+                None => break,
+            };
+
+            let before = woken;
+            let mut waiting = VecDeque::new();
+            for id in queue {
+                let blocked = match self.processes.get(&id) {
+                    Some(p) => p.is_blocked() && p.blocked_on == Some(event),
+                    None => false,
+                };
+                if !blocked {
+                    continue;
+                }
+                if woken < n && self.resume(id, false) {
+                    woken += 1;
+                } else {
+                    waiting.push_back(id);
+                }
+            }
+
+            if !waiting.is_empty() {
+                self.waiters.insert(event, waiting);
+            }
+            if woken == before {
+                break;
+            }
+        }
+        woken
+    }
+
+    /// Returns `true` if a process or kernel thread waits for console input.
+    fn awaits_console(&self) -> bool {
+        self.waiters.contains_key(&Event::Console) || CONSOLE_INPUT.has_waiters()
+    }
+
+    /// Wakes the processes whose deadline is at or before `now`, queues the
+    /// throttled real-time processes whose period ended with a new budget,
+    /// and wakes the processes waiting for console input if there is some or
+    /// a Ctrl-C arrived.
+    fn poll(&mut self, now: Duration) {
+        while let Some(&(end, id)) = self.throttled.iter().next() {
+            if end > now {
+                break;
+            }
+            self.throttled.remove(&(end, id));
+            if let Some(rt) = self.processes.get_mut(&id).and_then(|p| p.rt.as_mut()) {
+                rt.renew(now);
+            }
+            self.enqueue_rt(id, now);
+        }
+
+        while let Some(&(deadline, id)) = self.timers.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.timers.remove(&(deadline, id));
+
+            let expired = match self.processes.get(&id) {
+                Some(p) => p.is_blocked() && p.deadline == Some(deadline),
+                None => false,
+            };
+            if expired && !self.resume(id, true) {
+                if let Some(p) = self.processes.get_mut(&id) {
+                    p.deadline = None;
+                }
+            }
+        }
+
+        if self.awaits_console() && CONSOLE.lock().has_input() {
+            self.wake(Event::Console, usize::max_value());
+            if let Some(event) = CONSOLE_INPUT.advance() {
+                self.wake(event, usize::max_value());
+            }
+        }
+    }
+
+    /// Sends signal `sig` to the process `id` (see `Process::signal()`).
+    /// `SIGKILL` and `SIGCONT` resume the process if it is stopped, and a
+    /// signal that should interrupt a blocked system call fails it with
+    /// `OsError::Interrupted` and makes the process ready, so that the
+    /// signal is acted on.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoEntry` if there is no such process.
+    pub fn signal(&mut self, id: Id, sig: u64) -> OsResult<()> {
+        let proc = self.processes.get_mut(&id).ok_or(OsError::NoEntry)?;
+        proc.signal(sig);
+
+        let deadline = proc.deadline;
+        let woken = match proc.state {
+            State::Stopped if sig == SIGKILL || sig == SIGCONT => {
+                proc.set_state(State::Ready);
+                true
+            }
+            _ => proc.interrupt(),
+        };
+        if woken {
+            self.ready(id, deadline);
+        }
+        Ok(())
+    }
+
+    /// Queues the process running in `tf` on the futex word at physical
+    /// address `key` and schedules it out as `Blocked` until `deadline`.
+    ///
+    /// Checking the word and queueing happen in one critical section, so a
+    /// wake-up between the caller's check of the word and the call cannot be
+    /// missed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::WouldBlock` if the word does not hold `expected`.
+    fn futex_wait(
+        &mut self,
+        key: usize,
+        expected: u32,
+        deadline: Option<Duration>,
+        wake: WakeFn,
+        tf: &mut TrapFrame,
+    ) -> OsResult<()> {
+        // The kernel identity-maps RAM, so the word can be read through its
+        // physical address.
+        let value = unsafe { core::ptr::read_volatile(key as *const u32) };
+        if value != expected {
+            return Err(OsError::WouldBlock);
+        }
+
+        // The wake function finishes the call whenever it runs, so it is not
+        // polled as in `block()`: the check above already stands in for it.
+        let id = tf.tpidr;
+        if !self.schedule_out(State::Blocked(wake), tf) {
+            return Err(OsError::NoEntry);
+        }
+        self.queue_blocked(id, Some(Event::Futex(key)), deadline);
+        Ok(())
+    }
+
+    /// Makes the process `id` a real-time process that gets `budget` of CPU
+    /// time in every `period` from `now` on, for `Some((budget, period))`,
+    /// or a normal process again, for `None`. A real-time process keeps its
+    /// deadline miss count when its reservation changes.
+    ///
+    /// The reservation is admitted on the least loaded core that can take
+    /// it while keeping the share reserved on it at most `RT_UTIL_MAX`, and
+    /// the process only runs there from then on.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoEntry` if there is no such process or it exited,
+    /// `OsError::InvalidArgument` if `budget` is under `RT_BUDGET_MIN` or
+    /// over `period`, and `OsError::Busy` if no core has room for the
+    /// reservation.
+    fn set_reservation(&mut self, id: Id, reservation: Option<(Duration, Duration)>, now: Duration) -> OsResult<()> {
+        // A zombie never gives a reservation back.
+        let old = match self.processes.get(&id) {
+            Some(proc) if !proc.is_zombie() => proc.rt,
+            _ => return Err(OsError::NoEntry),
+        };
+        let new = match reservation {
+            Some((budget, period)) => {
+                if budget < RT_BUDGET_MIN || budget > period {
+                    return Err(OsError::InvalidArgument);
+                }
+
+                // The old reservation, if any, makes room for the new one.
+                let reserved = |c: usize| match old {
+                    Some(rt) if rt.core == c => self.cores[c].rt_util - rt.util(),
+                    _ => self.cores[c].rt_util,
+                };
+                let mut rt = Reservation::new(0, budget, period, now);
+                let util = rt.util();
+                rt.core = (0..NCORES)
+                    .filter(|&c| reserved(c) + util <= RT_UTIL_MAX)
+                    .min_by_key(|&c| reserved(c))
+                    .ok_or(OsError::Busy)?;
+                rt.misses = old.map(|rt| rt.misses).unwrap_or(0);
+                Some(rt)
+            }
+            None => None,
+        };
+
+        let ready = self.dequeue(id);
+        self.release(id);
+        if let Some(rt) = new {
+            self.cores[rt.core].rt_util += rt.util();
+        }
+        let proc = self.processes.get_mut(&id).ok_or(OsError::NoEntry)?;
+        proc.rt = new;
+        let core = proc.core;
+
+        match (ready, new) {
+            (true, Some(_)) => self.enqueue_rt(id, now),
+            (true, None) => {
+                let core = self.place(core);
+                if let Some(proc) = self.processes.get_mut(&id) {
+                    proc.core = core;
+                    self.cores[core].policy.enqueue(proc, Enqueue::New);
+                }
+                self.kick(core);
+            }
+            // A running process is charged and queued under its new class
+            // when it is next scheduled out; its core's timer is re-armed
+            // for that.
+            (false, _) => self.kick(core),
+        }
+        Ok(())
+    }
+
+    /// Takes the process `id` off the run queue it is on if it is `Ready`:
+    /// its core's real-time queue or the throttled queue if it is a
+    /// real-time process, and its core's policy otherwise. Returns whether
+    /// it was `Ready`.
+    fn dequeue(&mut self, id: Id) -> bool {
+        let proc = match self.processes.get(&id) {
+            Some(proc) if proc.is_ready() => proc,
+            _ => return false,
+        };
+        match proc.rt {
+            Some(rt) => {
+                self.cores[rt.core].rt.remove(&(rt.deadline, id));
+                self.throttled.remove(&(rt.deadline, id));
+            }
+            None => self.cores[proc.core].policy.remove(proc),
+        }
+        true
+    }
+
+    /// Gives the reservation of the process `id`, if it has one, back to its
+    /// core and makes it a normal process. It must not be on a run queue.
+    fn release(&mut self, id: Id) {
+        if let Some(rt) = self.processes.get_mut(&id).and_then(|p| p.rt.take()) {
+            self.cores[rt.core].rt_util -= rt.util();
+        }
+    }
+
+    /// Returns information about every process at `now`, by ID.
+    ///
+    /// The CPU time of a process is charged when it is scheduled out, so a
+    /// running process is also credited with the time since it was switched
+    /// to: as user time, or as kernel time for a kernel thread.
+    fn procinfo(&self, now: Duration) -> Vec<ProcInfo> {
+        self.processes
+            .values()
+            .map(|p| {
+                let (mut user, mut kernel) = (p.stats.user_time, p.stats.kernel_time);
+                if let State::Running = p.state {
+                    let ran = now.checked_sub(self.cores[p.core].switched_at).unwrap_or_default();
+                    match p.space {
+                        Some(_) => user += ran,
+                        None => kernel += ran,
+                    }
+                }
+
+                ProcInfo {
+                    pid: p.id(),
+                    ppid: p.parent.unwrap_or(0),
+                    tgid: p.main_thread(),
+                    state: p.state.code(),
+                    nice: p.nice,
+                    core: p.core as u64,
+                    kernel: p.space.is_none() as u64,
+                    user_us: user.as_micros() as u64,
+                    kernel_us: kernel.as_micros() as u64,
+                    switches: p.stats.switches,
+                    pages: p.space().map(|space| space.vmap.pages() as u64).unwrap_or(0),
+                    transitions: p.stats.transitions,
+                    policy: if p.rt.is_some() { SCHED_DEADLINE } else { SCHED_NORMAL },
+                    budget_us: p.rt.map(|rt| rt.budget.as_micros() as u64).unwrap_or(0),
+                    period_us: p.rt.map(|rt| rt.period.as_micros() as u64).unwrap_or(0),
+                    deadline_misses: p.rt.map(|rt| rt.misses).unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns a mutable reference to the process with ID `id`, if any.
+    pub fn find_mut(&mut self, id: Id) -> Option<&mut Process> {
+        self.processes.get_mut(&id).map(|p| &mut **p)
+    }
+
+    /// Removes the process with ID `id` and returns it. Its entries on the
+    /// wait queues go stale and are skipped later. If the process is running
+    /// on another core, that core is interrupted to switch away from it. A
+    /// real-time process gives its reservation back.
+    fn remove(&mut self, id: Id) -> Option<Process> {
+        self.dequeue(id);
+        self.release(id);
+        let proc = self.processes.remove(&id)?;
+        match proc.state {
+            State::Running if self.cores[proc.core].current == Some(id) => {
+                self.cores[proc.core].current = None;
+                if proc.core != percore::getcpu() {
+                    percore::send_ipi(proc.core);
+                }
+            }
+            _ => (),
+        }
+        if let Some(deadline) = proc.deadline {
+            self.timers.remove(&(deadline, id));
+        }
+        Some(SlabBox::into_inner(proc))
+    }
+
+    /// Terminates the process of the currently running thread with exit
+    /// status `status`, and returns the ID of its main thread.
+    ///
+    /// Threads do not outlive their process: every thread other than the
+    /// main thread is discarded, and the main thread becomes a `Zombie` with
+    /// exit status `status` (see `bury()`). A main thread running on another
+    /// core is only marked as exiting and that core is interrupted: it
+    /// becomes a `Zombie` once it is scheduled out, so its address space is
+    /// not freed under it.
+    fn exit(&mut self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        let id = tf.tpidr;
+        if !self.schedule_out(State::Zombie(status), tf) {
+            return None;
+        }
+
+        let main = self.find_mut(id)?.main_thread();
+        kprintln!("[scheduler] process {} exited with status {}", main, status);
+        let threads: Vec<Id> = self.processes.values().filter(|p| p.thread_of == Some(main)).map(|p| p.id()).collect();
+        for thread in threads {
+            self.reparent_children(thread);
+            self.remove(thread);
+        }
+
+        if main != id {
+            let core = self.find_mut(main)?.core;
+            if core != percore::getcpu() && self.cores[core].current == Some(main) {
+                self.find_mut(main)?.exiting = Some(status);
+                percore::send_ipi(core);
+                return Some(main);
+            }
+
+            self.dequeue(main);
+            let proc = self.find_mut(main)?;
+            proc.blocked_on = None;
+            if let Some(deadline) = proc.deadline.take() {
+                self.timers.remove(&(deadline, main));
+            }
+        }
+
+        self.find_mut(main)?.set_state(State::Zombie(status));
+        self.bury(main, status);
+        Some(main)
+    }
+
+    /// Terminates the currently running thread with exit status `status`
+    /// and returns its ID. The thread stays a `Zombie` until it is joined.
+    /// Exiting the main thread exits the whole process, like `exit()`.
+    fn exit_thread(&mut self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        let id = tf.tpidr;
+        if self.find_mut(id)?.thread_of.is_none() {
+            return self.exit(status, tf);
+        }
+        if !self.schedule_out(State::Zombie(status), tf) {
+            return None;
+        }
+
+        kprintln!("[scheduler] thread {} exited with status {}", id, status);
+        self.find_mut(id)?.release_thread_stack();
+        self.bury(id, status);
+        Some(id)
+    }
+
+    /// Cleans up after the process `id`, which just became a `Zombie` with
+    /// exit status `status`.
+    ///
+    /// Children of the exiting process are re-parented to the init process
+    /// (`INIT_PID`). If the parent is blocked in `wait` on this process, the
+    /// zombie is reaped right away and the parent is handed its status. A
+    /// process without a parent is dropped immediately since nobody can ever
+    /// wait for it.
+    fn bury(&mut self, id: Id, status: u64) {
+        // Close the zombie's descriptors now so that, for example, readers of
+        // its pipes see end of file without waiting for it to be reaped.
+        let events = match self.find_mut(id) {
+            Some(p) => {
+                let events = p.files.events();
+                p.files.clear();
+                events
+            }
+            None => Vec::new(),
+        };
+        for event in events {
+            self.wake(event, usize::max_value());
+        }
+        // A zombie never runs again, so its share of the core is free.
+        self.release(id);
+
+        let orphans = self.reparent_children(id);
+        let parent = self.find_mut(id).and_then(|p| p.parent);
+        self.notify_parent(id, parent, status);
+        for (orphan, parent, status) in orphans {
+            self.notify_parent(orphan, parent, status);
+        }
+    }
+
+    /// Re-parents the children of `id` to the init process, or to nobody for
+    /// threads, which only their creator can join. Returns the (ID, new
+    /// parent, exit status) of the children that already exited.
+    fn reparent_children(&mut self, id: Id) -> Vec<(Id, Option<Id>, u64)> {
+        let mut orphans = Vec::new();
+        for p in self.processes.values_mut().filter(|p| p.parent == Some(id)) {
+            p.parent = match p.thread_of {
+                None if id != INIT_PID => Some(INIT_PID),
+                _ => None,
+            };
+            if let State::Zombie(status) = p.state {
+                orphans.push((p.id(), p.parent, status));
+            }
+        }
+        orphans
+    }
+
+    /// Tells `parent` that its child `child` became a zombie with exit status
+    /// `status`. The child is reaped right away when `parent` is `None` or
+    /// when the parent is blocked waiting for it, in which case the parent is
+    /// woken. Waiting for any child (`wait_target` of `0`) never matches
+    /// threads.
+    fn notify_parent(&mut self, child: Id, parent: Option<Id>, status: u64) {
+        let is_thread = self.find_mut(child).map(|p| p.thread_of.is_some()).unwrap_or(false);
+        let reap = match parent.and_then(|ppid| self.find_mut(ppid)) {
+            None => true,
+            Some(p) => match p.wait_target {
+                Some(target) if target == child || (target == 0 && !is_thread) => {
+                    p.wait_target = None;
+                    p.reaped = Some((child, status));
+                    true
+                }
+                _ => false,
+            },
+        };
+
+        if reap {
+            self.remove(child);
+            if let Some(ppid) = parent {
+                self.wake(Event::Child(ppid), usize::max_value());
+            }
+        }
+    }
+
+    /// Reaps a zombie child of `parent`. `child` selects the child to reap;
+    /// `0` selects any child. `threads` selects whether the children to
+    /// consider are threads (for `thread_join`) or processes (for `wait`).
+    ///
+    /// Returns `Ok(Some((id, status)))` if a matching zombie was reaped and
+    /// `Ok(None)` if matching children exist but none has exited yet. In the
+    /// latter case, `parent` is marked as waiting so that `exit()` hands it the
+    /// status of the first matching child to exit and wakes it from
+    /// `Event::Child(parent)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoEntry` if `parent` has no matching child.
+    fn reap(&mut self, parent: Id, child: Id, threads: bool) -> OsResult<Option<(Id, u64)>> {
+        let matches = |p: &Process| {
+            p.parent == Some(parent) && (child == 0 || p.id() == child) && p.thread_of.is_some() == threads
+        };
+        if !self.processes.values().any(|p| matches(p)) {
+            return Err(OsError::NoEntry);
+        }
+
+        let zombie = self.processes.values().find(|p| matches(p) && p.is_zombie()).map(|p| p.id());
+        match zombie.and_then(|id| self.remove(id)) {
+            Some(Process { state: State::Zombie(status), context, .. }) => Ok(Some((context.tpidr, status))),
+            _ => {
+                self.find_mut(parent).ok_or(OsError::NoEntry)?.wait_target = Some(child);
+                Ok(None)
+            }
         }
     }
 }
@@ -0,0 +1,251 @@
+use core::mem::size_of;
+
+use aarch64::SPSR_EL1;
+
+use crate::console::kprintln;
+use crate::process::{Process, State};
+use crate::traps::TrapFrame;
+use crate::vm::UserPtr;
+use crate::SCHEDULER;
+use kernel_api::*;
+
+/// Signals that can be neither caught, ignored nor blocked.
+const UNCATCHABLE: u64 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+/// The condition flags of `SPSR`, the only bits of it a signal handler may
+/// change through the frame it returns with.
+const SPSR_FLAGS: u64 = SPSR_EL1::N | SPSR_EL1::Z | SPSR_EL1::C | SPSR_EL1::V;
+
+fn bit(sig: u64) -> u64 {
+    1 << sig
+}
+
+/// What delivering a signal does to a process.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    /// Nothing.
+    Ignore,
+    /// The process exits with status `SIGNAL_EXIT_BASE + sig`.
+    Terminate,
+    /// The process stops until it receives `SIGCONT` or `SIGKILL`.
+    Stop,
+    /// Nothing; the process was already woken when the signal was sent.
+    Continue,
+    /// The user handler runs on the process's stack.
+    Handle(SigAction),
+}
+
+/// Returns the action taken for `sig` when its handler is `SIG_DFL`.
+fn default_action(sig: u64) -> Action {
+    match sig {
+        SIGCHLD => Action::Ignore,
+        SIGCONT => Action::Continue,
+        SIGSTOP | SIGTSTP => Action::Stop,
+        _ => Action::Terminate,
+    }
+}
+
+/// The signal state of a process: pending and blocked signals, and the
+/// action registered for each signal. Bit `n` of a mask stands for signal
+/// `n`.
+#[derive(Debug)]
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; NSIG as usize],
+}
+
+impl Signals {
+    /// Returns a signal state with nothing pending or blocked and every
+    /// action set to `SIG_DFL`.
+    pub fn new() -> Signals {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); NSIG as usize],
+        }
+    }
+
+    /// Checks that `sig` is a valid signal number.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if it is not.
+    pub fn check(sig: u64) -> OsResult<()> {
+        if sig == 0 || sig >= NSIG {
+            return Err(OsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Returns the action taken when `sig` is delivered.
+    fn action(&self, sig: u64) -> Action {
+        let act = self.actions[sig as usize];
+        match act.handler {
+            SIG_DFL => default_action(sig),
+            SIG_IGN => Action::Ignore,
+            _ => Action::Handle(act),
+        }
+    }
+
+    /// Returns the `SigAction` registered for `sig`.
+    pub fn get_action(&self, sig: u64) -> SigAction {
+        self.actions[sig as usize]
+    }
+
+    /// Registers `act` for `sig` and returns the previous `SigAction`.
+    /// Ignoring a signal discards it if it is pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `sig` is `SIGKILL` or `SIGSTOP`,
+    /// or if `act` installs a handler without a restorer.
+    pub fn set_action(&mut self, sig: u64, act: SigAction) -> OsResult<SigAction> {
+        if UNCATCHABLE & bit(sig) != 0 || (act.handler > SIG_IGN && act.restorer == 0) {
+            return Err(OsError::InvalidArgument);
+        }
+
+        let old = core::mem::replace(&mut self.actions[sig as usize], act);
+        if self.action(sig) == Action::Ignore {
+            self.pending &= !bit(sig);
+        }
+        Ok(old)
+    }
+
+    /// Marks `sig` pending. `SIGCONT` cancels pending stop signals and vice
+    /// versa.
+    pub fn raise(&mut self, sig: u64) {
+        match sig {
+            SIGCONT => self.pending &= !(bit(SIGSTOP) | bit(SIGTSTP)),
+            SIGSTOP | SIGTSTP => self.pending &= !bit(SIGCONT),
+            _ => (),
+        }
+        self.pending |= bit(sig);
+    }
+
+    /// Raises `sig` on behalf of a fault. If the process blocks or ignores
+    /// `sig`, its action is reset to `SIG_DFL` first so that the faulting
+    /// instruction is not simply retried forever.
+    pub fn force(&mut self, sig: u64) {
+        if self.blocked & bit(sig) != 0 || self.action(sig) == Action::Ignore {
+            self.actions[sig as usize] = SigAction::default();
+            self.blocked &= !bit(sig);
+        }
+        self.raise(sig);
+    }
+
+    fn deliverable(&self) -> u64 {
+        self.pending & !(self.blocked & !UNCATCHABLE)
+    }
+
+    /// Returns `true` if a deliverable signal should interrupt a blocking
+    /// system call: one that runs a handler, stops or terminates.
+    pub fn interrupts(&self) -> bool {
+        let ready = self.deliverable();
+        (1..NSIG).any(|sig| {
+            ready & bit(sig) != 0
+                && match self.action(sig) {
+                    Action::Ignore | Action::Continue => false,
+                    _ => true,
+                }
+        })
+    }
+
+    /// Removes the lowest-numbered deliverable signal from the pending set
+    /// and returns it with the action to take.
+    pub fn take(&mut self) -> Option<(u64, Action)> {
+        let ready = self.deliverable();
+        if ready == 0 {
+            return None;
+        }
+
+        let sig = ready.trailing_zeros() as u64;
+        self.pending &= !bit(sig);
+        Some((sig, self.action(sig)))
+    }
+}
+
+/// The state saved on the user stack while a signal handler runs.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SigFrame {
+    tf: TrapFrame,
+    blocked: u64,
+    sig: u64,
+}
+
+/// Saves `tf` in a `SigFrame` on the user stack of `p` and points `tf` at the
+/// handler of `act`, which is called with `sig` and returns to the restorer.
+fn push_frame(p: &mut Process, sig: u64, act: &SigAction, tf: &mut TrapFrame) -> OsResult<()> {
+    let sp = (tf.sp as usize).checked_sub(size_of::<SigFrame>()).ok_or(OsError::BadAddress)? & !0xf;
+    let frame = SigFrame { tf: *tf, blocked: p.signals.blocked, sig };
+    UserPtr::<SigFrame>::new(sp)?.write(&mut p.space()?.vmap, frame)?;
+
+    p.signals.blocked |= (act.mask | bit(sig)) & !UNCATCHABLE;
+    tf.xregs[0] = sig;
+    tf.xregs[30] = act.restorer;
+    tf.elr = act.handler;
+    tf.sp = sp as u64;
+    Ok(())
+}
+
+/// Restores the state saved by `push_frame()` from the `SigFrame` at the
+/// stack pointer of `tf`.
+///
+/// Only the registers a user program could set itself are taken from the
+/// frame; page tables, the process ID and the privileged bits of `SPSR` are
+/// kept.
+///
+/// # Errors
+///
+/// Returns `OsError::BadAddress` if there is no readable frame at the stack
+/// pointer.
+pub fn pop_frame(p: &mut Process, tf: &mut TrapFrame) -> OsResult<()> {
+    let frame = UserPtr::<SigFrame>::new(tf.sp as usize)?.read(&mut p.space()?.vmap)?;
+    let saved = frame.tf;
+
+    tf.elr = saved.elr;
+    tf.sp = saved.sp;
+    tf.spsr = (tf.spsr & !SPSR_FLAGS) | (saved.spsr & SPSR_FLAGS);
+    tf.xregs = saved.xregs;
+    tf.qregs = saved.qregs;
+    p.signals.blocked = frame.blocked & !UNCATCHABLE;
+    Ok(())
+}
+
+/// Terminates the process running in `tf` because of `sig` and switches to
+/// the next process.
+fn terminate(sig: u64, tf: &mut TrapFrame) {
+    kprintln!("[signal] process {} killed by signal {}", tf.tpidr, sig);
+    let _ = SCHEDULER.exit(SIGNAL_EXIT_BASE + sig, tf);
+    SCHEDULER.switch_to(tf);
+}
+
+/// Delivers the pending signals of the process about to return to user mode
+/// in `tf`.
+///
+/// Stopping or terminating the process switches `tf` to another process,
+/// whose pending signals are delivered in turn.
+pub fn deliver(tf: &mut TrapFrame) {
+    loop {
+        let pid = tf.tpidr;
+        let (sig, action) = match SCHEDULER.with_process(pid, |p| p.signals.take()) {
+            Ok(Some(next)) => next,
+            _ => return,
+        };
+
+        match action {
+            Action::Ignore | Action::Continue => (),
+            Action::Terminate => terminate(sig, tf),
+            Action::Stop => {
+                SCHEDULER.switch(State::Stopped, tf);
+            }
+            Action::Handle(act) => {
+                let pushed = SCHEDULER.with_process(pid, |p| push_frame(p, sig, &act, tf));
+                if pushed.and_then(|r| r).is_err() {
+                    terminate(SIGSEGV, tf);
+                }
+            }
+        }
+    }
+}
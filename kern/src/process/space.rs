@@ -0,0 +1,317 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use shim::io;
+
+use crate::allocator::util::align_up;
+use crate::mutex::Mutex;
+use crate::param::*;
+use crate::process::elf::Elf;
+use crate::process::Process;
+use crate::vm::*;
+use fat32::traits::File;
+use io::{Read, Seek};
+use kernel_api::{OsError, OsResult};
+
+/// The user address space of a process, shared by all of its threads: the
+/// page table, the heap and the regions mapped with `mmap`.
+#[derive(Debug)]
+pub struct AddressSpace {
+    /// The page table describing the user virtual memory.
+    pub vmap: UserPageTable,
+    /// The start of the heap: the first page after the loaded image.
+    pub heap_base: usize,
+    /// The current end of the heap (the program break).
+    pub brk: usize,
+    /// The regions created by `mmap` in the window above the heap.
+    pub regions: RegionList,
+}
+
+impl AddressSpace {
+    /// Returns an empty address space with an empty heap at `USER_IMG_BASE`.
+    pub fn new() -> AddressSpace {
+        AddressSpace {
+            vmap: UserPageTable::new(),
+            heap_base: USER_IMG_BASE,
+            brk: USER_IMG_BASE,
+            regions: RegionList::new(USER_MMAP_BASE, Process::get_stack_base().as_usize() - PAGE_SIZE),
+        }
+    }
+
+    /// Copies a flat binary `image` to `USER_IMG_BASE`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if the image does not fit below
+    /// `USER_MMAP_BASE` and `OsError::NoMemory` if a page could not be
+    /// allocated.
+    pub fn load_flat(&mut self, image: &[u8]) -> OsResult<()> {
+        if image.len() > USER_MMAP_BASE - USER_IMG_BASE {
+            return Err(OsError::BadAddress);
+        }
+
+        let mut addr = USER_IMG_BASE;
+        for chunk in image.chunks(PAGE_SIZE) {
+            let page = self.vmap.alloc(addr.into(), PagePerm::RWX)?;
+            page[..chunk.len()].copy_from_slice(chunk);
+            addr += PAGE_SIZE;
+        }
+        self.set_heap_base(addr);
+        Ok(())
+    }
+
+    /// Maps every `PT_LOAD` segment of the ELF executable `image` at its
+    /// virtual address and returns the entry point.
+    ///
+    /// Segments may share a page, so the permission of each page is the union
+    /// of the flags of all segments overlapping it. Pages are zeroed when
+    /// allocated, which takes care of the `p_memsz > p_filesz` (`.bss`) tail.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::IoErrorInvalidData` if the image is malformed,
+    /// `OsError::NoMemory` if a page could not be allocated and
+    /// `OsError::BadAddress` if a segment lies outside of the user image
+    /// window (`USER_IMG_BASE` up to `USER_MMAP_BASE`, where the `mmap`
+    /// regions start).
+    pub fn load_elf(&mut self, image: &[u8]) -> OsResult<u64> {
+        let elf = Elf::parse(image)?;
+        let mut pages: BTreeMap<usize, u32> = BTreeMap::new();
+        for ph in elf.program_headers().filter(|ph| ph.is_load() && ph.memsz > 0) {
+            let start = ph.vaddr as usize;
+            let end = start.checked_add(ph.memsz as usize).ok_or(OsError::BadAddress)?;
+            if start < USER_IMG_BASE || end > USER_MMAP_BASE {
+                return Err(OsError::BadAddress);
+            }
+            elf.segment_data(&ph)?;
+
+            for page in (start & PAGE_MASK..end).step_by(PAGE_SIZE) {
+                *pages.entry(page).or_insert(0) |= ph.flags;
+            }
+        }
+
+        if pages.is_empty() {
+            return Err(OsError::IoErrorInvalidData);
+        }
+
+        for (&page, &flags) in pages.iter() {
+            self.vmap.alloc(page.into(), PagePerm::from_elf_flags(flags))?;
+        }
+        let image_end = pages.keys().next_back().map(|&page| page + PAGE_SIZE).unwrap();
+        self.set_heap_base(image_end);
+
+        for ph in elf.program_headers().filter(|ph| ph.is_load() && ph.memsz > 0) {
+            let mut data = elf.segment_data(&ph)?;
+            let mut addr = ph.vaddr as usize;
+            while !data.is_empty() {
+                let offset = addr & !PAGE_MASK;
+                let n = core::cmp::min(PAGE_SIZE - offset, data.len());
+                let page = self.vmap.get_page((addr & PAGE_MASK).into()).expect("segment page mapped");
+                page[offset..offset + n].copy_from_slice(&data[..n]);
+                data = &data[n..];
+                addr += n;
+            }
+        }
+
+        Ok(elf.entry())
+    }
+
+    /// Places an empty heap at `addr`, the page-aligned end of the image.
+    fn set_heap_base(&mut self, addr: usize) {
+        self.heap_base = addr;
+        self.brk = addr;
+    }
+
+    /// Moves the program break to `addr`, mapping or unmapping heap pages as
+    /// needed, and returns the new break. An `addr` of `0` leaves the break
+    /// where it is and returns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if `addr` is below the start of the
+    /// heap and `OsError::NoMemory` if it is above `get_heap_limit()` or the
+    /// pages could not be allocated, in which case the break is unchanged.
+    pub fn set_brk(&mut self, addr: usize) -> OsResult<usize> {
+        if addr == 0 {
+            return Ok(self.brk);
+        }
+        if addr < self.heap_base {
+            return Err(OsError::InvalidArgument);
+        }
+        if addr > Self::get_heap_limit().as_usize() {
+            return Err(OsError::NoMemory);
+        }
+
+        let old_end = align_up(self.brk, PAGE_SIZE);
+        let new_end = align_up(addr, PAGE_SIZE);
+        for va in (old_end..new_end).step_by(PAGE_SIZE) {
+            if let Err(e) = self.vmap.alloc(va.into(), PagePerm::RW) {
+                self.unmap_pages(old_end, va);
+                return Err(e);
+            }
+        }
+        for va in (new_end..old_end).step_by(PAGE_SIZE) {
+            self.vmap.dealloc(va.into());
+        }
+
+        self.brk = addr;
+        Ok(addr)
+    }
+
+    /// Returns the `VirtualAddr` the heap may grow up to: the start of the
+    /// `mmap` window.
+    pub fn get_heap_limit() -> VirtualAddr {
+        USER_MMAP_BASE.into()
+    }
+
+    /// Checks that `addr` is page aligned and `len` is non-zero, and returns
+    /// `len` rounded up to a whole number of pages.
+    fn page_range(addr: usize, len: usize) -> OsResult<usize> {
+        if len == 0 || addr & !PAGE_MASK != 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        len.checked_add(PAGE_SIZE - 1).map(|l| l & PAGE_MASK).ok_or(OsError::InvalidArgument)
+    }
+
+    /// Reserves a region of `len` bytes with permission `perm` in the `mmap`
+    /// window and maps zeroed pages for it. `addr` is a hint, rounded down to
+    /// a page boundary, unless `fixed` is set, in which case it must be page
+    /// aligned and anything already mapped there is unmapped first.
+    fn map_region(&mut self, addr: usize, len: usize, perm: PagePerm, fixed: bool, kind: RegionKind) -> OsResult<usize> {
+        let addr = if fixed { addr } else { addr & PAGE_MASK };
+        let len = Self::page_range(addr, len)?;
+        let start = if fixed {
+            self.munmap(addr, len)?;
+            addr
+        } else {
+            self.regions.find_free(addr, len)?
+        };
+
+        let region = Region { start, len, perm, kind };
+        for va in region.pages() {
+            if let Err(e) = self.vmap.alloc(va.into(), perm) {
+                self.unmap_pages(start, va);
+                return Err(e);
+            }
+        }
+        self.regions.insert(region);
+        Ok(start)
+    }
+
+    /// Unmaps the pages in `[start, end)`, which were just mapped.
+    fn unmap_pages(&mut self, start: usize, end: usize) {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            self.vmap.dealloc(va.into());
+        }
+    }
+
+    /// Maps `len` bytes of zero-filled memory and returns its address.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` for an empty request or a misaligned
+    /// `fixed` address, `OsError::NoVmSpace` if the `mmap` window is full and
+    /// `OsError::NoMemory` if the pages could not be allocated, in which case
+    /// nothing is left mapped.
+    pub fn mmap(&mut self, addr: usize, len: usize, perm: PagePerm, fixed: bool) -> OsResult<usize> {
+        self.map_region(addr, len, perm, fixed, RegionKind::Anonymous)
+    }
+
+    /// Maps a private copy of `len` bytes of `file`, starting at `offset`, in
+    /// `space` and returns its address. Bytes past the end of the file read
+    /// as zero.
+    ///
+    /// `space` is a spin lock, which must not be held while the file system
+    /// lock is taken (see `sync`), so the pages are mapped first and then
+    /// filled one at a time, reading each into a bounce buffer with `space`
+    /// unlocked.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `mmap()` and any I/O error reading `file`, in
+    /// which case nothing is left mapped. Returns `OsError::BadAddress` if
+    /// another thread unmaps the range while it is being filled.
+    pub fn mmap_file<F: File>(
+        space: &Mutex<AddressSpace>,
+        addr: usize,
+        len: usize,
+        perm: PagePerm,
+        fixed: bool,
+        file: &mut F,
+        offset: u64,
+    ) -> OsResult<usize> {
+        let start = space.lock().map_region(addr, len, perm, fixed, RegionKind::File)?;
+        if let Err(e) = Self::fill_from(space, start, len, file, offset) {
+            space.lock().munmap(start, len)?;
+            return Err(e);
+        }
+        Ok(start)
+    }
+
+    /// Copies up to `len` bytes of `file` at `offset` into the mapped pages
+    /// starting at `start`.
+    fn fill_from<F: File>(space: &Mutex<AddressSpace>, start: usize, len: usize, file: &mut F, offset: u64) -> OsResult<()> {
+        if offset >= file.size() {
+            return Ok(());
+        }
+        file.seek(io::SeekFrom::Start(offset))?;
+
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for va in (start..start + len).step_by(PAGE_SIZE) {
+            let want = core::cmp::min(PAGE_SIZE, start + len - va);
+            let mut filled = 0;
+            while filled < want {
+                match file.read(&mut buf[filled..want]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                }
+            }
+
+            let mut space = space.lock();
+            let page = space.vmap.get_page(va.into()).ok_or(OsError::BadAddress)?;
+            page[..filled].copy_from_slice(&buf[..filled]);
+            if filled < want {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps every mapped page of the `mmap` window in `[addr, addr + len)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if the range is empty, misaligned or
+    /// reaches outside of the `mmap` window.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> OsResult<()> {
+        let len = Self::page_range(addr, len)?;
+        if !self.regions.contains(addr, len) {
+            return Err(OsError::InvalidArgument);
+        }
+
+        for region in self.regions.remove(addr, len) {
+            for va in region.pages() {
+                self.vmap.dealloc(va.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the permission of the mapped pages in `[addr, addr + len)` to
+    /// `perm`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` for an empty or misaligned range and
+    /// `OsError::BadAddress` if part of the range is not mapped.
+    pub fn mprotect(&mut self, addr: usize, len: usize, perm: PagePerm) -> OsResult<()> {
+        let len = Self::page_range(addr, len)?;
+        for region in self.regions.protect(addr, len, perm)? {
+            for va in region.pages() {
+                self.vmap.set_perm(va.into(), perm);
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,33 +2,74 @@ use core::fmt;
 
 use alloc::boxed::Box;
 
-use crate::process::Process;
+use kernel_api::{PROC_BLOCKED, PROC_DEAD, PROC_READY, PROC_RUNNING, PROC_STOPPED, PROC_ZOMBIE};
 
-/// Type of a function used to determine if a process is ready to be scheduled
-/// again. The scheduler calls this function when it is the process's turn to
-/// execute. If the function returns `true`, the process is scheduled. If it
-/// returns `false`, the process is not scheduled, and this function will be
-/// called on the next time slice.
-pub type EventPollFn = Box<dyn FnMut(&mut Process) -> bool + Send>;
+use crate::process::{Id, Process};
+
+/// Type of a function that finishes the blocked system call of a process
+/// when the process is woken. It is called with the process and whether its
+/// deadline passed, only when an event the process waits for occurs or the
+/// deadline passes, never on every tick. If the function returns `true`, the
+/// process is made ready again. If it returns `false`, the process keeps
+/// waiting for the next occurrence of the event.
+pub type WakeFn = Box<dyn FnMut(&mut Process, bool) -> bool + Send>;
+
+/// Something a blocked process waits for. Whoever makes the event happen
+/// wakes the processes waiting for it with `GlobalScheduler::wake()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    /// Input arriving on the console.
+    Console,
+    /// Data or room in the pipe with the given ID, or one of its ends
+    /// closing.
+    Pipe(usize),
+    /// A child of the given process exiting.
+    Child(Id),
+    /// A `futex_wake` on the futex word at the given physical address.
+    Futex(usize),
+}
 
 /// The scheduling state of a process.
 pub enum State {
     /// The process is ready to be scheduled.
     Ready,
-    /// The process is waiting on an event to occur before it can be scheduled.
-    Waiting(EventPollFn),
+    /// The process is blocked on the wait queue of an event, the timer queue
+    /// or both, and runs the function when woken.
+    Blocked(WakeFn),
     /// The process is currently running.
     Running,
+    /// The process was stopped by a signal and is not scheduled until it
+    /// receives `SIGCONT` or `SIGKILL`.
+    Stopped,
+    /// The process has exited with the given status and is kept around until
+    /// its parent reaps it with `wait`.
+    Zombie(u64),
     /// The process is currently dead (ready to be reclaimed).
     Dead,
 }
 
+impl State {
+    /// Returns the `PROC_*` value of the state, as `procinfo` reports it.
+    pub fn code(&self) -> u64 {
+        match *self {
+            State::Ready => PROC_READY,
+            State::Running => PROC_RUNNING,
+            State::Blocked(_) => PROC_BLOCKED,
+            State::Stopped => PROC_STOPPED,
+            State::Zombie(_) => PROC_ZOMBIE,
+            State::Dead => PROC_DEAD,
+        }
+    }
+}
+
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             State::Ready => write!(f, "State::Ready"),
+            State::Blocked(_) => write!(f, "State::Blocked"),
             State::Running => write!(f, "State::Running"),
-            State::Waiting(_) => write!(f, "State::Waiting"),
+            State::Stopped => write!(f, "State::Stopped"),
+            State::Zombie(status) => write!(f, "State::Zombie({})", status),
             State::Dead => write!(f, "State::Dead"),
         }
     }
@@ -0,0 +1,36 @@
+use core::time::Duration;
+
+use kernel_api::PROC_STATES;
+
+use crate::process::State;
+
+/// What a process spent its time on, reported by `procinfo`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The CPU time spent running the process's own code.
+    pub user_time: Duration,
+    /// The CPU time spent in the kernel on behalf of the process, or running
+    /// a kernel thread.
+    pub kernel_time: Duration,
+    /// The number of times the process was switched to.
+    pub switches: u64,
+    /// The number of times the process entered each state, indexed by
+    /// `State::code()`.
+    pub transitions: [u64; PROC_STATES],
+}
+
+impl Stats {
+    /// Charges `user` and `kernel` time.
+    pub fn charge(&mut self, user: Duration, kernel: Duration) {
+        self.user_time += user;
+        self.kernel_time += kernel;
+    }
+
+    /// Records that the process entered `state`.
+    pub fn entered(&mut self, state: &State) {
+        self.transitions[state.code() as usize] += 1;
+        if let State::Running = state {
+            self.switches += 1;
+        }
+    }
+}
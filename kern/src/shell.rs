@@ -2,18 +2,26 @@ use io::{Read, Write as ioWrite};
 use shim::io;
 use shim::path::{Component, Path, PathBuf};
 
+use alloc::vec::Vec;
 use core::fmt::Write;
 use core::str;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 use stack_vec::StackVec;
 
 use fat32::traits::FileSystem;
 use fat32::traits::{Dir, Entry, File, Metadata, Timestamp};
 
-use crate::console::{kprint, kprintln, CONSOLE};
+use crate::console::{kprint, kprintln, CONSOLE, CONSOLE_INPUT, CTRL_C};
+use crate::fs::SECTORS;
 use crate::ALLOCATOR;
-use crate::FILESYSTEM;
+use crate::process::{Process, Signals, PROCESSES, TRAP_FRAMES};
+use crate::sync::Condvar;
+use crate::{FILESYSTEM, SCHEDULER};
 
-use kernel_api::syscall;
+use kernel_api::{syscall, OsError, ProcInfo, SCHED_DEADLINE, SIGNAL_EXIT_BASE};
+use kernel_api::{SIGALRM, SIGCONT, SIGHUP, SIGINT, SIGKILL, SIGPIPE, SIGQUIT, SIGSTOP, SIGTERM, SIGTSTP, SIGUSR1, SIGUSR2};
+use kernel_api::{PROC_BLOCKED, PROC_READY, PROC_RUNNING, PROC_STOPPED, PROC_ZOMBIE};
 
 /// Error type for `Command` parse failures.
 #[derive(Debug)]
@@ -54,20 +62,41 @@ impl<'a> Command<'a> {
     }
 }
 
-fn read_and_parse_line<'a>(line_buf: &'a mut [u8], args_buf: &'a mut [&'a str]) -> Result<Command<'a>, Error> {
-    let mut console = CONSOLE.lock();
+/// Waits for a byte from the console, sleeping on `CONSOLE_INPUT` until
+/// there is one. A Ctrl-C is returned as `CTRL_C`.
+fn read_byte() -> u8 {
+    let mut byte = None;
+    CONSOLE_INPUT.wait_until(|| {
+        let mut console = CONSOLE.lock();
+        if console.take_interrupt() {
+            byte = Some(CTRL_C);
+        } else if console.has_byte() {
+            byte = Some(console.read_byte());
+        }
+        byte.is_some()
+    });
+    byte.unwrap()
+}
 
+fn read_and_parse_line<'a>(line_buf: &'a mut [u8], args_buf: &'a mut [&'a str]) -> Result<Command<'a>, Error> {
     let max = line_buf.len();
     let mut n: usize = 0;
 
     // we can probably use a StackVec<u8> and ::{push, pop} to avoid book-keeping with the count here
     loop {
-        let b = console.read_byte();
+        let b = read_byte();
+        let mut console = CONSOLE.lock();
         if n == 0 && b == 0x0 {
             // null bytes from the Rx line
             continue;
         }
 
+        if b == CTRL_C {
+            // Ctrl-C abandons the line.
+            console.write_str("^C\r\n").unwrap();
+            return Err(Error::Empty);
+        }
+
         if b == 0x7f || b == 0x08 {
             if n == 0 {
                 console.write_byte(0x7);
@@ -182,7 +211,6 @@ fn ls<'a>(cmd: Command<'a>, cwd: &PathBuf) {
 fn cat<'a>(cmd: Command<'a>, cwd: &PathBuf) {
     fn cat_file(f: &str, path: PathBuf) {
         let mut buf: [u8; 512] = [0; 512];
-        let mut console = CONSOLE.lock();
 
         let res = FILESYSTEM.open(&path).and_then(|entry| {
             entry
@@ -206,6 +234,7 @@ fn cat<'a>(cmd: Command<'a>, cwd: &PathBuf) {
                         if n == 0 {
                             break;
                         } else {
+                            let mut console = CONSOLE.lock();
                             for b in &buf[..n] {
                                 if *b == b'\n' {
                                     // LF CR will go to LF LF CR but that's fine I guess since
@@ -255,6 +284,458 @@ fn sleep<'a>(cmd: Command<'a>) {
     }
 }
 
+fn strace<'a>(cmd: Command<'a>, cwd: &PathBuf) {
+    match cmd.args[1..] {
+        ["run", path] => {
+            let path = absolute_path(path, cwd);
+            match Process::load(&path) {
+                Ok(mut process) => {
+                    process.trace = true;
+                    match SCHEDULER.add(process) {
+                        Some(id) => kprintln!("tracing process {}", id),
+                        None => kprintln!("error: no free process ID"),
+                    }
+                }
+                Err(e) => kprintln!("{}: {:?}", path.display(), e),
+            }
+        }
+        [pid] | ["off", pid] => {
+            let on = cmd.args[1] != "off";
+            let res = pid
+                .parse::<u64>()
+                .map_err(|_| OsError::InvalidArgument)
+                .and_then(|pid| SCHEDULER.with_process(pid, |p| p.trace = on));
+            if let Err(e) = res {
+                kprintln!("strace: {}: {:?}", pid, e);
+            }
+        }
+        _ => kprintln!("Usage: strace <pid> | strace off <pid> | strace run <path>"),
+    }
+}
+
+/// The ID of the job the shell waits for in the foreground, or `0` while
+/// there is none.
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+/// Notified when a job is put in the foreground.
+static FOREGROUND_SET: Condvar = Condvar::new();
+
+/// Watches the console for Ctrl-C while the shell waits for a job in the
+/// foreground, and interrupts the job: with `SIGINT` the first time, and
+/// with `SIGKILL` after that, in case the job handles or ignores `SIGINT`.
+///
+/// This runs as a kernel thread of its own, since the shell is blocked in
+/// `wait` meanwhile.
+pub fn watch_console() -> ! {
+    loop {
+        FOREGROUND_SET.wait_until(|| FOREGROUND.load(Ordering::SeqCst) != 0);
+        let job = FOREGROUND.load(Ordering::SeqCst);
+        let mut sig = SIGINT;
+        loop {
+            let mut interrupted = false;
+            CONSOLE_INPUT.wait_until(|| {
+                interrupted = CONSOLE.lock().take_interrupt();
+                interrupted || FOREGROUND.load(Ordering::SeqCst) != job
+            });
+            if !interrupted {
+                break;
+            }
+
+            kprintln!("^C");
+            let _ = SCHEDULER.signal(job, sig);
+            sig = SIGKILL;
+        }
+    }
+}
+
+/// Waits for the job `pid` in the foreground, where Ctrl-C interrupts it,
+/// and reports if a signal killed it.
+fn foreground(pid: u64) {
+    // A Ctrl-C typed before is not meant for this job.
+    CONSOLE.lock().take_interrupt();
+    FOREGROUND.store(pid, Ordering::SeqCst);
+    FOREGROUND_SET.notify_all();
+
+    let res = syscall::waitpid(pid);
+
+    FOREGROUND.store(0, Ordering::SeqCst);
+    CONSOLE_INPUT.notify_all();
+    match res {
+        Ok((_, status)) if status > SIGNAL_EXIT_BASE => {
+            kprintln!("[{}] killed by signal {}", pid, status - SIGNAL_EXIT_BASE)
+        }
+        Ok(_) => (),
+        Err(e) => kprintln!("wait: {}: {:?}", pid, e),
+    }
+}
+
+/// Parses the job ID `pid` of a command named `name`, printing an error if
+/// it is not a number.
+fn job_id(name: &str, pid: &str) -> Option<u64> {
+    let id = pid.parse::<u64>().ok();
+    if id.is_none() {
+        kprintln!("{}: {}: {:?}", name, pid, OsError::InvalidArgument);
+    }
+    id
+}
+
+fn spawn<'a>(cmd: Command<'a>, cwd: &PathBuf) {
+    let (path, background) = match cmd.args[1..] {
+        [path] => (path, false),
+        [path, "&"] => (path, true),
+        _ => {
+            kprintln!("Usage: spawn <path> [&]");
+            return;
+        }
+    };
+
+    let path = absolute_path(path, cwd);
+    let mut process = match Process::load(&path) {
+        Ok(process) => process,
+        Err(e) => {
+            kprintln!("{}: {:?}", path.display(), e);
+            return;
+        }
+    };
+    // The shell is the parent, so that it can wait for the job.
+    process.parent = syscall::getpid().ok();
+    process.cwd = cwd.clone();
+
+    match SCHEDULER.add(process) {
+        Some(pid) if background => kprintln!("[{}]", pid),
+        Some(pid) => foreground(pid),
+        None => kprintln!("error: no free process ID"),
+    }
+}
+
+fn wait<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [pid] => {
+            if let Some(pid) = job_id("wait", pid) {
+                foreground(pid);
+            }
+        }
+        _ => kprintln!("Usage: wait <pid>"),
+    }
+}
+
+fn fg<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [pid] => {
+            if let Some(pid) = job_id("fg", pid) {
+                // Resumes the job if it is stopped.
+                match SCHEDULER.signal(pid, SIGCONT) {
+                    Ok(()) => foreground(pid),
+                    Err(e) => kprintln!("fg: {}: {:?}", pid, e),
+                }
+            }
+        }
+        _ => kprintln!("Usage: fg <pid>"),
+    }
+}
+
+fn bg<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [pid] => {
+            if let Some(pid) = job_id("bg", pid) {
+                match SCHEDULER.signal(pid, SIGCONT) {
+                    Ok(()) => kprintln!("[{}]", pid),
+                    Err(e) => kprintln!("bg: {}: {:?}", pid, e),
+                }
+            }
+        }
+        _ => kprintln!("Usage: bg <pid>"),
+    }
+}
+
+/// Returns the number of the signal `name`, given as a number or as a name
+/// with or without the `SIG` prefix.
+fn signal_number(name: &str) -> Option<u64> {
+    if let Ok(sig) = name.parse::<u64>() {
+        return Signals::check(sig).ok().map(|_| sig);
+    }
+    let sig = match name.trim_start_matches("SIG") {
+        "HUP" => SIGHUP,
+        "INT" => SIGINT,
+        "QUIT" => SIGQUIT,
+        "KILL" => SIGKILL,
+        "USR1" => SIGUSR1,
+        "USR2" => SIGUSR2,
+        "PIPE" => SIGPIPE,
+        "ALRM" => SIGALRM,
+        "TERM" => SIGTERM,
+        "CONT" => SIGCONT,
+        "STOP" => SIGSTOP,
+        "TSTP" => SIGTSTP,
+        _ => return None,
+    };
+    Some(sig)
+}
+
+fn kill<'a>(cmd: Command<'a>) {
+    let (sig, pid) = match cmd.args[1..] {
+        [pid] => (Some(SIGTERM), pid),
+        [sig, pid] if sig.starts_with('-') => (signal_number(&sig[1..]), pid),
+        _ => {
+            kprintln!("Usage: kill [-<signal>] <pid>");
+            return;
+        }
+    };
+
+    let res = match (sig, pid.parse::<u64>()) {
+        (Some(sig), Ok(pid)) => SCHEDULER.signal(pid, sig),
+        _ => Err(OsError::InvalidArgument),
+    };
+    if let Err(e) = res {
+        kprintln!("kill: {}: {:?}", pid, e);
+    }
+}
+
+fn sched<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [] => kprintln!("{}", SCHEDULER.policy()),
+        [name] => {
+            if SCHEDULER.set_policy(name).is_err() {
+                kprintln!("sched: unknown policy: {}", name);
+            }
+        }
+        _ => kprintln!("Usage: sched [rr|mlfq|cfs]"),
+    }
+}
+
+fn nice<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [pid, nice] => {
+            let res = match (pid.parse::<u64>(), nice.parse::<i64>()) {
+                (Ok(pid), Ok(nice)) => SCHEDULER.set_nice(pid, nice),
+                _ => Err(OsError::InvalidArgument),
+            };
+            if let Err(e) = res {
+                kprintln!("nice: {}: {:?}", pid, e);
+            }
+        }
+        _ => kprintln!("Usage: nice <pid> <nice>"),
+    }
+}
+
+fn rt<'a>(cmd: Command<'a>) {
+    let reservation = match cmd.args[1..] {
+        [_, "off"] => Ok(None),
+        [_, budget, period] => match (budget.parse::<u64>(), period.parse::<u64>()) {
+            (Ok(budget), Ok(period)) => Ok(Some((Duration::from_millis(budget), Duration::from_millis(period)))),
+            _ => Err(OsError::InvalidArgument),
+        },
+        _ => {
+            kprintln!("Usage: rt <pid> <budget-ms> <period-ms> | rt <pid> off");
+            return;
+        }
+    };
+
+    let pid = cmd.args[1];
+    let res = match pid.parse::<u64>() {
+        Ok(id) => reservation.and_then(|reservation| SCHEDULER.set_reservation(id, reservation)),
+        Err(_) => Err(OsError::InvalidArgument),
+    };
+    if let Err(e) = res {
+        kprintln!("rt: {}: {:?}", pid, e);
+    }
+}
+
+/// Returns a short name for the `PROC_*` state `state`.
+fn state_name(state: u64) -> &'static str {
+    match state {
+        PROC_READY => "ready",
+        PROC_RUNNING => "run",
+        PROC_BLOCKED => "block",
+        PROC_STOPPED => "stop",
+        PROC_ZOMBIE => "zombie",
+        _ => "dead",
+    }
+}
+
+/// Returns what kind of task `info` describes.
+fn kind(info: &ProcInfo) -> &'static str {
+    if info.kernel != 0 {
+        "kthread"
+    } else if info.tgid != info.pid {
+        "thread"
+    } else {
+        "process"
+    }
+}
+
+fn ps<'a>(cmd: Command<'a>) {
+    let infos = SCHEDULER.procinfo();
+    match cmd.args[1..] {
+        [] => {
+            kprintln!(
+                "{:>5} {:>5} {:>5} {:<7} {:<6} {:>3} {:>3} {:>9} {:>9} {:>8} {:>5}",
+                "PID", "PPID", "TGID", "KIND", "STATE", "NI", "CPU", "USER(ms)", "SYS(ms)", "SWITCHES", "PAGES"
+            );
+            for info in infos.iter() {
+                kprintln!(
+                    "{:>5} {:>5} {:>5} {:<7} {:<6} {:>3} {:>3} {:>9} {:>9} {:>8} {:>5}",
+                    info.pid,
+                    info.ppid,
+                    info.tgid,
+                    kind(info),
+                    state_name(info.state),
+                    info.nice,
+                    info.core,
+                    info.user_us / 1000,
+                    info.kernel_us / 1000,
+                    info.switches,
+                    info.pages
+                );
+            }
+        }
+        ["-s"] => {
+            kprintln!("{:>5} {:>8} {:>8} {:>8} {:>8} {:>8}", "PID", "READY", "RUN", "BLOCK", "STOP", "ZOMBIE");
+            for info in infos.iter() {
+                let t = &info.transitions;
+                kprintln!(
+                    "{:>5} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                    info.pid,
+                    t[PROC_READY as usize],
+                    t[PROC_RUNNING as usize],
+                    t[PROC_BLOCKED as usize],
+                    t[PROC_STOPPED as usize],
+                    t[PROC_ZOMBIE as usize]
+                );
+            }
+        }
+        ["-r"] => {
+            kprintln!("{:>5} {:<6} {:>3} {:>11} {:>11} {:>6}", "PID", "STATE", "CPU", "BUDGET(us)", "PERIOD(us)", "MISSES");
+            for info in infos.iter().filter(|info| info.policy == SCHED_DEADLINE) {
+                kprintln!(
+                    "{:>5} {:<6} {:>3} {:>11} {:>11} {:>6}",
+                    info.pid,
+                    state_name(info.state),
+                    info.core,
+                    info.budget_us,
+                    info.period_us,
+                    info.deadline_misses
+                );
+            }
+        }
+        _ => kprintln!("Usage: ps [-s | -r]"),
+    }
+}
+
+/// How often `top` redraws.
+const TOP_REFRESH: Duration = Duration::from_secs(1);
+
+/// Waits for up to `t`, returning early with `true` if `q` or Ctrl-C is
+/// typed on the console.
+fn wait_for_quit(t: Duration) -> bool {
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::from_secs(0);
+    while waited < t {
+        let quit = {
+            let mut console = CONSOLE.lock();
+            let mut quit = console.take_interrupt();
+            while console.has_byte() {
+                quit |= console.read_byte() == b'q';
+            }
+            quit
+        };
+        if quit {
+            return true;
+        }
+        let _ = syscall::sleep(step);
+        waited += step;
+    }
+    false
+}
+
+/// Shows the processes, busiest first, redrawing the screen every
+/// `TOP_REFRESH` until `q` is typed.
+fn top<'a>(cmd: Command<'a>) {
+    if cmd.args.len() != 1 {
+        kprintln!("Usage: top");
+        return;
+    }
+
+    let total = |info: &ProcInfo| info.user_us + info.kernel_us;
+    let mut last: Vec<(u64, u64)> = Vec::new();
+    let mut last_time = pi::timer::current_time();
+    loop {
+        let now = pi::timer::current_time();
+        let interval = now.checked_sub(last_time).unwrap_or_default().as_micros().max(1) as u64;
+        let infos = SCHEDULER.procinfo();
+
+        // The share of one core each process used since the last redraw, in
+        // tenths of a percent.
+        let mut rows: Vec<(u64, &ProcInfo)> = infos
+            .iter()
+            .map(|info| {
+                let before = last.iter().find(|&&(pid, _)| pid == info.pid).map(|&(_, t)| t).unwrap_or(0);
+                (total(info).saturating_sub(before) * 1000 / interval, info)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.pid.cmp(&b.1.pid)));
+
+        let count = |state: u64| infos.iter().filter(|info| info.state == state).count();
+        kprint!("\x1b[H\x1b[2J");
+        kprintln!(
+            "top - up {}s, {} tasks: {} running, {} ready, {} blocked, {} stopped (q to quit)",
+            now.as_secs(),
+            infos.len(),
+            count(PROC_RUNNING),
+            count(PROC_READY),
+            count(PROC_BLOCKED),
+            count(PROC_STOPPED)
+        );
+        kprintln!();
+        kprintln!(
+            "\x1b[7m{:>5} {:<7} {:<6} {:>3} {:>3} {:>6} {:>9} {:>9} {:>8} {:>5}\x1b[0m",
+            "PID", "KIND", "STATE", "NI", "CPU", "%CPU", "USER(ms)", "SYS(ms)", "SWITCHES", "PAGES"
+        );
+        for &(share, info) in rows.iter() {
+            kprintln!(
+                "{:>5} {:<7} {:<6} {:>3} {:>3} {:>4}.{} {:>9} {:>9} {:>8} {:>5}",
+                info.pid,
+                kind(info),
+                state_name(info.state),
+                info.nice,
+                info.core,
+                share / 10,
+                share % 10,
+                info.user_us / 1000,
+                info.kernel_us / 1000,
+                info.switches,
+                info.pages
+            );
+        }
+
+        last = infos.iter().map(|info| (info.pid, total(info))).collect();
+        last_time = now;
+        if wait_for_quit(TOP_REFRESH) {
+            break;
+        }
+    }
+}
+
+fn slabs<'a>(cmd: Command<'a>) {
+    match cmd.args[1..] {
+        [] => {
+            kprintln!("{:<12} {:>6} {:>6} {:>7} {:>9} {:>7}", "CACHE", "SIZE", "SLABS", "IN USE", "ALLOCS", "MISSES");
+            for stats in [PROCESSES.stats(), TRAP_FRAMES.stats(), SECTORS.stats()].iter() {
+                kprintln!(
+                    "{:<12} {:>6} {:>6} {:>7} {:>9} {:>7}",
+                    stats.name,
+                    stats.size,
+                    stats.slabs,
+                    stats.in_use,
+                    stats.allocs,
+                    stats.misses
+                );
+            }
+        }
+        _ => kprintln!("Usage: slabs"),
+    }
+}
+
 /// Starts a shell using `prefix` as the prefix for each line. This function
 /// never returns.
 pub fn shell(prefix: &str) {
@@ -280,6 +761,18 @@ pub fn shell(prefix: &str) {
                 "cat" => cat(cmd, &cwd),
                 "pwd" => kprintln!("{}", cwd.display()),
                 "sleep" => sleep(cmd),
+                "strace" => strace(cmd, &cwd),
+                "sched" => sched(cmd),
+                "nice" => nice(cmd),
+                "rt" => rt(cmd),
+                "ps" => ps(cmd),
+                "spawn" => spawn(cmd, &cwd),
+                "wait" => wait(cmd),
+                "fg" => fg(cmd),
+                "bg" => bg(cmd),
+                "kill" => kill(cmd),
+                "top" => top(cmd),
+                "slabs" => slabs(cmd),
                 "exit" => break 'shell_loop,
                 _ => kprintln!("unknown command: {}", cmd.path()),
             },
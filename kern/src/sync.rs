@@ -0,0 +1,331 @@
+//! Synchronization primitives that put the waiting kernel thread to sleep
+//! instead of spinning: a counting `Semaphore`, a reader-writer `RwLock` and
+//! a `Condvar`.
+//!
+//! Each is built on a 32-bit word that sleepers wait on as a futex, keyed by
+//! its kernel address (see `sys_futex_wait`). Only kernel threads can sleep.
+//! Exception handlers, which run on the core's own stack with IRQs masked,
+//! and the boot code spin on the word instead. A handler spinning on a lock
+//! held by a preempted kernel thread waits for another core to pick that
+//! thread up, so these locks should only guard work that handlers rarely
+//! contend on. A system call handler that does contend on a `Semaphore` can
+//! `watch()` it instead and block its process on the semaphore's `event()`.
+//!
+//! Since a thread holding one of these may sleep or be preempted, they must
+//! not be taken while holding a `Mutex` or an `IrqMutex`.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicU32, Ordering};
+
+use kernel_api::syscall::futex_wait;
+
+use crate::process::Event;
+use crate::SCHEDULER;
+
+/// Wakes every sleeper of a word.
+const WAKE_ALL: usize = usize::max_value();
+
+/// Returns `true` if the caller is a kernel thread, which runs at EL1 on
+/// `SP_EL0`, and so may sleep.
+fn can_sleep() -> bool {
+    aarch64::sp_sel() == 0
+}
+
+/// A futex word and the number of kernel threads sleeping on it.
+///
+/// Sleepers announce themselves before checking the word and wakers change
+/// the word before checking for sleepers, both sequentially consistent, so
+/// either the waker sees the sleeper or the sleeper sees the new value.
+struct WaitWord {
+    word: AtomicU32,
+    sleepers: AtomicU32,
+}
+
+impl WaitWord {
+    const fn new(val: u32) -> WaitWord {
+        WaitWord { word: AtomicU32::new(val), sleepers: AtomicU32::new(0) }
+    }
+
+    /// Waits until the word may no longer hold `expected`. Wake-ups may be
+    /// spurious, so the caller checks the word again.
+    fn wait(&self, expected: u32) {
+        if !can_sleep() {
+            spin_loop_hint();
+            return;
+        }
+
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        // `WouldBlock` means the word changed already, and `Interrupted` is a
+        // spurious wake-up: either way the caller looks at the word again.
+        let _ = futex_wait(&self.word, expected, None);
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wakes up to `n` threads sleeping on the word.
+    fn wake(&self, n: usize) {
+        if let Some(event) = self.event() {
+            SCHEDULER.wake(event, n);
+        }
+    }
+
+    /// Returns the event sleepers on the word block on, or `None` if nobody
+    /// sleeps on it.
+    fn event(&self) -> Option<Event> {
+        match self.sleepers.load(Ordering::SeqCst) {
+            0 => None,
+            _ => Some(Event::Futex(&self.word as *const AtomicU32 as usize)),
+        }
+    }
+}
+
+/// A counting semaphore.
+pub struct Semaphore {
+    count: WaitWord,
+}
+
+impl Semaphore {
+    /// Returns a semaphore with `count` units available.
+    pub const fn new(count: u32) -> Semaphore {
+        Semaphore { count: WaitWord::new(count) }
+    }
+
+    /// Takes a unit if one is available and returns whether it did.
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.word.load(Ordering::SeqCst);
+        while count != 0 {
+            match self.count.word.compare_exchange_weak(count, count - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(current) => count = current,
+            }
+        }
+        false
+    }
+
+    /// Takes a unit, sleeping until one is available.
+    pub fn down(&self) {
+        while !self.try_down() {
+            self.count.wait(0);
+        }
+    }
+
+    /// Returns a unit, waking a thread waiting for one.
+    pub fn up(&self) {
+        self.count.word.fetch_add(1, Ordering::SeqCst);
+        self.count.wake(1);
+    }
+
+    /// Returns `true` if a unit is available, without taking it.
+    pub fn available(&self) -> bool {
+        self.count.word.load(Ordering::SeqCst) != 0
+    }
+
+    /// Counts a waiter that does not sleep in `down()`, such as a process
+    /// blocked on `event()` by a system call handler, so that `up()` wakes
+    /// the event. Either `up()` sees the waiter or a check of `available()`
+    /// made after this call sees the unit. Every call must be matched by an
+    /// `unwatch()`.
+    pub fn watch(&self) {
+        self.count.sleepers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stops counting a waiter counted by `watch()`.
+    pub fn unwatch(&self) {
+        self.count.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Returns the event `up()` wakes while anybody waits for a unit.
+    pub fn event(&self) -> Event {
+        Event::Futex(&self.count.word as *const AtomicU32 as usize)
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore").field("count", &self.count.word.load(Ordering::Relaxed)).finish()
+    }
+}
+
+/// The state of an `RwLock` held for writing. Otherwise, the state is the
+/// number of readers.
+const WRITER: u32 = u32::max_value();
+
+/// A reader-writer lock: any number of readers or a single writer.
+pub struct RwLock<T> {
+    state: WaitWord,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// Shared access to the data of an `RwLock`, released when dropped.
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+/// Exclusive access to the data of an `RwLock`, released when dropped.
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> !Send for RwLockReadGuard<'a, T> {}
+impl<'a, T> !Send for RwLockWriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RwLockReadGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RwLockWriteGuard<'a, T> {}
+
+impl<T> RwLock<T> {
+    /// Returns a new, unlocked reader-writer lock protecting `val`.
+    pub const fn new(val: T) -> RwLock<T> {
+        RwLock { state: WaitWord::new(0), data: UnsafeCell::new(val) }
+    }
+
+    /// Takes the lock for reading if no writer holds it.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let mut state = self.state.word.load(Ordering::SeqCst);
+        while state < WRITER - 1 {
+            match self.state.word.compare_exchange_weak(state, state + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(current) => state = current,
+            }
+        }
+        None
+    }
+
+    /// Takes the lock for reading, sleeping while a writer holds it.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.state.wait(WRITER);
+        }
+    }
+
+    /// Takes the lock for writing if nobody holds it.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        match self.state.word.compare_exchange(0, WRITER, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => Some(RwLockWriteGuard { lock: self }),
+            Err(_) => None,
+        }
+    }
+
+    /// Takes the lock for writing, sleeping while anybody else holds it.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            let state = self.state.word.load(Ordering::SeqCst);
+            if state != 0 {
+                self.state.wait(state);
+            }
+        }
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.lock.state.word.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.lock.state.wake(WAKE_ALL);
+        }
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.word.store(0, Ordering::SeqCst);
+        self.lock.state.wake(WAKE_ALL);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// A condition variable: lets kernel threads sleep until a condition, which
+/// whoever makes it true announces with `notify_one()` or `notify_all()`,
+/// holds.
+pub struct Condvar {
+    seq: WaitWord,
+}
+
+impl Condvar {
+    /// Returns a new condition variable.
+    pub const fn new() -> Condvar {
+        Condvar { seq: WaitWord::new(0) }
+    }
+
+    /// Sleeps until `cond` returns `true`. `cond` is called once first and
+    /// again after each notification, and may take whatever locks the
+    /// condition needs.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut cond: F) {
+        loop {
+            let seq = self.seq.word.load(Ordering::SeqCst);
+            if cond() {
+                return;
+            }
+            self.seq.wait(seq);
+        }
+    }
+
+    /// Wakes one thread waiting on the condition variable.
+    pub fn notify_one(&self) {
+        self.seq.word.fetch_add(1, Ordering::SeqCst);
+        self.seq.wake(1);
+    }
+
+    /// Wakes every thread waiting on the condition variable.
+    pub fn notify_all(&self) {
+        if let Some(event) = self.advance() {
+            SCHEDULER.wake(event, WAKE_ALL);
+        }
+    }
+
+    /// Records a notification and returns the event to wake the waiters
+    /// through, if there are any. This is for the scheduler, which cannot
+    /// call `notify_all()` from inside its own critical section.
+    pub fn advance(&self) -> Option<Event> {
+        self.seq.word.fetch_add(1, Ordering::SeqCst);
+        self.seq.event()
+    }
+
+    /// Returns `true` if a thread sleeps on the condition variable.
+    pub fn has_waiters(&self) -> bool {
+        self.seq.event().is_some()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Condvar").field("seq", &self.seq.word.load(Ordering::Relaxed)).finish()
+    }
+}
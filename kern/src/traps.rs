@@ -6,12 +6,16 @@ pub mod irq;
 pub use self::frame::TrapFrame;
 
 use pi::interrupt::{Controller, Interrupt};
+use pi::local_interrupt::{LocalController, LocalInterrupt};
 
 use self::syndrome::Syndrome;
 use self::syscall::handle_syscall;
 use crate::console::kprintln;
-use crate::IRQ;
+use crate::percore;
+use crate::process::signal;
+use crate::{IRQ, SCHEDULER};
 use aarch64::*;
+use kernel_api::{SIGBUS, SIGILL, SIGSEGV};
 
 #[repr(u16)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -45,6 +49,7 @@ pub struct Info {
 #[no_mangle]
 pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
     // kprintln!("_________ exception handler {:?} ________", info);
+    percore::account(info.source == Source::LowerAArch64);
     match info.kind {
         Kind::Synchronous => {
             let syn = Syndrome::from(esr);
@@ -58,16 +63,40 @@ pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
                     // kprintln!("handling syscall {:?}", syn);
                     handle_syscall(n, tf);
                 },
+                _ if info.source == Source::LowerAArch64 => {
+                    let sig = match syn {
+                        Syndrome::DataAbort { .. } | Syndrome::InstructionAbort { .. } => SIGSEGV,
+                        Syndrome::PCAlignmentFault | Syndrome::SpAlignmentFault => SIGBUS,
+                        _ => SIGILL,
+                    };
+                    kprintln!(
+                        "process {}: {:?} at {:#x}, FAR: {:#0x}",
+                        tf.tpidr,
+                        syn,
+                        tf.elr,
+                        unsafe { FAR_EL1.get() }
+                    );
+                    let _ = SCHEDULER.with_process(tf.tpidr, |p| p.signals.force(sig));
+                }
                 _ => {
                     kprintln!("Syndrome {:#?} not handled, FAR: {:#0x}", syn, unsafe { FAR_EL1.get() });
                 }
             }
         },
         Kind::Irq => {
-            let controller = Controller::new();
-            for &int in Interrupt::iter() {
-                if controller.is_pending(int) {
-                    IRQ.invoke(int, tf);
+            let local = LocalController::new(percore::getcpu());
+            for &int in LocalInterrupt::iter() {
+                if int != LocalInterrupt::Gpu && local.is_pending(int) {
+                    IRQ.invoke_local(int, tf);
+                }
+            }
+
+            if local.is_pending(LocalInterrupt::Gpu) {
+                let controller = Controller::new();
+                for &int in Interrupt::iter() {
+                    if controller.is_pending(int) {
+                        IRQ.invoke(int, tf);
+                    }
                 }
             }
         },
@@ -75,5 +104,12 @@ pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
             kprintln!("Interrupt not handled: {:?}", info.kind);
         }
     }
+
+    // Whatever process `tf` belongs to now, act on its pending signals
+    // before it returns to user mode.
+    if tf.spsr & SPSR_EL1::M == 0 {
+        signal::deliver(tf);
+    }
+    percore::account(false);
     // kprintln!("________ handler returns _________");
 }
@@ -1,36 +1,73 @@
-use alloc::boxed::Box;
 use pi::interrupt::Interrupt;
+use pi::local_interrupt::LocalInterrupt;
 
-use crate::mutex::Mutex;
+use crate::mutex::{IrqMutex, Rank};
+use crate::param::NCORES;
+use crate::percore;
 use crate::traps::TrapFrame;
 
-pub type IrqHandler = Box<dyn FnMut(&mut TrapFrame) + Send>;
+/// An IRQ handler. A plain function rather than a boxed closure, so that
+/// registering one allocates nothing.
+pub type IrqHandler = fn(&mut TrapFrame);
 pub type IrqHandlers = [Option<IrqHandler>; Interrupt::MAX];
+pub type LocalIrqHandlers = [Option<IrqHandler>; LocalInterrupt::MAX];
 
-pub struct Irq(Mutex<Option<IrqHandlers>>);
+/// The IRQ handlers: one table for the GPU interrupts, which are routed to
+/// core 0, and one table per core for the core's local interrupts.
+pub struct Irq {
+    gpu: IrqMutex<Option<IrqHandlers>>,
+    local: [IrqMutex<Option<LocalIrqHandlers>>; NCORES],
+}
 
 impl Irq {
     pub const fn uninitialized() -> Irq {
-        Irq(Mutex::new(None))
+        Irq {
+            gpu: IrqMutex::ranked(None, Rank::Irq),
+            local: [
+                IrqMutex::ranked(None, Rank::Irq),
+                IrqMutex::ranked(None, Rank::Irq),
+                IrqMutex::ranked(None, Rank::Irq),
+                IrqMutex::ranked(None, Rank::Irq),
+            ],
+        }
     }
 
     pub fn initialize(&self) {
-        *self.0.lock() = Some([None, None, None, None, None, None, None, None]);
+        *self.gpu.lock() = Some([None, None, None, None, None, None, None, None]);
+        for local in self.local.iter() {
+            *local.lock() = Some(Default::default());
+        }
     }
 
     /// Register an irq handler for an interrupt.
     /// The caller should assure that `initialize()` has been called before calling this function.
     pub fn register(&self, int: Interrupt, handler: IrqHandler) {
-        self.0.lock().as_mut().unwrap()[int.to_index()] = Some(handler);
+        self.gpu.lock().as_mut().unwrap()[int.to_index()] = Some(handler);
+    }
+
+    /// Register an irq handler for a local interrupt of the current core.
+    /// The caller should assure that `initialize()` has been called before calling this function.
+    pub fn register_local(&self, int: LocalInterrupt, handler: IrqHandler) {
+        self.local[percore::getcpu()].lock().as_mut().unwrap()[int.to_index()] = Some(handler);
     }
 
     /// Executes an irq handler for the givven interrupt.
     /// The caller should assure that `initialize()` has been called before calling this function.
     pub fn invoke(&self, int: Interrupt, tf: &mut TrapFrame) {
-        let guard = &mut self.0.lock();
+        let guard = &mut self.gpu.lock();
         if let Some(handler) = &mut (guard.as_mut().unwrap())[int.to_index()] {
             //                       -----^^ <- This actually is operating on the Option<> via DerefMut
             handler(tf);
         }
     }
+
+    /// Executes the irq handler the current core registered for the local
+    /// interrupt `int`.
+    /// The caller should assure that `initialize()` has been called before calling this function.
+    pub fn invoke_local(&self, int: LocalInterrupt, tf: &mut TrapFrame) {
+        let guard = &mut self.local[percore::getcpu()].lock();
+        if let Some(handler) = &mut (guard.as_mut().unwrap())[int.to_index()] {
+            handler(tf);
+        }
+    }
 }
@@ -1,11 +1,34 @@
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::fmt::{self, Write};
+use core::cmp::min;
+use core::mem::size_of;
 use core::time::Duration;
 
-use crate::console::{CONSOLE, kprintln};
-use crate::process::State;
+use crate::console::kprintln;
+use crate::mutex::Mutex;
+use crate::param::PAGE_SIZE;
+use crate::process::{fd, pipe, signal, AddressSpace, Descriptor, Event, Fd, Id, Process, Signals, WakeFn};
 use crate::traps::TrapFrame;
-use crate::SCHEDULER;
+use crate::vm::{PagePerm, UserPtr, UserSlice};
+use crate::{FILESYSTEM, SCHEDULER};
 use kernel_api::*;
+use shim::path::PathBuf;
+
+/// Runs `f` with the process that made the system call in `tf`.
+///
+/// `f` runs in the scheduler's critical section, so it must not use the file
+/// system, whose lock wakes the threads sleeping on it through the
+/// scheduler. Handlers take the descriptor or address space they need out of
+/// the process and do file I/O once `f` has returned.
+fn with_current<F, R>(tf: &TrapFrame, f: F) -> OsResult<R>
+where
+    F: FnOnce(&mut Process) -> OsResult<R>,
+{
+    SCHEDULER.with_process(tf.tpidr, f).and_then(|r| r)
+}
 
 /// Sleep for `ms` milliseconds.
 ///
@@ -14,23 +37,14 @@ use kernel_api::*;
 /// In addition to the usual status value, this system call returns one
 /// parameter: the approximate true elapsed time from when `sleep` was called to
 /// when `sleep` returned.
-pub fn sys_sleep(ms: u32, tf: &mut TrapFrame) {
+pub fn sys_sleep(ms: u32, tf: &mut TrapFrame) -> OsResult<Return> {
     let start = pi::timer::current_time();
     let end = start + Duration::from_millis(ms.into());
-    kprintln!("[syscall] start={:?} end={:?}", start, end);
 
-    let state = State::Waiting(Box::new(move |p| {
+    block_on(tf, None, Some(end), move |_, _| {
         let now = pi::timer::current_time();
-        if now >= end {
-            kprintln!("returning! @ {:?}", now);
-            p.context.xregs[0] = (now - start).as_millis() as u64;
-            p.context.xregs[7] = OsError::Ok as u64;
-            true
-        } else {
-            false
-        }
-    }));
-    SCHEDULER.switch(state, tf);
+        Some(Ok(((now - start).as_millis() as u64).into()))
+    })
 }
 
 /// Returns current time.
@@ -41,37 +55,664 @@ pub fn sys_sleep(ms: u32, tf: &mut TrapFrame) {
 /// parameter:
 ///  - current time as seconds
 ///  - fractional part of the current time, in nanoseconds.
-pub fn sys_time(tf: &mut TrapFrame) {
+pub fn sys_time(_tf: &mut TrapFrame) -> OsResult<Return> {
     let now = pi::timer::current_time();
-
-    tf.xregs[0] = now.as_secs();
-    tf.xregs[1] = now.subsec_nanos() as u64;
-    tf.xregs[7] = OsError::Ok as u64;
-    // unimplemented!("sys_time()");
+    Ok((now.as_secs(), now.subsec_nanos() as u64).into())
 }
 
 /// Kills current process.
 ///
-/// This system call does not take paramer and does not return any value.
-pub fn sys_exit(tf: &mut TrapFrame) {
-    if SCHEDULER.kill(tf).is_none() {
+/// This system call takes one parameter: the exit status, which is kept until
+/// the parent process collects it with `wait`. It does not return.
+pub fn sys_exit(status: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    if SCHEDULER.exit(status, tf).is_none() {
         kprintln!("Could not find process with ID: {}", tf.tpidr);
     }
+    SCHEDULER.switch_to(tf);
+    Ok(Return::Switched)
+}
+
+/// Waits for a child process to exit.
+///
+/// This system call takes one parameter: the ID of the child to wait for, or
+/// `0` to wait for any child. It blocks until a matching child has exited.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters:
+///  - the ID of the reaped child
+///  - the child's exit status
+///
+/// Returns `OsError::NoEntry` if the caller has no matching child.
+pub fn sys_wait(pid: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let id = tf.tpidr;
+    match SCHEDULER.reap(pid, tf)? {
+        Some(reaped) => Ok(reaped.into()),
+        None => block_on(tf, Some(Event::Child(id)), None, |p, _| {
+            p.reaped.take().map(|reaped| Ok(reaped.into()))
+        }),
+    }
 }
 
-/// Write to console.
+/// Opens a file or directory.
 ///
-/// This system call takes one parameter: a u8 character to print.
+/// This system call takes two parameters: the address and length of the path
+/// of the file to open. Files are opened read-only; directories can only be
+/// read with `readdir`.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new file descriptor.
+pub fn sys_open(path: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let path = with_current(tf, |p| user_path(p, path, len))?;
+    with_fs(tf, |tf| {
+        let desc = Descriptor::open(&path)?;
+        with_current(tf, |p| p.files.insert(desc)).map(Return::from)
+    })
+}
+
+/// Copies the path at `[addr, addr + len)` from the user memory of `p` and
+/// resolves it against the working directory of `p`.
+fn user_path(p: &mut Process, addr: usize, len: usize) -> OsResult<PathBuf> {
+    let path = UserSlice::new(addr, len)?.to_string(&mut p.space()?.vmap)?;
+    Ok(p.resolve(path))
+}
+
+/// Wakes the processes blocked on the other end of a pipe, whose `event` a
+/// process just read from, wrote to or closed.
+fn notify(event: Option<Event>) {
+    if let Some(event @ Event::Pipe(_)) = event {
+        SCHEDULER.wake(event, usize::max_value());
+    }
+}
+
+/// A descriptor of a process and its address space, taken out of the
+/// scheduler's critical section to do I/O with.
+type Io = (Arc<Mutex<Descriptor>>, Arc<Mutex<AddressSpace>>);
+
+/// Returns the descriptor `fd` of `p` and the address space of `p`.
+fn io_of(p: &Process, fd: Fd) -> OsResult<Io> {
+    Ok((p.files.shared(fd)?, p.shared_space()?))
+}
+
+/// Returns the event of `fd` of `p` (see `Descriptor::event()`).
+fn fd_event(p: &Process, fd: Fd) -> OsResult<Option<Event>> {
+    Ok(p.files.get(fd)?.lock().event())
+}
+
+/// Reads from the descriptor of `io` into the user buffer `slice`. Returns
+/// `Ok(None)` if the read would block.
+fn read_into((desc, space): &Io, slice: UserSlice) -> OsResult<Option<usize>> {
+    let mut buf = vec![0u8; slice.len()];
+    let read = desc.lock().read(&mut buf)?;
+    match read {
+        Some(n) => {
+            slice.truncate(n).copy_out(&mut space.lock().vmap, &buf[..n])?;
+            Ok(Some(n))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads from a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and length of the buffer to read into. At most one page is read
+/// per call. Reading from the console blocks until input is available.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes read, which is `0` at end of file.
+pub fn sys_read(fd: Fd, buf: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let slice = UserSlice::new(buf, len)?.truncate(PAGE_SIZE);
+    let io = with_current(tf, |p| io_of(p, fd))?;
+    let event = io.0.lock().event();
+    // Files never block, but reading them takes the file system lock.
+    if event.is_none() {
+        return with_fs(tf, |_| read_into(&io, slice).map(|n| n.unwrap_or(0).into()));
+    }
+
+    match read_into(&io, slice)? {
+        Some(n) => {
+            notify(event);
+            Ok(n.into())
+        }
+        // Only the console and pipes block, so retrying in the scheduler's
+        // critical section does not touch the file system.
+        None => block_on(tf, event, None, move |p, _| {
+            io_of(p, fd).and_then(|io| read_into(&io, slice)).transpose().map(|r| r.map(Return::from))
+        }),
+    }
+}
+
+/// Writes to the descriptor of `io` from the user buffer `slice`. Returns
+/// `Ok(None)` if the write would block.
+fn write_from((desc, space): &Io, slice: UserSlice) -> OsResult<Option<usize>> {
+    let data = slice.to_vec(&mut space.lock().vmap)?;
+    desc.lock().write(&data)
+}
+
+/// Writes to a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and length of the buffer to write. At most one page is written per
+/// call. Writing to a full pipe blocks until a reader makes room.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes written.
+pub fn sys_write(fd: Fd, buf: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let slice = UserSlice::new(buf, len)?.truncate(PAGE_SIZE);
+    let io = with_current(tf, |p| io_of(p, fd))?;
+    let written = write_from(&io, slice)?;
+    let event = io.0.lock().event();
+    match written {
+        Some(n) => {
+            notify(event);
+            Ok(n.into())
+        }
+        None => block_on(tf, event, None, move |p, _| {
+            io_of(p, fd).and_then(|io| write_from(&io, slice)).transpose().map(|r| r.map(Return::from))
+        }),
+    }
+}
+
+/// Closes a file descriptor.
+///
+/// This system call takes one parameter: the file descriptor to close. It only
+/// returns the usual status value.
+pub fn sys_close(fd: Fd, tf: &mut TrapFrame) -> OsResult<Return> {
+    let event = with_current(tf, |p| {
+        let event = fd_event(p, fd)?;
+        p.files.remove(fd)?;
+        Ok(event)
+    })?;
+    notify(event);
+    Ok(Return::Unit)
+}
+
+/// Moves the offset of a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, the offset
+/// and where it is relative to (`SEEK_SET`, `SEEK_CUR` or `SEEK_END`).
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new offset from the start of the file.
+pub fn sys_seek(fd: Fd, offset: i64, whence: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let desc = with_current(tf, |p| p.files.shared(fd))?;
+    with_fs(tf, |_| desc.lock().seek(offset, whence).map(Return::from))
+}
+
+/// Returns information about a file descriptor.
+///
+/// This system call takes two parameters: the file descriptor and the address
+/// of a `Stat` to fill in. It only returns the usual status value.
+pub fn sys_fstat(fd: Fd, stat: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| {
+        let ptr = UserPtr::<Stat>::new(stat)?;
+        let stat = p.files.get(fd)?.lock().stat();
+        ptr.write(&mut p.space()?.vmap, stat)
+    })
+    .map(Return::from)
+}
+
+/// Starts a new child process.
 ///
+/// This system call takes two parameters: the address and length of the path
+/// of the program to load. The child starts in the caller's working
+/// directory with the caller's nice value and shares the caller's open file
+/// descriptors.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the ID of the new process.
+pub fn sys_spawn(path: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let (path, cwd, files, nice) =
+        with_current(tf, |p| Ok((user_path(p, path, len)?, p.cwd.clone(), p.files.clone(), p.nice)))?;
+    with_fs(tf, |tf| {
+        let mut child = Process::load(path)?;
+        child.parent = Some(tf.tpidr);
+        child.nice = nice;
+        child.cwd = cwd;
+        child.files = files;
+        SCHEDULER.add(child).ok_or(OsError::NoMemory).map(Return::from)
+    })
+}
+
+/// Starts a new thread in the current process.
+///
+/// This system call takes three parameters: the address the thread starts
+/// at, and the values of `x0` and `x1` it starts with. The thread shares the
+/// caller's address space and runs on a new stack of its own.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the ID of the new thread.
+pub fn sys_thread_create(entry: u64, arg0: u64, arg1: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let mut thread = with_current(tf, |p| p.create_thread(entry, arg0, arg1))?;
+    thread.parent = Some(tf.tpidr);
+    SCHEDULER.add(thread).ok_or(OsError::NoMemory).map(Return::from)
+}
+
+/// Terminates the current thread.
+///
+/// This system call takes one parameter: the exit status, which is kept until
+/// the thread that created this one collects it with `thread_join`. Called
+/// from the main thread, it exits the whole process. It does not return.
+pub fn sys_thread_exit(status: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    if SCHEDULER.exit_thread(status, tf).is_none() {
+        kprintln!("Could not find thread with ID: {}", tf.tpidr);
+    }
+    SCHEDULER.switch_to(tf);
+    Ok(Return::Switched)
+}
+
+/// Waits for a thread to exit.
+///
+/// This system call takes one parameter: the ID of a thread created by the
+/// caller. It blocks until that thread has exited.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the thread's exit status.
+///
+/// Returns `OsError::NoEntry` if the caller did not create such a thread.
+pub fn sys_thread_join(thread: Id, tf: &mut TrapFrame) -> OsResult<Return> {
+    if thread == 0 {
+        return Err(OsError::InvalidArgument);
+    }
+    let id = tf.tpidr;
+    match SCHEDULER.join(thread, tf)? {
+        Some((_, status)) => Ok(status.into()),
+        None => block_on(tf, Some(Event::Child(id)), None, |p, _| {
+            p.reaped.take().map(|(_, status)| Ok(status.into()))
+        }),
+    }
+}
+
+/// Returns the physical address of the futex word at `addr` in the user
+/// memory of `p`, which identifies the futex. Kernel threads wait on words
+/// in kernel memory, which is identity-mapped, so their addresses are used
+/// as they are (see `kern::sync`).
+fn futex_key(p: &mut Process, addr: usize) -> OsResult<usize> {
+    if p.space.is_none() {
+        return match addr % core::mem::align_of::<u32>() {
+            0 => Ok(addr),
+            _ => Err(OsError::BadAddress),
+        };
+    }
+    UserPtr::<u32>::new(addr)?;
+    let pa = p.space()?.vmap.translate(addr.into(), false).ok_or(OsError::BadAddress)?;
+    Ok(pa.as_usize())
+}
+
+/// Waits on a futex.
+///
+/// This system call takes three parameters: the address of a 32-bit futex
+/// word, the value the caller expects it to hold and a timeout in
+/// milliseconds, where `0` waits forever. If the word holds the expected
+/// value, the caller blocks until `futex_wake` is called on the word, the
+/// timeout expires (`OsError::IoErrorTimedOut`) or a signal arrives
+/// (`OsError::Interrupted`). Otherwise, it fails with `OsError::WouldBlock`
+/// right away. Futexes are identified by physical address, so a word mapped
+/// by several threads or processes is the same futex for all of them. It
+/// only returns the usual status value.
+pub fn sys_futex_wait(addr: usize, expected: u32, timeout: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let key = with_current(tf, |p| futex_key(p, addr))?;
+    let deadline = match timeout {
+        0 => None,
+        ms => Some(pi::timer::current_time() + Duration::from_millis(ms)),
+    };
+
+    let wake = wake_fn(|_, expired| Some(if expired { Err(OsError::IoErrorTimedOut) } else { Ok(Return::Unit) }));
+    SCHEDULER.futex_wait(key, expected, deadline, wake, tf)?;
+    Ok(Return::Switched)
+}
+
+/// Wakes processes waiting on a futex.
+///
+/// This system call takes two parameters: the address of the futex word and
+/// the maximum number of waiters to wake, which are woken in the order they
+/// started waiting.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of waiters woken.
+pub fn sys_futex_wake(addr: usize, n: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let key = with_current(tf, |p| futex_key(p, addr))?;
+    Ok(SCHEDULER.wake(Event::Futex(key), n).into())
+}
+
+/// Sets the priority of a process.
+///
+/// This system call takes two parameters: the ID of the process, or `0` for
+/// the caller, and its new nice value, which is clamped to
+/// `NICE_MIN..=NICE_MAX`. Only the caller and its children may be changed.
 /// It only returns the usual status value.
-pub fn sys_write(b: u8, tf: &mut TrapFrame) {
-    let mut console = CONSOLE.lock();
-    if b == b'\n' {
-        console.write_byte(b'\r');
+pub fn sys_setpriority(pid: Id, nice: i64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let caller = tf.tpidr;
+    let pid = if pid == 0 { caller } else { pid };
+    SCHEDULER.with_process(pid, |p| {
+        if p.id() != caller && p.parent != Some(caller) {
+            return Err(OsError::NoAccess);
+        }
+        Ok(())
+    })??;
+    SCHEDULER.set_nice(pid, nice).map(Return::from)
+}
+
+/// Sets the scheduling class of a process.
+///
+/// This system call takes two parameters: the ID of the process, `0` for
+/// the caller, and the address of a `SchedAttr`. A `SCHED_DEADLINE` process
+/// gets `budget_us` of CPU time every `period_us`, ahead of every normal
+/// process, if a core has room for it. Like `setpriority`, a process may
+/// only change itself and its children.
+///
+/// This system call does not return values.
+pub fn sys_sched_setattr(pid: Id, attr: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let attr = with_current(tf, |p| UserPtr::<SchedAttr>::new(attr)?.read(&mut p.space()?.vmap))?;
+    let reservation = match attr.policy {
+        SCHED_NORMAL => None,
+        SCHED_DEADLINE => Some((Duration::from_micros(attr.budget_us), Duration::from_micros(attr.period_us))),
+        _ => return Err(OsError::InvalidArgument),
+    };
+
+    let caller = tf.tpidr;
+    let pid = if pid == 0 { caller } else { pid };
+    SCHEDULER.with_process(pid, |p| {
+        if p.id() != caller && p.parent != Some(caller) {
+            return Err(OsError::NoAccess);
+        }
+        Ok(())
+    })??;
+    SCHEDULER.set_reservation(pid, reservation).map(Return::from)
+}
+
+/// Returns information about processes.
+///
+/// This system call takes two parameters: the address and capacity of an
+/// array of `ProcInfo` to fill in, with one entry per process or thread, by
+/// ID. Processes that do not fit are left out.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of entries filled in.
+pub fn sys_procinfo(buf: usize, count: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let size = count.checked_mul(size_of::<ProcInfo>()).ok_or(OsError::InvalidArgument)?;
+    UserPtr::<ProcInfo>::new(buf)?;
+    UserSlice::new(buf, size)?;
+
+    let infos = SCHEDULER.procinfo();
+    with_current(tf, |p| {
+        let n = min(count, infos.len());
+        for (i, info) in infos[..n].iter().enumerate() {
+            UserPtr::new(buf + i * size_of::<ProcInfo>())?.write(&mut p.space()?.vmap, *info)?;
+        }
+        Ok(n)
+    })
+    .map(Return::from)
+}
+
+/// Moves the end of the heap (the program break).
+///
+/// This system call takes one parameter: the new break address, or `0` to
+/// query the current break. Heap pages are mapped or unmapped to match.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the resulting program break.
+pub fn sys_brk(addr: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| p.space()?.set_brk(addr)).map(Return::from)
+}
+
+/// Creates a pipe.
+///
+/// This system call does not take parameter. Bytes written to the write end
+/// can be read from the read end; reads block while the pipe is empty and
+/// writes block while it is full.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters:
+///  - the file descriptor of the read end
+///  - the file descriptor of the write end
+pub fn sys_pipe(tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| {
+        let (reader, writer) = pipe::pipe();
+        let read_fd = p.files.insert(Descriptor::Pipe(reader))?;
+        match p.files.insert(Descriptor::Pipe(writer)) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                p.files.remove(read_fd)?;
+                Err(e)
+            }
+        }
+    })
+    .map(Return::from)
+}
+
+/// Duplicates a file descriptor.
+///
+/// This system call takes two parameters: the descriptor to duplicate and the
+/// descriptor number to make refer to it. Whatever the second descriptor
+/// referred to before is closed first. It only returns the usual status value.
+pub fn sys_dup2(old: Fd, new: Fd, tf: &mut TrapFrame) -> OsResult<Return> {
+    let event = with_current(tf, |p| {
+        let event = fd_event(p, new).unwrap_or(None);
+        p.files.dup2(old, new)?;
+        Ok(event)
+    })?;
+    notify(event);
+    Ok(Return::Unit)
+}
+
+/// Turns system call tracing on or off.
+///
+/// This system call takes two parameters: the ID of the process to trace, or
+/// `0` for the caller, and whether to trace (non-zero) or not (zero). Only the
+/// caller and its children may be traced. It only returns the usual status
+/// value.
+pub fn sys_trace(pid: Id, on: bool, tf: &mut TrapFrame) -> OsResult<Return> {
+    let caller = tf.tpidr;
+    let pid = if pid == 0 { caller } else { pid };
+    SCHEDULER
+        .with_process(pid, |p| {
+            if p.id() != caller && p.parent != Some(caller) {
+                return Err(OsError::NoAccess);
+            }
+            p.trace = on;
+            Ok(())
+        })?
+        .map(Return::from)
+}
+
+/// Sends a signal to a process.
+///
+/// This system call takes two parameters: the ID of the process and the
+/// signal number. Signal `0` only checks that the process exists. Like
+/// `setpriority`, only the caller and its children may be signaled, and
+/// kernel threads never. It only returns the usual status value.
+pub fn sys_kill(pid: Id, sig: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    if sig != 0 {
+        Signals::check(sig)?;
     }
-    console.write_byte(b);
-    tf.xregs[7] = OsError::Ok as u64;
-    // unimplemented!("sys_write()");
+    let caller = tf.tpidr;
+    // Checking and delivering in one critical section keeps the process
+    // from exiting or getting a new parent in between.
+    SCHEDULER
+        .critical(|scheduler| {
+            let p = scheduler.find_mut(pid).ok_or(OsError::NoEntry)?;
+            if p.space.is_none() || (p.id() != caller && p.parent != Some(caller)) {
+                return Err(OsError::NoAccess);
+            }
+            match sig {
+                0 => Ok(()),
+                _ => scheduler.signal(pid, sig),
+            }
+        })
+        .map(Return::from)
+}
+
+/// Examines and changes the action taken for a signal.
+///
+/// This system call takes three parameters: the signal number, the address
+/// of the new `SigAction` (or `0` to leave it unchanged) and the address of a
+/// `SigAction` to store the previous action in (or `0`). `SIGKILL` and
+/// `SIGSTOP` cannot be caught or ignored. It only returns the usual status
+/// value.
+pub fn sys_sigaction(sig: u64, act: usize, oldact: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    Signals::check(sig)?;
+    with_current(tf, |p| {
+        let oldact = if oldact != 0 { Some(UserPtr::<SigAction>::new(oldact)?) } else { None };
+        let old = match act {
+            0 => p.signals.get_action(sig),
+            _ => {
+                let act = UserPtr::<SigAction>::new(act)?.read(&mut p.space()?.vmap)?;
+                p.signals.set_action(sig, act)?
+            }
+        };
+        match oldact {
+            Some(ptr) => ptr.write(&mut p.space()?.vmap, old),
+            None => Ok(()),
+        }
+    })
+    .map(Return::from)
+}
+
+/// Returns from a signal handler.
+///
+/// This system call does not take parameter and does not return to its
+/// caller: the registers and signal mask saved when the handler was called,
+/// found at the caller's stack pointer, are restored. A process whose frame
+/// cannot be read is sent `SIGSEGV`.
+pub fn sys_sigreturn(tf: &mut TrapFrame) -> OsResult<Return> {
+    let pid = tf.tpidr;
+    SCHEDULER.with_process(pid, |p| {
+        if signal::pop_frame(p, tf).is_err() {
+            p.signals.force(SIGSEGV);
+        }
+    })?;
+    Ok(Return::Restored)
+}
+
+/// Reads entries of a directory.
+///
+/// This system call takes three parameters: a file descriptor open on a
+/// directory, and the address and capacity of an array of `DirEntry` to fill
+/// in. Each call continues after the entries returned by the previous one.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of entries read, which is `0` at the end of the
+/// directory.
+pub fn sys_readdir(fd: Fd, buf: usize, count: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let size = count.checked_mul(size_of::<DirEntry>()).ok_or(OsError::InvalidArgument)?;
+    UserPtr::<DirEntry>::new(buf)?;
+    UserSlice::new(buf, size)?;
+
+    let (desc, space) = with_current(tf, |p| io_of(p, fd))?;
+    with_fs(tf, |_| {
+        let entries = desc.lock().read_dir(count)?;
+        let mut space = space.lock();
+        for (i, entry) in entries.iter().enumerate() {
+            UserPtr::new(buf + i * size_of::<DirEntry>())?.write(&mut space.vmap, *entry)?;
+        }
+        Ok(entries.len().into())
+    })
+}
+
+/// Returns information about a file or directory.
+///
+/// This system call takes three parameters: the address and length of the
+/// path, and the address of a `Stat` to fill in. It only returns the usual
+/// status value.
+pub fn sys_stat(path: usize, len: usize, stat: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let ptr = UserPtr::<Stat>::new(stat)?;
+    let (path, space) = with_current(tf, |p| Ok((user_path(p, path, len)?, p.shared_space()?)))?;
+    with_fs(tf, |_| {
+        let stat = fd::stat_path(&path)?;
+        ptr.write(&mut space.lock().vmap, stat).map(Return::from)
+    })
+}
+
+/// Changes the working directory.
+///
+/// This system call takes two parameters: the address and length of the path
+/// of the new working directory. It only returns the usual status value.
+pub fn sys_chdir(path: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    let path = with_current(tf, |p| user_path(p, path, len))?;
+    with_fs(tf, |tf| {
+        if fd::stat_path(&path)?.flags & STAT_DIR == 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        with_current(tf, |p| {
+            p.cwd = path;
+            Ok(())
+        })
+        .map(Return::from)
+    })
+}
+
+/// Returns the working directory.
+///
+/// This system call takes two parameters: the address and length of the
+/// buffer to copy the absolute path of the working directory into. Fails with
+/// `OsError::InvalidArgument` if the buffer is too small.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the length of the path.
+pub fn sys_getcwd(buf: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| {
+        let cwd = p.cwd.to_str().ok_or(OsError::IoErrorInvalidData)?.as_bytes();
+        if cwd.len() > len {
+            return Err(OsError::InvalidArgument);
+        }
+        UserSlice::new(buf, cwd.len())?.copy_out(&mut p.space()?.vmap, cwd)?;
+        Ok(cwd.len())
+    })
+    .map(Return::from)
+}
+
+/// Maps memory into the current process.
+///
+/// This system call takes six parameters: the address hint, the length, the
+/// protection bits (`PROT_*`), the flags (`MAP_*`), a file descriptor and a
+/// file offset. Unless `MAP_ANONYMOUS` is given, the mapping is a private
+/// copy of the file open as the descriptor, which must be a regular file.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the address of the new mapping.
+pub fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: u64,
+    flags: u64,
+    fd: Fd,
+    offset: u64,
+    tf: &mut TrapFrame,
+) -> OsResult<Return> {
+    let perm = PagePerm::from_prot(prot).ok_or(OsError::InvalidArgument)?;
+    let fixed = flags & MAP_FIXED != 0;
+
+    if flags & MAP_ANONYMOUS != 0 {
+        return with_current(tf, |p| p.space()?.mmap(addr, len, perm, fixed)).map(Return::from);
+    }
+
+    let (desc, space) = with_current(tf, |p| io_of(p, fd))?;
+    // Map through a copy of the file so the descriptor's offset is left
+    // untouched.
+    let mut file = match &*desc.lock() {
+        Descriptor::File(file) => file.clone(),
+        _ => return Err(OsError::InvalidArgument),
+    };
+    with_fs(tf, |_| AddressSpace::mmap_file(&space, addr, len, perm, fixed, &mut file, offset).map(Return::from))
+}
+
+/// Unmaps memory from the current process.
+///
+/// This system call takes two parameters: the start address and the length
+/// of the range to unmap. It only returns the usual status value.
+pub fn sys_munmap(addr: usize, len: usize, tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| p.space()?.munmap(addr, len)).map(Return::from)
+}
+
+/// Changes the protection of mapped memory.
+///
+/// This system call takes three parameters: the start address, the length
+/// and the new protection bits (`PROT_*`). It only returns the usual status
+/// value.
+pub fn sys_mprotect(addr: usize, len: usize, prot: u64, tf: &mut TrapFrame) -> OsResult<Return> {
+    let perm = PagePerm::from_prot(prot).ok_or(OsError::InvalidArgument)?;
+    with_current(tf, |p| p.space()?.mprotect(addr, len, perm)).map(Return::from)
 }
 
 /// Returns current process's ID.
@@ -79,20 +720,451 @@ pub fn sys_write(b: u8, tf: &mut TrapFrame) {
 /// This system call does not take parameter.
 ///
 /// In addition to the usual status value, this system call returns a
-/// parameter: the current process's ID.
-pub fn sys_getpid(tf: &mut TrapFrame) {
-    tf.xregs[0] = tf.tpidr;
+/// parameter: the current process's ID, which is the ID of its main thread.
+pub fn sys_getpid(tf: &mut TrapFrame) -> OsResult<Return> {
+    with_current(tf, |p| Ok(p.main_thread())).map(Return::from)
+}
+
+/// The arguments of a system call: the caller's `x0` to `x5`. Registers past
+/// the system call's argument count read as zero.
+#[derive(Debug, Copy, Clone)]
+pub struct Args([u64; 6]);
+
+impl Args {
+    /// Collects the first `nargs` arguments from `tf`.
+    fn new(tf: &TrapFrame, nargs: usize) -> Args {
+        let mut args = [0; 6];
+        args[..nargs].copy_from_slice(&tf.xregs[..nargs]);
+        Args(args)
+    }
+
+    /// Returns the `i`th argument.
+    pub fn u64(&self, i: usize) -> u64 {
+        self.0[i]
+    }
+
+    /// Returns the `i`th argument as an address or length.
+    pub fn usize(&self, i: usize) -> usize {
+        self.0[i] as usize
+    }
+
+    /// Returns the `i`th argument as a signed integer.
+    pub fn i64(&self, i: usize) -> i64 {
+        self.0[i] as i64
+    }
+
+    /// Returns the `i`th argument as a `u32`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if the value does not fit.
+    pub fn u32(&self, i: usize) -> OsResult<u32> {
+        if self.0[i] > core::u32::MAX as u64 {
+            return Err(OsError::InvalidArgument);
+        }
+        Ok(self.0[i] as u32)
+    }
+}
+
+/// The values a system call returns in `x0..x6`, in addition to the status
+/// value in `x7`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Return {
+    /// Only the status value.
+    Unit,
+    /// One value, in `x0`.
+    One(u64),
+    /// Two values, in `x0` and `x1`.
+    Two(u64, u64),
+    /// The handler switched away from the calling process, which was saved
+    /// and will be resumed with its results already set. `tf` belongs to
+    /// another process now and must not be written to.
+    Switched,
+    /// The handler restored a saved register state into `tf`, which must
+    /// not be overwritten.
+    Restored,
+}
+
+impl From<()> for Return {
+    fn from(_: ()) -> Return {
+        Return::Unit
+    }
+}
+
+impl From<u64> for Return {
+    fn from(value: u64) -> Return {
+        Return::One(value)
+    }
+}
+
+impl From<usize> for Return {
+    fn from(value: usize) -> Return {
+        Return::One(value as u64)
+    }
+}
+
+impl From<(u64, u64)> for Return {
+    fn from((a, b): (u64, u64)) -> Return {
+        Return::Two(a, b)
+    }
+}
+
+/// Stores `result` in the return registers of `tf`.
+fn set_result(tf: &mut TrapFrame, result: OsResult<Return>) {
+    match result {
+        Ok(Return::Switched) | Ok(Return::Restored) => return,
+        Ok(Return::Unit) => (),
+        Ok(Return::One(a)) => tf.xregs[0] = a,
+        Ok(Return::Two(a, b)) => {
+            tf.xregs[0] = a;
+            tf.xregs[1] = b;
+        }
+        Err(e) => {
+            tf.xregs[7] = e as u64;
+            return;
+        }
+    }
     tf.xregs[7] = OsError::Ok as u64;
 }
 
+/// Returns a wake function that finishes a blocked system call with the
+/// result of `poll`, if it returns one. `poll` is called with the process and
+/// whether its deadline passed.
+fn wake_fn<F>(mut poll: F) -> WakeFn
+where
+    F: FnMut(&mut Process, bool) -> Option<OsResult<Return>> + Send + 'static,
+{
+    Box::new(move |p, expired| match poll(p, expired) {
+        Some(result) => {
+            if p.trace {
+                kprintln!("[strace {}] <resumed> = {}", p.id(), TraceResult(&result));
+            }
+            set_result(&mut p.context, result);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Runs `f`, which uses the file system, for the system call in `tf` and
+/// returns its result.
+///
+/// Handlers cannot sleep, so if a kernel thread holds the file system lock,
+/// the process blocks until the lock is released and then makes the system
+/// call again. `f` must not block.
+fn with_fs<F>(tf: &mut TrapFrame, f: F) -> OsResult<Return>
+where
+    F: FnOnce(&mut TrapFrame) -> OsResult<Return>,
+{
+    if let Some(result) = FILESYSTEM.try_enter(|| f(tf)) {
+        return result;
+    }
+
+    // A release since `try_enter()` is caught by `block_on()` polling the
+    // waiter once.
+    let waiter = FILESYSTEM.waiter();
+    block_on(tf, Some(waiter.event()), None, move |p, _| {
+        if !waiter.ready() {
+            return None;
+        }
+        // Back up to the `svc` instruction, so that the call is made again.
+        p.context.elr -= 4;
+        Some(Ok(Return::Restored))
+    })
+}
+
+/// Blocks the calling process until `poll` returns a result, which is then
+/// returned to the process. `poll` is called when `event` is woken or
+/// `deadline` passes, and must return a result in the latter case. With an
+/// `event`, it is also called once right away, in case the event occurred
+/// since the caller last looked for it.
+fn block_on<F>(tf: &mut TrapFrame, event: Option<Event>, deadline: Option<Duration>, poll: F) -> OsResult<Return>
+where
+    F: FnMut(&mut Process, bool) -> Option<OsResult<Return>> + Send + 'static,
+{
+    SCHEDULER.block(event, deadline, wake_fn(poll), tf);
+    Ok(Return::Switched)
+}
+
+/// A system call handler. It decodes its arguments and performs the system
+/// call on behalf of the process running in the trap frame.
+pub type Handler = fn(&Args, &mut TrapFrame) -> OsResult<Return>;
+
+/// How the trace log shows a system call argument.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Arg {
+    /// An unsigned number, in decimal.
+    Int,
+    /// A signed number, in decimal.
+    Signed,
+    /// An address or flags, in hexadecimal.
+    Hex,
+    /// The address of a path or buffer whose length is the next argument,
+    /// shown as its first `TRACE_BYTES` bytes once the call returned.
+    Bytes,
+}
+
+/// An entry of the system call table.
+pub struct Syscall {
+    pub nr: usize,
+    pub name: &'static str,
+    /// The kind of each argument, which also gives their number.
+    pub args: &'static [Arg],
+    pub handler: Handler,
+}
+
+/// Every system call, by number.
+pub static SYSCALLS: &[Syscall] = &[
+    Syscall { nr: NR_SLEEP, name: "sleep", args: &[Arg::Int], handler: |a, tf| sys_sleep(a.u32(0)?, tf) },
+    Syscall { nr: NR_TIME, name: "time", args: &[], handler: |_, tf| sys_time(tf) },
+    Syscall { nr: NR_EXIT, name: "exit", args: &[Arg::Int], handler: |a, tf| sys_exit(a.u64(0), tf) },
+    Syscall {
+        nr: NR_WRITE,
+        name: "write",
+        args: &[Arg::Int, Arg::Bytes, Arg::Int],
+        handler: |a, tf| sys_write(a.u64(0), a.usize(1), a.usize(2), tf),
+    },
+    Syscall { nr: NR_GETPID, name: "getpid", args: &[], handler: |_, tf| sys_getpid(tf) },
+    Syscall { nr: NR_WAIT, name: "wait", args: &[Arg::Int], handler: |a, tf| sys_wait(a.u64(0), tf) },
+    Syscall { nr: NR_BRK, name: "brk", args: &[Arg::Hex], handler: |a, tf| sys_brk(a.usize(0), tf) },
+    Syscall {
+        nr: NR_MMAP,
+        name: "mmap",
+        args: &[Arg::Hex, Arg::Int, Arg::Hex, Arg::Hex, Arg::Int, Arg::Int],
+        handler: |a, tf| sys_mmap(a.usize(0), a.usize(1), a.u64(2), a.u64(3), a.u64(4), a.u64(5), tf),
+    },
+    Syscall {
+        nr: NR_MUNMAP,
+        name: "munmap",
+        args: &[Arg::Hex, Arg::Int],
+        handler: |a, tf| sys_munmap(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_MPROTECT,
+        name: "mprotect",
+        args: &[Arg::Hex, Arg::Int, Arg::Hex],
+        handler: |a, tf| sys_mprotect(a.usize(0), a.usize(1), a.u64(2), tf),
+    },
+    Syscall {
+        nr: NR_SPAWN,
+        name: "spawn",
+        args: &[Arg::Bytes, Arg::Int],
+        handler: |a, tf| sys_spawn(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_OPEN,
+        name: "open",
+        args: &[Arg::Bytes, Arg::Int],
+        handler: |a, tf| sys_open(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_READ,
+        name: "read",
+        args: &[Arg::Int, Arg::Bytes, Arg::Int],
+        handler: |a, tf| sys_read(a.u64(0), a.usize(1), a.usize(2), tf),
+    },
+    Syscall { nr: NR_CLOSE, name: "close", args: &[Arg::Int], handler: |a, tf| sys_close(a.u64(0), tf) },
+    Syscall {
+        nr: NR_SEEK,
+        name: "seek",
+        args: &[Arg::Int, Arg::Signed, Arg::Int],
+        handler: |a, tf| sys_seek(a.u64(0), a.i64(1), a.u64(2), tf),
+    },
+    Syscall {
+        nr: NR_FSTAT,
+        name: "fstat",
+        args: &[Arg::Int, Arg::Hex],
+        handler: |a, tf| sys_fstat(a.u64(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_CHDIR,
+        name: "chdir",
+        args: &[Arg::Bytes, Arg::Int],
+        handler: |a, tf| sys_chdir(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_GETCWD,
+        name: "getcwd",
+        args: &[Arg::Hex, Arg::Int],
+        handler: |a, tf| sys_getcwd(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_STAT,
+        name: "stat",
+        args: &[Arg::Bytes, Arg::Int, Arg::Hex],
+        handler: |a, tf| sys_stat(a.usize(0), a.usize(1), a.usize(2), tf),
+    },
+    Syscall {
+        nr: NR_READDIR,
+        name: "readdir",
+        args: &[Arg::Int, Arg::Hex, Arg::Int],
+        handler: |a, tf| sys_readdir(a.u64(0), a.usize(1), a.usize(2), tf),
+    },
+    Syscall { nr: NR_PIPE, name: "pipe", args: &[], handler: |_, tf| sys_pipe(tf) },
+    Syscall {
+        nr: NR_DUP2,
+        name: "dup2",
+        args: &[Arg::Int, Arg::Int],
+        handler: |a, tf| sys_dup2(a.u64(0), a.u64(1), tf),
+    },
+    Syscall {
+        nr: NR_TRACE,
+        name: "trace",
+        args: &[Arg::Int, Arg::Int],
+        handler: |a, tf| sys_trace(a.u64(0), a.u64(1) != 0, tf),
+    },
+    Syscall {
+        nr: NR_KILL,
+        name: "kill",
+        args: &[Arg::Int, Arg::Int],
+        handler: |a, tf| sys_kill(a.u64(0), a.u64(1), tf),
+    },
+    Syscall {
+        nr: NR_SIGACTION,
+        name: "sigaction",
+        args: &[Arg::Int, Arg::Hex, Arg::Hex],
+        handler: |a, tf| sys_sigaction(a.u64(0), a.usize(1), a.usize(2), tf),
+    },
+    Syscall { nr: NR_SIGRETURN, name: "sigreturn", args: &[], handler: |_, tf| sys_sigreturn(tf) },
+    Syscall {
+        nr: NR_THREAD_CREATE,
+        name: "thread_create",
+        args: &[Arg::Hex, Arg::Hex, Arg::Hex],
+        handler: |a, tf| sys_thread_create(a.u64(0), a.u64(1), a.u64(2), tf),
+    },
+    Syscall {
+        nr: NR_THREAD_EXIT,
+        name: "thread_exit",
+        args: &[Arg::Int],
+        handler: |a, tf| sys_thread_exit(a.u64(0), tf),
+    },
+    Syscall {
+        nr: NR_THREAD_JOIN,
+        name: "thread_join",
+        args: &[Arg::Int],
+        handler: |a, tf| sys_thread_join(a.u64(0), tf),
+    },
+    Syscall {
+        nr: NR_FUTEX_WAIT,
+        name: "futex_wait",
+        args: &[Arg::Hex, Arg::Int, Arg::Int],
+        handler: |a, tf| sys_futex_wait(a.usize(0), a.u32(1)?, a.u64(2), tf),
+    },
+    Syscall {
+        nr: NR_FUTEX_WAKE,
+        name: "futex_wake",
+        args: &[Arg::Hex, Arg::Int],
+        handler: |a, tf| sys_futex_wake(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_SETPRIORITY,
+        name: "setpriority",
+        args: &[Arg::Int, Arg::Signed],
+        handler: |a, tf| sys_setpriority(a.u64(0), a.i64(1), tf),
+    },
+    Syscall {
+        nr: NR_PROCINFO,
+        name: "procinfo",
+        args: &[Arg::Hex, Arg::Int],
+        handler: |a, tf| sys_procinfo(a.usize(0), a.usize(1), tf),
+    },
+    Syscall {
+        nr: NR_SCHED_SETATTR,
+        name: "sched_setattr",
+        args: &[Arg::Int, Arg::Hex],
+        handler: |a, tf| sys_sched_setattr(a.u64(0), a.usize(1), tf),
+    },
+];
+
+/// Returns the system call table entry for `nr`, if any.
+pub fn lookup(nr: usize) -> Option<&'static Syscall> {
+    SYSCALLS.iter().find(|s| s.nr == nr)
+}
+
+/// Formats a system call result for the trace log.
+struct TraceResult<'a>(&'a OsResult<Return>);
+
+impl fmt::Display for TraceResult<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Ok(Return::Unit) => write!(f, "0"),
+            Ok(Return::One(a)) => write!(f, "{:#x}", a),
+            Ok(Return::Two(a, b)) => write!(f, "({:#x}, {:#x})", a, b),
+            Ok(Return::Switched) => write!(f, "? <blocked>"),
+            Ok(Return::Restored) => write!(f, "? <restored>"),
+            Err(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+/// The number of bytes of a path or buffer the trace log shows.
+const TRACE_BYTES: usize = 32;
+
+/// Formats argument `i` of `args`, of kind `kind`, for the trace log. Paths
+/// and buffers are read from `space` and shown as escaped text, or as their
+/// address if they cannot be read.
+fn trace_arg(line: &mut String, kind: Arg, args: &Args, i: usize, space: Option<&Mutex<AddressSpace>>) -> fmt::Result {
+    match kind {
+        Arg::Int => write!(line, "{}", args.u64(i)),
+        Arg::Signed => write!(line, "{}", args.i64(i)),
+        Arg::Hex => write!(line, "{:#x}", args.u64(i)),
+        Arg::Bytes => {
+            let len = args.usize(i + 1);
+            let bytes = space.and_then(|space| {
+                let slice = UserSlice::new(args.usize(i), len).ok()?.truncate(TRACE_BYTES);
+                slice.to_vec(&mut space.lock().vmap).ok()
+            });
+            match bytes {
+                Some(bytes) => {
+                    write!(line, "{:?}", String::from_utf8_lossy(&bytes))?;
+                    if len > TRACE_BYTES {
+                        write!(line, "...")?;
+                    }
+                    Ok(())
+                }
+                None => write!(line, "{:#x}", args.u64(i)),
+            }
+        }
+    }
+}
+
+/// Logs one traced system call of process `pid`. The line is built first and
+/// printed at once, so that it is not interleaved with output of other cores.
+fn trace(pid: Id, num: u16, syscall: Option<&Syscall>, args: &Args, result: &OsResult<Return>, elapsed: Duration) {
+    let space = SCHEDULER.with_process(pid, |p| p.space.clone()).ok().flatten();
+    let mut line = String::new();
+    let _ = write!(line, "[strace {}] ", pid);
+    let _ = match syscall {
+        Some(syscall) => write!(line, "{}(", syscall.name),
+        None => write!(line, "syscall_{}(", num),
+    };
+    for (i, &kind) in syscall.map(|s| s.args).unwrap_or(&[]).iter().enumerate() {
+        if i > 0 {
+            line.push_str(", ");
+        }
+        let _ = trace_arg(&mut line, kind, args, i, space.as_deref());
+    }
+    kprintln!("{}) = {} <{}us>", line, TraceResult(result), elapsed.as_micros());
+}
+
+/// Performs the system call `num` for the process running in `tf` and stores
+/// its result in `tf`. Unknown system calls fail with `OsError::NoSyscall`.
+///
+/// If the calling process is traced, the call is logged to the console.
 pub fn handle_syscall(num: u16, tf: &mut TrapFrame) {
-    use crate::console::kprintln;
-    match num as usize {
-        NR_SLEEP => sys_sleep(tf.xregs[0] as u32, tf),
-        NR_EXIT => sys_exit(tf),
-        NR_TIME => sys_time(tf),
-        NR_GETPID => sys_getpid(tf),
-        NR_WRITE => sys_write(tf.xregs[0] as u8, tf),
-        _ => unimplemented!("syscall not yet implemented"),
+    let pid = tf.tpidr;
+    let traced = SCHEDULER.with_process(pid, |p| p.trace).unwrap_or(false);
+    let start = pi::timer::current_time();
+
+    let syscall = lookup(num as usize);
+    let args = Args::new(tf, syscall.map(|s| s.args.len()).unwrap_or(0));
+    let result = match syscall {
+        Some(syscall) => (syscall.handler)(&args, tf),
+        None => Err(OsError::NoSyscall),
+    };
+
+    if traced {
+        trace(pid, num, syscall, &args, &result, pi::timer::current_time() - start);
     }
+    set_result(tf, result);
 }
@@ -1,17 +1,31 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use crate::console::kprintln;
-use crate::mutex::Mutex;
+use crate::mutex::{Mutex, Rank};
+use crate::percore;
 
 use aarch64::*;
 
 mod address;
 mod pagetable;
+mod region;
+mod user;
 
 pub use self::address::{PhysicalAddr, VirtualAddr};
 pub use self::pagetable::*;
-use crate::param::{KERNEL_MASK_BITS, USER_MASK_BITS};
+pub use self::region::{Region, RegionKind, RegionList};
+pub use self::user::{UserPtr, UserSlice};
+use crate::param::{KERNEL_MASK_BITS, NCORES, USER_MASK_BITS};
 
 /// Thread-safe (locking) wrapper around a kernel page table.
-pub struct VMManager(Mutex<Option<KernPageTable>>);
+pub struct VMManager {
+    kern_pt: Mutex<Option<KernPageTable>>,
+    /// The base address of the kernel page table, readable without taking
+    /// the lock by cores whose MMU is still off.
+    kern_pt_addr: AtomicUsize,
+    /// The number of cores that finished `setup()`.
+    ready_core_cnt: AtomicUsize,
+}
 
 impl VMManager {
     /// Returns an uninitialized `VMManager`.
@@ -19,27 +33,33 @@ impl VMManager {
     /// The virtual memory manager must be initialized by calling `initialize()` and `setup()`
     /// before the first memory allocation. Failure to do will result in panics.
     pub const fn uninitialized() -> Self {
-        VMManager(Mutex::new(None))
+        VMManager {
+            kern_pt: Mutex::ranked(None, Rank::Vm),
+            kern_pt_addr: AtomicUsize::new(0),
+            ready_core_cnt: AtomicUsize::new(0),
+        }
     }
 
     /// Initializes the virtual memory manager.
     /// The caller should assure that the method is invoked only once during the kernel
     /// initialization.
     pub fn initialize(&self) {
-        *self.0.lock() = Some(KernPageTable::new());
+        let table = KernPageTable::new();
+        self.kern_pt_addr.store(table.get_baddr().as_usize(), Ordering::Release);
+        *self.kern_pt.lock() = Some(table);
         self.setup();
     }
 
-    /// Set up the virtual memory manager.
+    /// Set up the virtual memory manager for the current core.
     /// The caller should assure that `initialize()` has been called before calling this function.
     /// Sets proper configuration bits to MAIR_EL1, TCR_EL1, TTBR0_EL1, and TTBR1_EL1 registers.
+    /// Every core calls this once, after which its `Mutex`es synchronize with the other cores.
     ///
     /// # Panics
     ///
     /// Panics if the current system does not support 64KB memory translation granule size.
     pub fn setup(&self) {
-        // let kern_page_table = self.0.lock();
-        let baddr = self.get_baddr().as_u64(); // kern_page_table.as_ref().unwrap().get_baddr().as_u64();
+        let baddr = self.kern_pt_addr.load(Ordering::Acquire) as u64;
         unsafe {
             assert!(ID_AA64MMFR0_EL1.get_value(ID_AA64MMFR0_EL1::TGran64) == 0);
 
@@ -79,16 +99,25 @@ impl VMManager {
             SCTLR_EL1.set(SCTLR_EL1.get() | SCTLR_EL1::I | SCTLR_EL1::C | SCTLR_EL1::M);
             asm!("dsb sy");
             isb();
+
+            percore::set_mmu_ready();
+        }
+        self.ready_core_cnt.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Waits until every core has finished `setup()`.
+    pub fn wait(&self) {
+        while self.ready_core_cnt.load(Ordering::Acquire) != NCORES {
+            core::sync::atomic::spin_loop_hint();
         }
     }
 
     /// Returns the base address of the kernel page table as `PhysicalAddr`.
     pub fn get_baddr(&self) -> PhysicalAddr {
-        let kern_page_table = self.0.lock();
-        kern_page_table.as_ref().unwrap().get_baddr()
+        self.kern_pt_addr.load(Ordering::Acquire).into()
     }
 
     pub fn debug_table(&self) -> &Mutex<Option<KernPageTable>> {
-        &self.0
+        &self.kern_pt
     }
 }
@@ -1,17 +1,18 @@
 use core::iter::Chain;
+use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
+use core::ptr::{self, Unique};
 use core::slice::Iter;
 
-use alloc::boxed::Box;
 use alloc::fmt;
-use core::alloc::{GlobalAlloc, Layout};
 
 use crate::allocator;
 use crate::param::*;
 use crate::vm::{PhysicalAddr, VirtualAddr};
-use crate::ALLOCATOR;
+use crate::FRAMES;
 
 use aarch64::vmsa::*;
+use kernel_api::{OsError, OsResult};
 use shim::const_assert_size;
 use crate::{kprintln};
 
@@ -22,10 +23,6 @@ const_assert_size!(Page, PAGE_SIZE);
 impl Page {
     pub const SIZE: usize = PAGE_SIZE;
     pub const ALIGN: usize = PAGE_SIZE;
-
-    fn layout() -> Layout {
-        unsafe { Layout::from_size_align_unchecked(Self::SIZE, Self::ALIGN) }
-    }
 }
 
 #[repr(C)]
@@ -99,28 +96,63 @@ impl L3PageTable {
     }
 }
 
+/// The L3 tables of a `PageTable`, one for each 512MB the L2 table maps.
+pub trait L3Tables: AsRef<[L3PageTable]> + AsMut<[L3PageTable]> {
+    /// Returns empty L3 tables.
+    fn new() -> Self;
+}
+
+/// The L3 tables of a user page table, which maps the 1GB of the user
+/// address space.
+pub type UserL3Tables = [L3PageTable; 2];
+
+/// The L3 tables of the kernel page table, which maps 1GB of physical memory
+/// and the local peripherals right above it.
+pub type KernL3Tables = [L3PageTable; 3];
+
+impl L3Tables for UserL3Tables {
+    fn new() -> Self {
+        [L3PageTable::new(), L3PageTable::new()]
+    }
+}
+
+impl L3Tables for KernL3Tables {
+    fn new() -> Self {
+        [L3PageTable::new(), L3PageTable::new(), L3PageTable::new()]
+    }
+}
+
 #[repr(C)]
 #[repr(align(65536))]
-pub struct PageTable {
+pub struct PageTable<T: L3Tables> {
     pub l2: L2PageTable,
-    pub l3: [L3PageTable; 2],
+    pub l3: T,
 }
 
-impl PageTable {
-    /// Returns a new `Box` containing `PageTable`.
+impl<T: L3Tables> PageTable<T> {
+    /// The number of frames a `PageTable` takes.
+    const FRAMES: usize = size_of::<PageTable<T>>() / PAGE_SIZE;
+
+    /// Returns a new `PageTable` in frames of its own.
     /// Entries in L2PageTable should be initialized properly before return.
-    fn new(perm: u64) -> Box<PageTable> {
-        let mut table = Box::new(PageTable {
-            l2: L2PageTable::new(),
-            l3: [L3PageTable::new(), L3PageTable::new()],
-        });
-        kprintln!("l2 addr: {:0x}, l3 addresses: {:0x} {:0x}",
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frames could not be allocated.
+    fn new(perm: u64) -> TableFrames<T> {
+        let addr = FRAMES.alloc(Self::FRAMES, PAGE_SIZE).expect("no memory for a page table");
+        let mut table = unsafe {
+            ptr::write(addr as *mut PageTable<T>, PageTable { l2: L2PageTable::new(), l3: T::new() });
+            TableFrames(Unique::new(addr as *mut PageTable<T>).expect("non-null"))
+        };
+        kprintln!("l2 addr: {:0x}, {} l3 tables from: {:0x}",
             table.l2.as_ptr().as_usize(),
-            table.l3[0].as_ptr().as_usize(),
-            table.l3[1].as_ptr().as_usize());
+            table.l3.as_ref().len(),
+            table.l3.as_ref()[0].as_ptr().as_usize());
 
-        for (i, l3table) in table.l3.iter().enumerate() {
-            table.l2.entries[i]
+        let PageTable { l2, l3 } = &mut *table;
+        for (i, l3table) in l3.as_ref().iter().enumerate() {
+            l2.entries[i]
                 .set_bit(RawL2Entry::AF)
                 .set_value(EntryType::Table, RawL2Entry::TYPE)
                 .set_value(EntryValid::Valid, RawL2Entry::VALID)
@@ -131,14 +163,15 @@ impl PageTable {
     }
 
     /// Returns the (L2index, L3index) extracted from the given virtual address.
-    /// Since we are only supporting 1GB virtual memory in this system, L2index
-    /// should be smaller than 2.
+    /// Since we are only supporting 1GB virtual memory in this system, plus the
+    /// local peripherals right above it in the kernel, L2index should be
+    /// smaller than the number of L3 tables.
     ///
     /// # Panics
     ///
     /// Panics if the virtual address is not properly aligned to page size.
     /// Panics if extracted L2index exceeds the number of L3PageTable.
-    fn locate(va: VirtualAddr) -> (usize, usize) {
+    fn locate(&self, va: VirtualAddr) -> (usize, usize) {
         let mut va = va.as_usize();
         assert!((va & !PAGE_MASK) == 0, "badly aligned addr? {:x} {:0x} {:0x}", va, !PAGE_MASK, va & !PAGE_MASK);
         if va >= USER_IMG_BASE {
@@ -147,14 +180,14 @@ impl PageTable {
 
         let va = VirtualAddrBits::new(va as u64);
         let (l2idx, l3idx) = (va.get_value(VirtualAddrBits::L2), va.get_value(VirtualAddrBits::L3));
-        assert!(l2idx < 2, "L2index value too large {}", l2idx);
+        assert!((l2idx as usize) < self.l3.as_ref().len(), "L2index value too large {}", l2idx);
         (l2idx as usize, l3idx as usize)
         // unimplemented!("PageTable::localte()")
     }
 
     pub fn entry(&mut self, va: VirtualAddr) -> &mut L3Entry {
-        let (l2idx, l3idx) = PageTable::locate(va);
-        &mut self.l3[l2idx].entries[l3idx]
+        let (l2idx, l3idx) = self.locate(va);
+        &mut self.l3.as_mut()[l2idx].entries[l3idx]
     }
 
     /// Returns `true` if the L3entry indicated by the given virtual address is valid.
@@ -169,8 +202,8 @@ impl PageTable {
     /// Set the given RawL3Entry `entry` to the L3Entry indicated by the given virtual
     /// address.
     pub fn set_entry(&mut self, va: VirtualAddr, entry: RawL3Entry) -> &mut Self {
-        let (l2idx, l3idx) = PageTable::locate(va);
-        self.l3[l2idx].entries[l3idx].0 = entry;
+        let (l2idx, l3idx) = self.locate(va);
+        self.l3.as_mut()[l2idx].entries[l3idx].0 = entry;
         self
         // unimplemented!("PageTable::set_entry()")
     }
@@ -178,12 +211,12 @@ impl PageTable {
     /// Returns a base address of the pagetable. The returned `PhysicalAddr` value
     /// will point the start address of the L2PageTable.
     pub fn get_baddr(&self) -> PhysicalAddr {
-        assert!(self as *const PageTable as usize == self.l2.as_ptr().as_usize());
-        (self as *const PageTable).into()
+        assert!(self as *const PageTable<T> as usize == self.l2.as_ptr().as_usize());
+        (self as *const PageTable<T>).into()
     }
 }
 
-impl fmt::Debug for PageTable {
+impl<T: L3Tables> fmt::Debug for PageTable<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("L2: ")?;
         f.debug_list().entries(self.l2.entries[0..5].iter()).finish()?;
@@ -191,13 +224,13 @@ impl fmt::Debug for PageTable {
             f.write_str("\n")?;
         }
         f.write_str("First L3: ")?;
-        f.debug_list().entries(self.l3[0].entries[0..32].iter()).finish()?;
+        f.debug_list().entries(self.l3.as_ref()[0].entries[0..32].iter()).finish()?;
         if f.alternate() {
             f.write_str("\n")?;
         }
         f.write_str("Second L3: ")?;
-        f.debug_list().entries(self.l3[1].entries[0..16].iter()).finish()?;
-        f.debug_list().entries(self.l3[1].entries.iter().rev().take(8)).finish()?;
+        f.debug_list().entries(self.l3.as_ref()[1].entries[0..16].iter()).finish()?;
+        f.debug_list().entries(self.l3.as_ref()[1].entries.iter().rev().take(8)).finish()?;
         if f.alternate() {
             f.write_str("\n")?;
             f.write_str("================\n")?;
@@ -209,8 +242,39 @@ impl fmt::Debug for PageTable {
 
 // FIXME: Implement `IntoIterator` for `&PageTable`.
 
+/// A `PageTable` in frames taken from `FRAMES`, given back when it is
+/// dropped. Page tables are kept out of the kernel heap like the pages they
+/// map.
+pub struct TableFrames<T: L3Tables>(Unique<PageTable<T>>);
+
+impl<T: L3Tables> Deref for TableFrames<T> {
+    type Target = PageTable<T>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl<T: L3Tables> DerefMut for TableFrames<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl<T: L3Tables> Drop for TableFrames<T> {
+    fn drop(&mut self) {
+        FRAMES.release(self.0.as_ptr() as usize, PageTable::<T>::FRAMES);
+    }
+}
+
+impl<T: L3Tables> fmt::Debug for TableFrames<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
 #[derive(Debug)]
-pub struct KernPageTable(Box<PageTable>);
+pub struct KernPageTable(TableFrames<KernL3Tables>);
 
 impl KernPageTable {
     /// Returns a new `KernPageTable`. `KernPageTable` should have a `Pagetable`
@@ -223,7 +287,7 @@ impl KernPageTable {
     /// more details.
     pub fn new() -> KernPageTable {
         let perm = EntryPerm::KERN_RW;
-        let mut table = PageTable::new(perm);
+        let mut table = PageTable::<KernL3Tables>::new(perm);
 
         let (_, mut end) = allocator::memory_map().expect("failed to find memory map");
         end = allocator::util::align_down(end, PAGE_SIZE);
@@ -258,14 +322,56 @@ impl KernPageTable {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PagePerm {
     RW,
     RO,
+    RX,
     RWX,
 }
 
+impl PagePerm {
+    /// Returns the permission matching the ELF segment flags `flags`
+    /// (`PF_R`, `PF_W`, `PF_X`). Writable segments are always readable.
+    pub fn from_elf_flags(flags: u32) -> PagePerm {
+        use crate::process::elf::{PF_W, PF_X};
+
+        match (flags & PF_W != 0, flags & PF_X != 0) {
+            (true, true) => PagePerm::RWX,
+            (true, false) => PagePerm::RW,
+            (false, true) => PagePerm::RX,
+            (false, false) => PagePerm::RO,
+        }
+    }
+
+    /// Returns the permission matching the `mmap` protection bits `prot`
+    /// (`PROT_READ`, `PROT_WRITE`, `PROT_EXEC`), or `None` for `PROT_NONE`,
+    /// which cannot be expressed for a mapped page.
+    pub fn from_prot(prot: u64) -> Option<PagePerm> {
+        use kernel_api::{PROT_EXEC, PROT_READ, PROT_WRITE};
+
+        match (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0, prot & PROT_READ != 0) {
+            (true, true, _) => Some(PagePerm::RWX),
+            (true, false, _) => Some(PagePerm::RW),
+            (false, true, _) => Some(PagePerm::RX),
+            (false, false, true) => Some(PagePerm::RO),
+            (false, false, false) => None,
+        }
+    }
+
+    /// Returns the (AP, UXN) bits of an L3 entry for this permission.
+    fn bits(&self) -> (u64, u64) {
+        match self {
+            PagePerm::RW => (EntryPerm::USER_RW, 1),
+            PagePerm::RO => (EntryPerm::USER_RO, 1),
+            PagePerm::RX => (EntryPerm::USER_RO, 0),
+            PagePerm::RWX => (EntryPerm::USER_RW, 0),
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct UserPageTable(Box<PageTable>);
+pub struct UserPageTable(TableFrames<UserL3Tables>);
 
 impl UserPageTable {
     /// Returns a new `UserPageTable` containing a `PageTable` created with
@@ -275,44 +381,107 @@ impl UserPageTable {
         // unimplemented!("UserPageTable::new()")
     }
 
-    /// Allocates a page and set an L3 entry translates given virtual address to the
-    /// physical address of the allocated page. Returns the allocated page.
+    /// Allocates a page frame and set an L3 entry translates given virtual address to
+    /// the physical address of the allocated frame. Returns the allocated page.
+    ///
+    /// The page is zeroed and mapped with the access permissions of `perm`.
+    /// User pages are never executable from the kernel (`PXN`).
+    ///
+    /// # Errors
+    /// Returns `OsError::NoMemory` if `FRAMES` fails to allocate a frame.
     ///
     /// # Panics
     /// Panics if the virtual address is lower than `USER_IMG_BASE`.
     /// Panics if the virtual address has already been allocated.
-    /// Panics if allocator fails to allocate a page.
-    ///
-    /// TODO. use Result<T> and make it failurable
-    /// TODO. use perm properly
-    pub fn alloc(&mut self, va: VirtualAddr, _perm: PagePerm) -> &mut [u8] {
+    pub fn alloc(&mut self, va: VirtualAddr, perm: PagePerm) -> OsResult<&mut [u8]> {
         assert!(va.as_usize() >= USER_IMG_BASE, "addr from invalid range: {:0x}", va.as_usize());
 
         let l3entry = self.entry(va);
         assert!(!l3entry.is_valid(), "page already allocated?");
 
-        let ptr = unsafe { ALLOCATOR.alloc(Page::layout()) };
-        assert!(!ptr.is_null(), "failed allocation");
+        let ptr = FRAMES.alloc(1, Page::ALIGN).ok_or(OsError::NoMemory)? as *mut u8;
+        unsafe { ptr.write_bytes(0, Page::SIZE) };
 
+        let (ap, uxn) = perm.bits();
         let mut raw_entry = RawL3Entry::new(0);
         raw_entry
             .set_bit(RawL3Entry::AF)
+            .set_bit(RawL3Entry::PXN)
+            .set_value(uxn, RawL3Entry::UXN)
             .set_value(PageType::Page, RawL3Entry::TYPE)
             .set_value(EntryValid::Valid, RawL3Entry::VALID)
-            .set_value(EntryPerm::USER_RW, RawL3Entry::AP)
+            .set_value(ap, RawL3Entry::AP)
             .set_value(EntryAttr::Normal, RawL3Entry::ATTR)
             .set_value(EntrySh::Inner, RawL3Entry::SH)
             .set_masked(ptr as u64, RawL3Entry::ADDR);
         l3entry.0 = raw_entry;
 
         let page = unsafe { &mut *(ptr as *mut Page) };
-        page.0.as_mut()
+        Ok(page.0.as_mut())
         // unimplemented!("alloc()");
     }
+
+    /// Unmaps the page at the page-aligned virtual address `va` and drops its
+    /// reference to the frame. Does nothing if no page is mapped there.
+    ///
+    /// The TLB is invalidated by `context_restore` on the way back to user
+    /// space, so the caller does not have to flush it.
+    pub fn dealloc(&mut self, va: VirtualAddr) {
+        let l3entry = self.entry(va);
+        if let Some(addr) = l3entry.get_page_addr() {
+            l3entry.0 = RawL3Entry::new(0);
+            FRAMES.release(addr.as_usize(), 1);
+        }
+    }
+
+    /// Changes the permission of the page mapped at the page-aligned virtual
+    /// address `va` to `perm`. Returns `false` if no page is mapped there.
+    pub fn set_perm(&mut self, va: VirtualAddr, perm: PagePerm) -> bool {
+        let l3entry = self.entry(va);
+        if !l3entry.is_valid() {
+            return false;
+        }
+
+        let (ap, uxn) = perm.bits();
+        l3entry.0.set_value(ap, RawL3Entry::AP).set_value(uxn, RawL3Entry::UXN);
+        true
+    }
+
+    /// Translates the user virtual address `va` to the physical address it is
+    /// mapped to. Returns `None` if `va` is outside of the user address space
+    /// or unmapped, or if `write` is set and the page is read-only.
+    pub fn translate(&mut self, va: VirtualAddr, write: bool) -> Option<PhysicalAddr> {
+        let addr = va.as_usize();
+        if addr < USER_IMG_BASE {
+            return None;
+        }
+
+        let l3entry = self.entry((addr & PAGE_MASK).into());
+        let page = l3entry.get_page_addr()?;
+        match l3entry.0.get_value(RawL3Entry::AP) {
+            EntryPerm::USER_RW => (),
+            EntryPerm::USER_RO if !write => (),
+            _ => return None,
+        }
+        Some(page + (addr & !PAGE_MASK).into())
+    }
+
+    /// Returns the page mapped at the page-aligned virtual address `va`, or
+    /// `None` if no page is mapped there.
+    pub fn get_page(&mut self, va: VirtualAddr) -> Option<&mut [u8]> {
+        let addr = self.entry(va).get_page_addr()?;
+        let page = unsafe { &mut *(addr.as_usize() as *mut Page) };
+        Some(page.0.as_mut())
+    }
+
+    /// Returns the number of pages mapped.
+    pub fn pages(&self) -> usize {
+        self.l3.iter().map(|table| table.entries.iter().filter(|entry| entry.is_valid()).count()).sum()
+    }
 }
 
 impl Deref for KernPageTable {
-    type Target = PageTable;
+    type Target = PageTable<KernL3Tables>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -320,7 +489,7 @@ impl Deref for KernPageTable {
 }
 
 impl Deref for UserPageTable {
-    type Target = PageTable;
+    type Target = PageTable<UserL3Tables>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -345,9 +514,7 @@ impl Drop for UserPageTable {
         for table in self.l3.iter() {
             for entry in table.entries.iter() {
                 if let Some(addr) = entry.get_page_addr() {
-                    unsafe {
-                        ALLOCATOR.dealloc(addr.as_ptr() as *mut u8, Page::layout());
-                    }
+                    FRAMES.release(addr.as_usize(), 1);
                 }
             }
         }
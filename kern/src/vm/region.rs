@@ -0,0 +1,278 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::param::PAGE_SIZE;
+use crate::vm::PagePerm;
+use kernel_api::{OsError, OsResult};
+
+/// What a mapped region was created from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegionKind {
+    /// Zero-filled memory.
+    Anonymous,
+    /// A private copy of part of a file.
+    File,
+}
+
+/// A contiguous, page-aligned range of user virtual memory created by `mmap`.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Region {
+    pub start: usize,
+    pub len: usize,
+    pub perm: PagePerm,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// Returns the first address past the end of the region.
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Returns an iterator over the page addresses of the region.
+    pub fn pages(&self) -> impl Iterator<Item = usize> {
+        (self.start..self.end()).step_by(PAGE_SIZE)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+impl fmt::Debug for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}-{:#x} {:?} {:?}", self.start, self.end(), self.perm, self.kind)
+    }
+}
+
+/// The sorted, non-overlapping list of regions mapped in the window
+/// `[base, limit)` of a process's address space.
+///
+/// The list only does bookkeeping; mapping the pages of a region in the
+/// `UserPageTable` is up to the caller.
+#[derive(Debug)]
+pub struct RegionList {
+    base: usize,
+    limit: usize,
+    regions: Vec<Region>,
+}
+
+impl RegionList {
+    /// Returns an empty region list managing `[base, limit)`.
+    pub fn new(base: usize, limit: usize) -> RegionList {
+        RegionList {
+            base,
+            limit,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Returns an iterator over the regions, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    /// Returns `true` if `[start, start + len)` lies inside the managed window.
+    pub fn contains(&self, start: usize, len: usize) -> bool {
+        start >= self.base && start <= self.limit && len <= self.limit - start
+    }
+
+    /// Returns `true` if no region overlaps `[start, start + len)`.
+    fn is_free(&self, start: usize, len: usize) -> bool {
+        self.contains(start, len) && !self.regions.iter().any(|r| r.overlaps(start, start + len))
+    }
+
+    /// Finds a free, page-aligned range of `len` bytes. `hint`, if it is
+    /// non-zero and free, is preferred; otherwise the lowest free range is
+    /// returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::NoVmSpace` if the window has no gap of `len` bytes.
+    pub fn find_free(&self, hint: usize, len: usize) -> OsResult<usize> {
+        if hint != 0 && self.is_free(hint, len) {
+            return Ok(hint);
+        }
+
+        let mut start = self.base;
+        for region in self.regions.iter() {
+            if region.start - start >= len {
+                return Ok(start);
+            }
+            start = region.end();
+        }
+
+        if self.limit - start >= len {
+            Ok(start)
+        } else {
+            Err(OsError::NoVmSpace)
+        }
+    }
+
+    /// Records `region`, which must not overlap any existing region.
+    pub fn insert(&mut self, region: Region) {
+        assert!(self.is_free(region.start, region.len), "overlapping region {:?}", region);
+        let pos = self.regions.iter().position(|r| r.start > region.start).unwrap_or(self.regions.len());
+        self.regions.insert(pos, region);
+    }
+
+    /// Splits regions so that `start` and `end` fall on region boundaries.
+    fn split_at(&mut self, start: usize, end: usize) {
+        let mut i = 0;
+        while i < self.regions.len() {
+            let region = self.regions[i];
+            for &at in [start, end].iter() {
+                if region.start < at && at < region.end() {
+                    self.regions[i].len = at - region.start;
+                    self.regions.insert(i + 1, Region { start: at, len: region.end() - at, ..region });
+                    break;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Removes the part of every region that overlaps `[start, start + len)`
+    /// and returns the removed pieces.
+    pub fn remove(&mut self, start: usize, len: usize) -> Vec<Region> {
+        let end = start + len;
+        self.split_at(start, end);
+
+        let mut removed = Vec::new();
+        let mut i = 0;
+        while i < self.regions.len() {
+            if self.regions[i].overlaps(start, end) {
+                removed.push(self.regions.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+
+    /// Changes the permission of `[start, start + len)` to `perm` and returns
+    /// the affected regions.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if part of the range is not mapped.
+    pub fn protect(&mut self, start: usize, len: usize, perm: PagePerm) -> OsResult<Vec<Region>> {
+        let end = start + len;
+        let mapped: usize = self
+            .regions
+            .iter()
+            .filter(|r| r.overlaps(start, end))
+            .map(|r| core::cmp::min(r.end(), end) - core::cmp::max(r.start, start))
+            .sum();
+        if mapped != len {
+            return Err(OsError::BadAddress);
+        }
+
+        self.split_at(start, end);
+        let mut changed = Vec::new();
+        for region in self.regions.iter_mut().filter(|r| r.overlaps(start, end)) {
+            region.perm = perm;
+            changed.push(*region);
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x10_0000;
+    const LIMIT: usize = BASE + 16 * PAGE_SIZE;
+
+    fn region(page: usize, pages: usize, perm: PagePerm) -> Region {
+        Region {
+            start: BASE + page * PAGE_SIZE,
+            len: pages * PAGE_SIZE,
+            perm,
+            kind: RegionKind::Anonymous,
+        }
+    }
+
+    fn spans(list: &RegionList) -> Vec<(usize, usize, PagePerm)> {
+        list.iter()
+            .map(|r| ((r.start - BASE) / PAGE_SIZE, r.len / PAGE_SIZE, r.perm))
+            .collect()
+    }
+
+    #[test]
+    fn remove_partial_overlap() {
+        let mut list = RegionList::new(BASE, LIMIT);
+        list.insert(region(0, 4, PagePerm::RW));
+        list.insert(region(6, 4, PagePerm::RO));
+
+        // Covers the tail of the first region, the gap and the head of the
+        // second one.
+        let removed = list.remove(BASE + 2 * PAGE_SIZE, 6 * PAGE_SIZE);
+        assert_eq!(removed, vec![region(2, 2, PagePerm::RW), region(6, 2, PagePerm::RO)]);
+        assert_eq!(spans(&list), vec![(0, 2, PagePerm::RW), (8, 2, PagePerm::RO)]);
+
+        // Nothing left to remove in the same range.
+        assert!(list.remove(BASE + 2 * PAGE_SIZE, 6 * PAGE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn protect_splits_in_three() {
+        let mut list = RegionList::new(BASE, LIMIT);
+        list.insert(region(0, 6, PagePerm::RW));
+
+        let changed = list.protect(BASE + 2 * PAGE_SIZE, 2 * PAGE_SIZE, PagePerm::RO).unwrap();
+        assert_eq!(changed, vec![region(2, 2, PagePerm::RO)]);
+        assert_eq!(
+            spans(&list),
+            vec![(0, 2, PagePerm::RW), (2, 2, PagePerm::RO), (4, 2, PagePerm::RW)]
+        );
+    }
+
+    #[test]
+    fn protect_unmapped_range() {
+        let mut list = RegionList::new(BASE, LIMIT);
+        list.insert(region(0, 2, PagePerm::RW));
+        list.insert(region(3, 2, PagePerm::RW));
+
+        // The hole at page 2 makes the whole call fail without changes.
+        let err = list.protect(BASE, 5 * PAGE_SIZE, PagePerm::RO).err();
+        assert_eq!(err, Some(OsError::BadAddress));
+        assert_eq!(spans(&list), vec![(0, 2, PagePerm::RW), (3, 2, PagePerm::RW)]);
+    }
+
+    #[test]
+    fn find_free_with_hint() {
+        let mut list = RegionList::new(BASE, LIMIT);
+        list.insert(region(0, 2, PagePerm::RW));
+        list.insert(region(4, 2, PagePerm::RW));
+
+        // A free hint is taken as is.
+        let hint = BASE + 8 * PAGE_SIZE;
+        assert_eq!(list.find_free(hint, 2 * PAGE_SIZE), Ok(hint));
+
+        // A hint overlapping a region falls back to the lowest gap.
+        let hint = BASE + 5 * PAGE_SIZE;
+        assert_eq!(list.find_free(hint, 2 * PAGE_SIZE), Ok(BASE + 2 * PAGE_SIZE));
+
+        // So does a hint outside of the window.
+        assert_eq!(list.find_free(LIMIT, PAGE_SIZE), Ok(BASE + 2 * PAGE_SIZE));
+
+        // The gap at pages 2-3 is too small for three pages.
+        assert_eq!(list.find_free(0, 3 * PAGE_SIZE), Ok(BASE + 6 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn find_free_full_window() {
+        let mut list = RegionList::new(BASE, LIMIT);
+        list.insert(region(0, 8, PagePerm::RW));
+        list.insert(region(9, 7, PagePerm::RW));
+
+        assert_eq!(list.find_free(0, PAGE_SIZE), Ok(BASE + 8 * PAGE_SIZE));
+        assert_eq!(list.find_free(0, 2 * PAGE_SIZE), Err(OsError::NoVmSpace));
+
+        list.insert(region(8, 1, PagePerm::RW));
+        assert_eq!(list.find_free(0, PAGE_SIZE), Err(OsError::NoVmSpace));
+        assert_eq!(list.find_free(BASE, PAGE_SIZE), Err(OsError::NoVmSpace));
+    }
+}
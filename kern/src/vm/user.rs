@@ -0,0 +1,164 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::{cmp, ptr, slice};
+
+use crate::param::{PAGE_SIZE, USER_IMG_BASE, USER_MAX_VM_SIZE};
+use crate::vm::UserPageTable;
+use kernel_api::{OsError, OsResult};
+
+/// A range of user virtual memory passed to a system call.
+///
+/// Creating a `UserSlice` only checks that the range lies inside the user
+/// address space. Every copy translates the range page by page through the
+/// caller's `UserPageTable`, so a buffer that is unmapped or lacks the needed
+/// permission is reported as `OsError::BadAddress` instead of faulting in the
+/// kernel.
+#[derive(Debug, Copy, Clone)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    /// Returns a `UserSlice` over `[addr, addr + len)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if the range is not inside the user
+    /// address space.
+    pub fn new(addr: usize, len: usize) -> OsResult<UserSlice> {
+        if addr < USER_IMG_BASE || len > USER_MAX_VM_SIZE - (addr - USER_IMG_BASE) {
+            return Err(OsError::BadAddress);
+        }
+        Ok(UserSlice { addr, len })
+    }
+
+    /// Returns the user address of the first byte.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Returns the length of the range in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the first `len` bytes of this range (or all of them, if the
+    /// range is shorter).
+    pub fn truncate(&self, len: usize) -> UserSlice {
+        UserSlice { addr: self.addr, len: cmp::min(self.len, len) }
+    }
+
+    /// Calls `f` with the kernel-accessible address and length of every
+    /// page-sized chunk of the range, in order.
+    fn for_each_chunk<F>(&self, vmap: &mut UserPageTable, write: bool, mut f: F) -> OsResult<()>
+    where
+        F: FnMut(usize, usize, usize),
+    {
+        let mut done = 0;
+        while done < self.len {
+            let va = self.addr + done;
+            let chunk = cmp::min(self.len - done, PAGE_SIZE - va % PAGE_SIZE);
+            let pa = vmap.translate(va.into(), write).ok_or(OsError::BadAddress)?;
+            f(pa.as_usize(), done, chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies the range into `buf`, which must be exactly as long as the
+    /// range.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if part of the range is not readable by
+    /// the user. `buf` may then be partially filled.
+    pub fn copy_in(&self, vmap: &mut UserPageTable, buf: &mut [u8]) -> OsResult<()> {
+        assert_eq!(buf.len(), self.len);
+        self.for_each_chunk(vmap, false, |pa, offset, len| unsafe {
+            ptr::copy_nonoverlapping(pa as *const u8, buf[offset..].as_mut_ptr(), len);
+        })
+    }
+
+    /// Copies `data`, which must be exactly as long as the range, into the
+    /// range.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if part of the range is not writable by
+    /// the user. The range may then be partially written.
+    pub fn copy_out(&self, vmap: &mut UserPageTable, data: &[u8]) -> OsResult<()> {
+        assert_eq!(data.len(), self.len);
+        self.for_each_chunk(vmap, true, |pa, offset, len| unsafe {
+            ptr::copy_nonoverlapping(data[offset..].as_ptr(), pa as *mut u8, len);
+        })
+    }
+
+    /// Copies the range into a newly allocated vector.
+    pub fn to_vec(&self, vmap: &mut UserPageTable) -> OsResult<Vec<u8>> {
+        let mut buf = vec![0u8; self.len];
+        self.copy_in(vmap, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Copies the range into a newly allocated string.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::InvalidArgument` if the bytes are not valid UTF-8.
+    pub fn to_string(&self, vmap: &mut UserPageTable) -> OsResult<String> {
+        String::from_utf8(self.to_vec(vmap)?).map_err(|_| OsError::InvalidArgument)
+    }
+}
+
+/// A pointer to a `T` in user memory passed to a system call.
+///
+/// Like `UserSlice`, reads and writes go through the caller's page table and
+/// fail with `OsError::BadAddress` instead of faulting. `T` must be plain data
+/// that is valid for any bit pattern, such as integers or `#[repr(C)]`
+/// structs of integers.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    slice: UserSlice,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Returns a `UserPtr` to the `T` at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OsError::BadAddress` if `addr` is not suitably aligned for a
+    /// `T` or if the `T` would not lie inside the user address space.
+    pub fn new(addr: usize) -> OsResult<UserPtr<T>> {
+        if addr % align_of::<T>() != 0 {
+            return Err(OsError::BadAddress);
+        }
+        Ok(UserPtr {
+            slice: UserSlice::new(addr, size_of::<T>())?,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the user address the pointer points to.
+    pub fn addr(&self) -> usize {
+        self.slice.addr()
+    }
+
+    /// Reads the `T` from user memory.
+    pub fn read(&self, vmap: &mut UserPageTable) -> OsResult<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.slice.copy_in(vmap, bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Writes `value` to user memory.
+    pub fn write(&self, vmap: &mut UserPageTable, value: T) -> OsResult<()> {
+        let bytes = unsafe { slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        self.slice.copy_out(vmap, bytes)
+    }
+}
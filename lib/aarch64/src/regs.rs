@@ -225,3 +225,13 @@ defreg!(CNTHCTL_EL2, [
 ]);
 
 defreg!(CNTVOFF_EL2);
+
+// Generic timer (ref: D7.5)
+defreg!(CNTFRQ_EL0);
+defreg!(CNTPCT_EL0);
+defreg!(CNTP_CTL_EL0, [
+    ISTATUS [2-2],
+    IMASK   [1-1],
+    ENABLE  [0-0],
+]);
+defreg!(CNTP_TVAL_EL0);
@@ -64,6 +64,8 @@ defbit!(RawL2Entry, [
 ]);
 
 defbit!(RawL3Entry, [
+    UXN   [54-54],
+    PXN   [53-53],
     ADDR  [47-16],
 
     AF    [10-10],
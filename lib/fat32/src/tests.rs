@@ -24,6 +24,8 @@ impl Debug for StdVFatHandle {
 }
 
 impl VFatHandle for StdVFatHandle {
+    type Sector = Vec<u8>;
+
     fn new(val: VFat<StdVFatHandle>) -> Self {
         StdVFatHandle(Arc::new(Mutex::new(val)))
     }
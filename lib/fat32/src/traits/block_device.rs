@@ -1,7 +1,10 @@
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::fmt::Debug;
 use shim::io;
 
+use crate::util::VecExt;
+
 /// Trait implemented by devices that can be read/written in sector
 /// granularities.
 pub trait BlockDevice: Send {
@@ -95,3 +98,24 @@ impl_for_read_write_seek!(shim::io::Cursor<Vec<u8>>);
 impl_for_read_write_seek!(shim::io::Cursor<Box<[u8]>>);
 #[cfg(test)]
 impl_for_read_write_seek!(::std::fs::File);
+
+/// A buffer holding one cached sector of a partition.
+///
+/// `Vec<u8>` is the default; the user of a `VFat` can choose another buffer,
+/// say one taken from a cache of sector-sized objects, with
+/// `VFatHandle::Sector`.
+pub trait SectorBuf: AsRef<[u8]> + AsMut<[u8]> + Debug + Send + Sized {
+    /// Returns a zeroed buffer of `size` bytes, or `None` if memory is
+    /// exhausted.
+    fn new(size: usize) -> Option<Self>;
+}
+
+impl SectorBuf for Vec<u8> {
+    fn new(size: usize) -> Option<Vec<u8>> {
+        // force the buf to be at least 4-byte aligned so our SD card reader doesn't suffer
+        let words: Vec<u32> = vec![0; (size + 3) / 4];
+        let mut buf: Vec<u8> = unsafe { words.cast() };
+        buf.truncate(size);
+        Some(buf)
+    }
+}
@@ -3,7 +3,7 @@ mod dummy;
 mod fs;
 mod metadata;
 
-pub use self::block_device::BlockDevice;
+pub use self::block_device::{BlockDevice, SectorBuf};
 pub use self::dummy::Dummy;
 pub use self::fs::{Dir, Entry, File, FileSystem};
 pub use self::metadata::{Metadata, Timestamp};
@@ -5,12 +5,11 @@ use hashbrown::hash_map::Entry;
 use hashbrown::HashMap;
 use shim::io;
 
-use crate::util::VecExt;
-use crate::traits::BlockDevice;
+use crate::traits::{BlockDevice, SectorBuf};
 
 #[derive(Debug)]
-struct CacheEntry {
-    data: Vec<u8>,
+struct CacheEntry<S> {
+    data: S,
     dirty: bool,
 }
 
@@ -24,13 +23,13 @@ pub struct Partition {
     pub sector_size: u64,
 }
 
-pub struct CachedPartition {
+pub struct CachedPartition<S = Vec<u8>> {
     device: Box<dyn BlockDevice>,
-    cache: HashMap<u64, CacheEntry>,
+    cache: HashMap<u64, CacheEntry<S>>,
     partition: Partition,
 }
 
-impl CachedPartition {
+impl<S: SectorBuf> CachedPartition<S> {
     /// Creates a new `CachedPartition` that transparently caches sectors from
     /// `device` and maps physical sectors to logical sectors inside of
     /// `partition`. All reads and writes from `CacheDevice` are performed on
@@ -42,12 +41,12 @@ impl CachedPartition {
     /// sector number `[0, num_sectors)` are accessible.
     ///
     /// `partition.sector_size` must be an integer multiple of
-    /// `device.sector_size()`.
+    /// `device.sector_size()`. Cached sectors are kept in buffers of type `S`.
     ///
     /// # Panics
     ///
     /// Panics if the partition's sector size is < the device's sector size.
-    pub fn new<T>(device: T, partition: Partition) -> CachedPartition
+    pub fn new<T>(device: T, partition: Partition) -> CachedPartition<S>
     where
         T: BlockDevice + 'static,
     {
@@ -80,24 +79,23 @@ impl CachedPartition {
         Some(physical_sector)
     }
 
-    fn cache_entry(&mut self, sector: u64) -> io::Result<&mut CacheEntry> {
+    fn cache_entry(&mut self, sector: u64) -> io::Result<&mut CacheEntry<S>> {
         let start = self.virtual_to_physical(sector);
         if start.is_none() {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid logical sector"));
         }
-        let factor = self.factor();
+        let device_sector_size = self.device.sector_size() as usize;
 
         match self.cache.entry(sector) {
             Entry::Occupied(o) => Ok(o.into_mut()),
             Entry::Vacant(v) => {
-                // force the buf to be at least 4-byte aligned so our SD card reader doesn't suffer
-                let vec_aligned: Vec<u32> = Vec::with_capacity(128);
-                let mut vec: Vec<u8> = unsafe { vec_aligned.cast() };
-                for i in 0..factor {
-                    self.device.read_all_sector(start.unwrap() + i, &mut vec)?;
+                let mut data = S::new(self.partition.sector_size as usize)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "out of memory for a cached sector"))?;
+                for (i, chunk) in data.as_mut().chunks_mut(device_sector_size).enumerate() {
+                    self.device.read_sector(start.unwrap() + i as u64, chunk)?;
                 }
                 Ok(v.insert(CacheEntry {
-                    data: vec,
+                    data,
                     dirty: false,
                 }))
             }
@@ -148,7 +146,7 @@ impl CachedPartition {
 
 // FIXME: Implement `BlockDevice` for `CacheDevice`. The `read_sector` and
 // `write_sector` methods should only read/write from/to cached sectors.
-impl BlockDevice for CachedPartition {
+impl<S: SectorBuf> BlockDevice for CachedPartition<S> {
     fn sector_size(&self) -> u64 {
         self.partition.sector_size
     }
@@ -168,7 +166,7 @@ impl BlockDevice for CachedPartition {
     }
 }
 
-impl fmt::Debug for CachedPartition {
+impl<S: SectorBuf> fmt::Debug for CachedPartition<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CachedPartition")
             .field("device", &"<block device>")
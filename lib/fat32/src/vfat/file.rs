@@ -5,7 +5,7 @@ use shim::io::{self, SeekFrom};
 use crate::traits;
 use crate::vfat::{Cluster, Metadata, VFat, VFatHandle};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File<HANDLE: VFatHandle> {
     pub vfat: HANDLE,
     // FIXME: Fill me in.
@@ -122,11 +122,25 @@ impl<HANDLE: VFatHandle> io::Seek for File<HANDLE> {
             offset += add as u64;
         }
 
-        if offset >= self.file_size {
-            Err(io::Error::new(io::ErrorKind::InvalidInput, "seek past EOF"))
-        } else {
-            self.offset = offset;
-            Ok(self.offset)
+        if offset > self.file_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek past EOF"));
         }
+
+        // `read` continues from `current_cluster`, so walk the chain to the
+        // cluster holding the new offset.
+        let handle = self.vfat.clone();
+        let start_cluster = self.start_cluster;
+        self.current_cluster = handle.lock(|vfat: &mut VFat<HANDLE>| -> io::Result<Cluster> {
+            let mut cluster = start_cluster;
+            for _ in 0..offset / vfat.cluster_size() {
+                match vfat.next_cluster(cluster)? {
+                    Some(next) => cluster = next,
+                    None => break,
+                }
+            }
+            Ok(cluster)
+        })?;
+        self.offset = offset;
+        Ok(self.offset)
     }
 }
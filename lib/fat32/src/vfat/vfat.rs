@@ -12,7 +12,7 @@ use shim::path::{Component, Path};
 use Component::*;
 
 use crate::mbr::MasterBootRecord;
-use crate::traits::{BlockDevice, FileSystem};
+use crate::traits::{BlockDevice, FileSystem, SectorBuf};
 use crate::traits::{Dir as DirTrait, Entry as EntryTrait};
 use crate::util::SliceExt;
 use crate::vfat::{BiosParameterBlock, CachedPartition, Partition};
@@ -20,6 +20,9 @@ use crate::vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Status};
 
 /// A generic trait that handles a critical section as a closure
 pub trait VFatHandle: Clone + Debug + Send + Sync {
+    /// The buffer cached sectors are kept in.
+    type Sector: SectorBuf;
+
     fn new(val: VFat<Self>) -> Self;
     fn lock<R>(&self, f: impl FnOnce(&mut VFat<Self>) -> R) -> R;
 }
@@ -27,7 +30,7 @@ pub trait VFatHandle: Clone + Debug + Send + Sync {
 #[derive(Debug)]
 pub struct VFat<HANDLE: VFatHandle> {
     phantom: PhantomData<HANDLE>,
-    device: CachedPartition,
+    device: CachedPartition<HANDLE::Sector>,
     bytes_per_sector: u16,
     sectors_per_cluster: u8,
     sectors_per_fat: u32,
@@ -0,0 +1,142 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::syscall::sbrk;
+
+/// The number of size classes. Bin `k` serves blocks of `2^(k + 3)` bytes.
+const NUM_BINS: usize = 32;
+
+/// The minimum number of bytes requested from the kernel at once.
+const GROW_SIZE: usize = 64 * 1024;
+
+fn bin_size(bin: usize) -> usize {
+    1 << (bin + 3)
+}
+
+/// Returns the bin serving blocks large enough for `layout`.
+fn bin_for(layout: &Layout) -> usize {
+    let size = core::cmp::max(layout.size(), layout.align());
+    let mut bin = 0;
+    while bin_size(bin) < size {
+        bin += 1;
+    }
+    bin
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// A size-class heap backed by memory obtained from `sbrk`.
+///
+/// Freed blocks are kept on an intrusive free list per size class and are
+/// never returned to the kernel. Because every block is a power of two that is
+/// at least `layout.align()` bytes large, aligning the start of a fresh block
+/// to its size satisfies any alignment.
+struct Heap {
+    bins: [*mut usize; NUM_BINS],
+    current: usize,
+    end: usize,
+}
+
+impl Heap {
+    const fn new() -> Heap {
+        Heap {
+            bins: [ptr::null_mut(); NUM_BINS],
+            current: 0,
+            end: 0,
+        }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let bin = bin_for(&layout);
+        if bin >= NUM_BINS {
+            return ptr::null_mut();
+        }
+
+        let head = self.bins[bin];
+        if !head.is_null() {
+            self.bins[bin] = *head as *mut usize;
+            return head as *mut u8;
+        }
+
+        let size = bin_size(bin);
+        if self.current == 0 {
+            match sbrk(0) {
+                Ok(brk) => {
+                    self.current = brk;
+                    self.end = brk;
+                }
+                Err(_) => return ptr::null_mut(),
+            }
+        }
+
+        let start = align_up(self.current, size);
+        if start + size > self.end {
+            let grow = align_up(core::cmp::max(start + size - self.end, GROW_SIZE), GROW_SIZE);
+            if sbrk(grow as isize).is_err() {
+                return ptr::null_mut();
+            }
+            self.end += grow;
+        }
+
+        self.current = start + size;
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let bin = bin_for(&layout);
+        let block = ptr as *mut usize;
+        *block = self.bins[bin] as usize;
+        self.bins[bin] = block;
+    }
+}
+
+/// A `GlobalAlloc` for user programs built on the `brk` system call.
+///
+/// The heap is protected by a spin lock so that it may be shared by several
+/// threads of one process.
+pub struct Allocator {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap>,
+}
+
+unsafe impl Sync for Allocator {}
+
+impl Allocator {
+    /// Returns a new allocator with an empty heap. Memory is requested from
+    /// the kernel on the first allocation.
+    pub const fn new() -> Allocator {
+        Allocator {
+            locked: AtomicBool::new(false),
+            heap: UnsafeCell::new(Heap::new()),
+        }
+    }
+
+    fn with_heap<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {}
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_heap(|heap| heap.dealloc(ptr, layout))
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+#[alloc_error_handler]
+fn oom(_layout: Layout) -> ! {
+    crate::syscall::exit(1)
+}
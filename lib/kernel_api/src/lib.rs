@@ -1,4 +1,6 @@
 #![feature(asm)]
+#![cfg_attr(feature = "user-space", feature(naked_functions))]
+#![cfg_attr(feature = "user-heap", feature(alloc_error_handler))]
 #![no_std]
 
 use core::fmt;
@@ -8,6 +10,12 @@ use shim::io;
 #[cfg(feature = "user-space")]
 pub mod syscall;
 
+#[cfg(feature = "user-space")]
+pub mod sync;
+
+#[cfg(feature = "user-heap")]
+pub mod allocator;
+
 pub type OsResult<T> = core::result::Result<T, OsError>;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -22,6 +30,9 @@ pub enum OsError {
     BadAddress = 50,
     FileExists = 60,
     InvalidArgument = 70,
+    NoSyscall = 80,
+    Interrupted = 90,
+    WouldBlock = 100,
 
     IoError = 101,
     IoErrorEof = 102,
@@ -29,6 +40,8 @@ pub enum OsError {
     IoErrorInvalidInput = 104,
     IoErrorTimedOut = 105,
 
+    Busy = 110,
+
     InvalidSocket = 200,
     SocketAlreadyOpen = 201,
     InvalidPort = 202,
@@ -46,12 +59,17 @@ impl core::convert::From<u64> for OsError {
             50 => OsError::BadAddress,
             60 => OsError::FileExists,
             70 => OsError::InvalidArgument,
+            80 => OsError::NoSyscall,
+            90 => OsError::Interrupted,
+            100 => OsError::WouldBlock,
 
             101 => OsError::IoError,
             102 => OsError::IoErrorEof,
             103 => OsError::IoErrorInvalidData,
             104 => OsError::IoErrorInvalidInput,
 
+            110 => OsError::Busy,
+
             200 => OsError::InvalidSocket,
             201 => OsError::SocketAlreadyOpen,
             202 => OsError::InvalidPort,
@@ -61,6 +79,23 @@ impl core::convert::From<u64> for OsError {
     }
 }
 
+impl core::convert::From<OsError> for io::Error {
+    fn from(e: OsError) -> Self {
+        io::Error::from(match e {
+            OsError::NoEntry => io::ErrorKind::NotFound,
+            OsError::NoAccess => io::ErrorKind::PermissionDenied,
+            OsError::FileExists => io::ErrorKind::AlreadyExists,
+            OsError::InvalidArgument | OsError::IoErrorInvalidInput => io::ErrorKind::InvalidInput,
+            OsError::IoErrorEof => io::ErrorKind::UnexpectedEof,
+            OsError::IoErrorInvalidData => io::ErrorKind::InvalidData,
+            OsError::IoErrorTimedOut => io::ErrorKind::TimedOut,
+            OsError::Interrupted => io::ErrorKind::Interrupted,
+            OsError::WouldBlock => io::ErrorKind::WouldBlock,
+            _ => io::ErrorKind::Other,
+        })
+    }
+}
+
 impl core::convert::From<io::Error> for OsError {
     fn from(e: io::Error) -> Self {
         match e.kind() {
@@ -69,6 +104,8 @@ impl core::convert::From<io::Error> for OsError {
             io::ErrorKind::InvalidInput => OsError::IoErrorInvalidInput,
             io::ErrorKind::TimedOut => OsError::IoErrorTimedOut,
             io::ErrorKind::NotFound => OsError::NoEntry,
+            io::ErrorKind::Interrupted => OsError::Interrupted,
+            io::ErrorKind::WouldBlock => OsError::WouldBlock,
             _ => OsError::IoError,
         }
     }
@@ -79,3 +116,246 @@ pub const NR_TIME: usize = 2;
 pub const NR_EXIT: usize = 3;
 pub const NR_WRITE: usize = 4;
 pub const NR_GETPID: usize = 5;
+pub const NR_WAIT: usize = 6;
+pub const NR_BRK: usize = 7;
+pub const NR_MMAP: usize = 8;
+pub const NR_MUNMAP: usize = 9;
+pub const NR_MPROTECT: usize = 10;
+pub const NR_SPAWN: usize = 11;
+pub const NR_OPEN: usize = 12;
+pub const NR_READ: usize = 13;
+pub const NR_CLOSE: usize = 14;
+pub const NR_SEEK: usize = 15;
+pub const NR_FSTAT: usize = 16;
+pub const NR_CHDIR: usize = 17;
+pub const NR_GETCWD: usize = 18;
+pub const NR_STAT: usize = 19;
+pub const NR_READDIR: usize = 20;
+// 21 and 22 are reserved for `mkdir` and `unlink`, for when the FAT32
+// driver can write.
+pub const NR_PIPE: usize = 23;
+pub const NR_DUP2: usize = 24;
+pub const NR_TRACE: usize = 25;
+pub const NR_KILL: usize = 26;
+pub const NR_SIGACTION: usize = 27;
+pub const NR_SIGRETURN: usize = 28;
+pub const NR_THREAD_CREATE: usize = 29;
+pub const NR_THREAD_EXIT: usize = 30;
+pub const NR_THREAD_JOIN: usize = 31;
+pub const NR_FUTEX_WAIT: usize = 32;
+pub const NR_FUTEX_WAKE: usize = 33;
+pub const NR_SETPRIORITY: usize = 34;
+pub const NR_PROCINFO: usize = 35;
+pub const NR_SCHED_SETATTR: usize = 36;
+
+/// The range of nice values. Lower values mean a higher priority; `0` is the
+/// default.
+pub const NICE_MIN: i64 = -20;
+pub const NICE_MAX: i64 = 19;
+
+/// `SchedAttr::policy` values.
+pub const SCHED_NORMAL: u64 = 0;
+pub const SCHED_DEADLINE: u64 = 1;
+
+/// `ProcInfo::state` values, which also index `ProcInfo::transitions`.
+pub const PROC_READY: u64 = 0;
+pub const PROC_RUNNING: u64 = 1;
+pub const PROC_BLOCKED: u64 = 2;
+pub const PROC_STOPPED: u64 = 3;
+pub const PROC_ZOMBIE: u64 = 4;
+pub const PROC_DEAD: u64 = 5;
+pub const PROC_STATES: usize = 6;
+
+/// `mmap`/`mprotect` protection bits.
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// `mmap` flags. All mappings are private; `MAP_ANONYMOUS` mappings are
+/// zero-filled and ignore the file descriptor and offset.
+pub const MAP_PRIVATE: u64 = 1 << 1;
+pub const MAP_FIXED: u64 = 1 << 4;
+pub const MAP_ANONYMOUS: u64 = 1 << 5;
+
+/// `seek` origins.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// `Stat::flags` bits.
+pub const STAT_DIR: u64 = 1 << 0;
+pub const STAT_READ_ONLY: u64 = 1 << 1;
+pub const STAT_HIDDEN: u64 = 1 << 2;
+pub const STAT_CONSOLE: u64 = 1 << 3;
+pub const STAT_PIPE: u64 = 1 << 4;
+
+/// Signal numbers. Signals are numbered from `1` up to `NSIG - 1`; `kill`
+/// with signal `0` only checks that the process exists.
+pub const NSIG: u64 = 32;
+pub const SIGHUP: u64 = 1;
+pub const SIGINT: u64 = 2;
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGABRT: u64 = 6;
+pub const SIGBUS: u64 = 7;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGPIPE: u64 = 13;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+pub const SIGTSTP: u64 = 20;
+
+/// `SigAction::handler` values that are not handler addresses.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// A process terminated by signal `sig` exits with status
+/// `SIGNAL_EXIT_BASE + sig`.
+pub const SIGNAL_EXIT_BASE: u64 = 128;
+
+/// How a process handles a signal, set with `sigaction`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN`, or the address of an `extern "C" fn(u64)` that
+    /// is called with the signal number.
+    pub handler: u64,
+    /// Signals blocked while the handler runs, in addition to the signal
+    /// being handled. Bit `n` stands for signal `n`.
+    pub mask: u64,
+    /// The address the handler returns to. The code there must call
+    /// `sigreturn` without touching the stack.
+    pub restorer: u64,
+}
+
+/// A calendar date and time, as stored by the file system.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Information about a file or directory, filled in by `stat` and `fstat`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stat {
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// A combination of the `STAT_*` bits.
+    pub flags: u64,
+    pub created: DateTime,
+    pub accessed: DateTime,
+    pub modified: DateTime,
+}
+
+/// The scheduling class of a process, set with `sched_setattr`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SchedAttr {
+    /// `SCHED_NORMAL`, or `SCHED_DEADLINE` for a real-time process, which
+    /// runs before every normal process.
+    pub policy: u64,
+    /// For `SCHED_DEADLINE`: the process is guaranteed `budget_us`
+    /// microseconds of CPU time every `period_us` microseconds, by the end
+    /// of each period, and gets no more.
+    pub budget_us: u64,
+    pub period_us: u64,
+}
+
+/// Information about a process or thread, filled in by `procinfo`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProcInfo {
+    pub pid: u64,
+    /// The ID of the parent process, or `0` if it has none.
+    pub ppid: u64,
+    /// The ID of the main thread of its process: `pid` itself, unless it is
+    /// a thread created with `thread_create`.
+    pub tgid: u64,
+    /// One of the `PROC_*` states.
+    pub state: u64,
+    pub nice: i64,
+    /// The core it runs on, or last ran on.
+    pub core: u64,
+    /// Whether it is a kernel thread: `1` if it is, `0` otherwise.
+    pub kernel: u64,
+    /// The CPU time spent running its own code and in the kernel on its
+    /// behalf, in microseconds.
+    pub user_us: u64,
+    pub kernel_us: u64,
+    /// The number of times it was switched to.
+    pub switches: u64,
+    /// The number of pages mapped in its address space.
+    pub pages: u64,
+    /// The number of times it entered each state, indexed by `PROC_*`.
+    pub transitions: [u64; PROC_STATES],
+    /// Its `SCHED_*` policy, and for `SCHED_DEADLINE` its budget and period
+    /// in microseconds.
+    pub policy: u64,
+    pub budget_us: u64,
+    pub period_us: u64,
+    /// For `SCHED_DEADLINE`: the number of periods that ended before it got
+    /// its budget while it was ready to run.
+    pub deadline_misses: u64,
+}
+
+/// The longest directory entry name `readdir` returns, in bytes. Longer names
+/// are truncated.
+pub const NAME_MAX: usize = 255;
+
+/// A directory entry returned by `readdir`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub name_len: u64,
+    pub name: [u8; NAME_MAX + 1],
+    pub stat: Stat,
+}
+
+impl DirEntry {
+    /// Returns the name of the entry.
+    pub fn name(&self) -> &str {
+        let len = core::cmp::min(self.name_len as usize, NAME_MAX);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+impl Default for DirEntry {
+    fn default() -> DirEntry {
+        DirEntry {
+            name_len: 0,
+            name: [0; NAME_MAX + 1],
+            stat: Stat::default(),
+        }
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("name", &self.name())
+            .field("stat", &self.stat)
+            .finish()
+    }
+}
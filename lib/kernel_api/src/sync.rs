@@ -0,0 +1,164 @@
+//! Blocking synchronization primitives for user programs, built on
+//! `futex_wait` and `futex_wake`.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::syscall::{futex_wait, futex_wake};
+
+/// Wakes every waiter of a futex.
+const WAKE_ALL: usize = core::u32::MAX as usize;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and other threads may be blocked waiting for the lock.
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock whose waiters block in the kernel instead of
+/// spinning.
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// A guard that releases its `Mutex` when dropped.
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Returns a new, unlocked mutex protecting `val`.
+    pub const fn new(val: T) -> Mutex<T> {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Acquires the lock, blocking until it is available.
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) != UNLOCKED {
+            // Mark the lock contended so that the owner wakes us on unlock.
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = futex_wait(&self.state, CONTENDED, None);
+            }
+        }
+        MutexGuard { lock: self }
+    }
+
+    /// Acquires the lock if it is available.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        match self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) {
+            UNLOCKED => Some(MutexGuard { lock: self }),
+            _ => None,
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock()
+    }
+}
+
+/// A condition variable, used with a `Mutex` to wait for a condition.
+pub struct Condvar {
+    /// Incremented on every notification, so that a waiter that released
+    /// the mutex does not miss a notification sent before it blocked.
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    /// Returns a new condition variable.
+    pub const fn new() -> Condvar {
+        Condvar { seq: AtomicU32::new(0) }
+    }
+
+    /// Releases the mutex of `guard`, blocks until the condition variable is
+    /// notified and acquires the mutex again. Wake-ups may be spurious, so
+    /// callers must check their condition again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let lock = guard.lock;
+        drop(guard);
+        let _ = futex_wait(&self.seq, seq, None);
+        lock.lock()
+    }
+
+    /// Wakes one thread blocked in `wait()`.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        let _ = futex_wake(&self.seq, 1);
+    }
+
+    /// Wakes every thread blocked in `wait()`.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        let _ = futex_wake(&self.seq, WAKE_ALL);
+    }
+}
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const COMPLETE: u32 = 2;
+
+/// Runs a one-time initialization exactly once, even if several threads
+/// attempt it at the same time.
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    /// Returns a `Once` that has not run yet.
+    pub const fn new() -> Once {
+        Once { state: AtomicU32::new(INCOMPLETE) }
+    }
+
+    /// Calls `f` if no call to `call_once()` has yet. Other threads calling
+    /// `call_once()` meanwhile block until `f` has returned.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            return;
+        }
+
+        if self.state.compare_and_swap(INCOMPLETE, RUNNING, Ordering::Acquire) == INCOMPLETE {
+            f();
+            self.state.store(COMPLETE, Ordering::Release);
+            let _ = futex_wake(&self.state, WAKE_ALL);
+            return;
+        }
+
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            let _ = futex_wait(&self.state, RUNNING, None);
+        }
+    }
+
+    /// Returns `true` if a call to `call_once()` has completed.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
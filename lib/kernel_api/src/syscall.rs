@@ -1,7 +1,10 @@
 use core::fmt;
 use core::fmt::Write;
+use core::sync::atomic::AtomicU32;
 use core::time::Duration;
 
+use shim::io;
+
 use crate::*;
 
 macro_rules! err_or {
@@ -55,26 +58,738 @@ pub fn time() -> OsResult<Duration> {
     err_or!(ecode, Duration::from_millis(elapsed_ms))
 }
 
-pub fn exit() -> ! {
+pub fn exit(status: u64) -> ! {
     unsafe {
-        asm!("svc $0"
+        asm!("mov x0, $0
+              svc $1"
              : // no output reg
-             : "i"(NR_EXIT)
-             :
+             : "r"(status), "i"(NR_EXIT)
+             : "x0"
              : "volatile");
     }
     loop {}
 }
 
-pub fn write(b: u8) {
+/// Waits for the child process `pid` to exit and returns its (ID, exit
+/// status). A `pid` of `0` waits for any child.
+pub fn waitpid(pid: u64) -> OsResult<(u64, u64)> {
+    let mut ecode: u64;
+    let mut id: u64;
+    let mut status: u64;
+
+    unsafe {
+        asm!("mov x0, $3
+              svc $4
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+             : "=r"(id), "=r"(status), "=r"(ecode)
+             : "r"(pid), "i"(NR_WAIT)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, (id, status))
+}
+
+/// Waits for any child process to exit and returns its (ID, exit status).
+pub fn wait() -> OsResult<(u64, u64)> {
+    waitpid(0)
+}
+
+/// Opens the file or directory at `path` for reading and returns its file
+/// descriptor. Relative paths are resolved against the working directory.
+pub fn open(path: &str) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut fd: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc $4
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(fd), "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "i"(NR_OPEN)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, fd)
+}
+
+/// Reads bytes from the file descriptor `fd` into `buf` and returns how many
+/// were read. `0` means end of file.
+pub fn read(fd: u64, buf: &mut [u8]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut read: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(read), "=r"(ecode)
+             : "r"(fd), "r"(buf.as_mut_ptr()), "r"(buf.len()), "i"(NR_READ)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, read as usize)
+}
+
+/// Writes bytes from `buf` to the file descriptor `fd` and returns how many
+/// were written, which may be fewer than `buf.len()`.
+pub fn write(fd: u64, buf: &[u8]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut written: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(written), "=r"(ecode)
+             : "r"(fd), "r"(buf.as_ptr()), "r"(buf.len()), "i"(NR_WRITE)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, written as usize)
+}
+
+/// Closes the file descriptor `fd`.
+pub fn close(fd: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              svc $2
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(fd), "i"(NR_CLOSE)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Moves the offset of the file descriptor `fd` to `offset` relative to
+/// `whence` (`SEEK_SET`, `SEEK_CUR` or `SEEK_END`) and returns the new offset.
+pub fn seek(fd: u64, offset: i64, whence: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut pos: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(pos), "=r"(ecode)
+             : "r"(fd), "r"(offset), "r"(whence), "i"(NR_SEEK)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, pos)
+}
+
+/// Returns information about the file descriptor `fd`.
+pub fn fstat(fd: u64) -> OsResult<Stat> {
+    let mut ecode: u64;
+    let mut stat = Stat::default();
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(fd), "r"(&mut stat as *mut Stat), "i"(NR_FSTAT)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, stat)
+}
+
+/// Returns information about the file or directory at `path`.
+pub fn stat(path: &str) -> OsResult<Stat> {
+    let mut ecode: u64;
+    let mut stat = Stat::default();
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "r"(&mut stat as *mut Stat), "i"(NR_STAT)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, stat)
+}
+
+/// Reads the next entries of the directory open as `fd` into `entries` and
+/// returns how many were read. `0` means the end of the directory.
+pub fn readdir(fd: u64, entries: &mut [DirEntry]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut count: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(count), "=r"(ecode)
+             : "r"(fd), "r"(entries.as_mut_ptr()), "r"(entries.len()), "i"(NR_READDIR)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+/// Changes the working directory to `path`.
+pub fn chdir(path: &str) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "i"(NR_CHDIR)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Copies the absolute path of the working directory into `buf` and returns
+/// it.
+pub fn getcwd(buf: &mut [u8]) -> OsResult<&str> {
+    let mut ecode: u64;
+    let mut len: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc $4
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(len), "=r"(ecode)
+             : "r"(buf.as_mut_ptr()), "r"(buf.len()), "i"(NR_GETCWD)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    let len = err_or!(ecode, len as usize)?;
+    core::str::from_utf8(&buf[..len]).map_err(|_| OsError::IoErrorInvalidData)
+}
+
+/// Creates a pipe and returns the file descriptors of its (read end, write
+/// end).
+pub fn pipe() -> OsResult<(u64, u64)> {
+    let mut ecode: u64;
+    let mut read_fd: u64;
+    let mut write_fd: u64;
+
+    unsafe {
+        asm!("svc $3
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+             : "=r"(read_fd), "=r"(write_fd), "=r"(ecode)
+             : "i"(NR_PIPE)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, (read_fd, write_fd))
+}
+
+/// Makes the file descriptor `new` refer to what `old` refers to, closing
+/// `new` first if it was open.
+pub fn dup2(old: u64, new: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(old), "r"(new), "i"(NR_DUP2)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Turns system call tracing of process `pid` (`0` for the caller) on or off.
+/// Traced calls are logged to the kernel console.
+pub fn trace(pid: u64, on: bool) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(pid), "r"(on as u64), "i"(NR_TRACE)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Sends signal `sig` to process `pid`. Signal `0` only checks that the
+/// process exists. Fails with `OsError::NoAccess` unless `pid` is the caller
+/// or one of its children.
+pub fn kill(pid: u64, sig: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(pid), "r"(sig), "i"(NR_KILL)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Sets how signal `sig` is handled to `act`, if given, and returns the
+/// previous action.
+///
+/// A handler installed with a raw `SigAction` must come with a `restorer`
+/// that calls `sigreturn`; `signal()` takes care of that.
+pub fn sigaction(sig: u64, act: Option<&SigAction>) -> OsResult<SigAction> {
+    let mut ecode: u64;
+    let mut old = SigAction::default();
+    let act = act.map(|a| a as *const SigAction as u64).unwrap_or(0);
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(sig), "r"(act), "r"(&mut old as *mut SigAction), "i"(NR_SIGACTION)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, old)
+}
+
+/// Calls `handler` with the signal number whenever signal `sig` is
+/// delivered, and returns the previous action.
+pub fn signal(sig: u64, handler: extern "C" fn(u64)) -> OsResult<SigAction> {
+    let act = SigAction {
+        handler: handler as u64,
+        mask: 0,
+        restorer: sigreturn as u64,
+    };
+    sigaction(sig, Some(&act))
+}
+
+/// Returns from a signal handler to the code it interrupted.
+///
+/// The kernel finds the saved state at the stack pointer, so this must be
+/// entered with the stack exactly as the kernel left it for the handler; it
+/// is naked to keep the compiler from pushing a frame first.
+#[naked]
+pub extern "C" fn sigreturn() -> ! {
+    unsafe {
+        asm!("svc $0" :: "i"(NR_SIGRETURN) :: "volatile");
+    }
+    loop {}
+}
+
+/// Starts a thread that calls `f(arg)` on a stack of its own and exits with
+/// the value `f` returns. Returns the ID of the new thread.
+pub fn thread_create(f: extern "C" fn(u64) -> u64, arg: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut tid: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc $5
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(tid), "=r"(ecode)
+             : "r"(thread_start as u64), "r"(f as u64), "r"(arg), "i"(NR_THREAD_CREATE)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, tid)
+}
+
+/// The first code a thread started by `thread_create` runs.
+extern "C" fn thread_start(f: extern "C" fn(u64) -> u64, arg: u64) -> ! {
+    thread_exit(f(arg))
+}
+
+/// Terminates the calling thread with exit status `status`. Called from the
+/// main thread, this exits the whole process.
+pub fn thread_exit(status: u64) -> ! {
     unsafe {
         asm!("mov x0, $0
               svc $1"
-             : // no output reg
-             : "r"(b), "i"(NR_WRITE)
+             :: "r"(status), "i"(NR_THREAD_EXIT)
              : "x0"
              : "volatile");
     }
+    loop {}
+}
+
+/// Waits for thread `tid`, which must have been created by the calling
+/// thread, to exit and returns its exit status.
+pub fn thread_join(tid: u64) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut status: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              svc $3
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(status), "=r"(ecode)
+             : "r"(tid), "i"(NR_THREAD_JOIN)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, status)
+}
+
+/// Blocks until `futex_wake` is called on `futex`, if it still holds
+/// `expected`, or until `timeout` (if given) expires.
+///
+/// Fails with `OsError::WouldBlock` if `futex` does not hold `expected`,
+/// `OsError::IoErrorTimedOut` on timeout and `OsError::Interrupted` if a
+/// signal arrived. Wake-ups may also be spurious, so callers must check the
+/// condition they wait for again.
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> OsResult<()> {
+    let mut ecode: u64;
+    let ms = timeout.map(|t| core::cmp::max(t.as_millis() as u64, 1)).unwrap_or(0);
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(futex as *const AtomicU32), "r"(expected as u64), "r"(ms), "i"(NR_FUTEX_WAIT)
+             : "x0", "x1", "x2", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Wakes up to `n` threads blocked in `futex_wait` on `futex` and returns how
+/// many were woken.
+pub fn futex_wake(futex: &AtomicU32, n: usize) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut woken: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc $4
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(woken), "=r"(ecode)
+             : "r"(futex as *const AtomicU32), "r"(n), "i"(NR_FUTEX_WAKE)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, woken as usize)
+}
+
+/// Sets the nice value of process `pid` (`0` for the caller) to `nice`, which
+/// is clamped to `NICE_MIN..=NICE_MAX`. Lower values get a larger share of
+/// the CPU.
+pub fn setpriority(pid: u64, nice: i64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(pid), "r"(nice), "i"(NR_SETPRIORITY)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Fills `infos` with information about the processes and threads in the
+/// system, by ID, and returns how many entries were filled in. Processes
+/// that do not fit are left out.
+pub fn procinfo(infos: &mut [ProcInfo]) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut count: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc $4
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(count), "=r"(ecode)
+             : "r"(infos.as_mut_ptr()), "r"(infos.len()), "i"(NR_PROCINFO)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, count as usize)
+}
+
+/// Sets the scheduling class of process `pid` (`0` for the caller) to
+/// `attr`.
+///
+/// A `SCHED_DEADLINE` process is admitted only if its reservation fits on a
+/// core next to the real-time processes already there; otherwise this fails
+/// with `OsError::Busy`.
+pub fn sched_setattr(pid: u64, attr: &SchedAttr) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(pid), "r"(attr as *const SchedAttr), "i"(NR_SCHED_SETATTR)
+             : "x0", "x1", "x7", "memory"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// An open file descriptor, closed when dropped.
+pub struct File {
+    fd: u64,
+}
+
+impl File {
+    /// Opens the file or directory at `path` for reading.
+    pub fn open(path: &str) -> OsResult<File> {
+        open(path).map(|fd| File { fd })
+    }
+
+    /// Wraps the already open file descriptor `fd`. It is closed when the
+    /// returned `File` is dropped.
+    pub fn from_raw(fd: u64) -> File {
+        File { fd }
+    }
+
+    /// Returns the file descriptor number.
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// Returns information about the file.
+    pub fn stat(&self) -> OsResult<Stat> {
+        fstat(self.fd)
+    }
+
+    /// Reads the next entries of a directory into `entries`. See `readdir()`.
+    pub fn read_dir(&mut self, entries: &mut [DirEntry]) -> OsResult<usize> {
+        readdir(self.fd, entries)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+impl io::Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(read(self.fd, buf)?)
+    }
+}
+
+impl io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(write(self.fd, buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for File {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            io::SeekFrom::Start(offset) => (offset as i64, SEEK_SET),
+            io::SeekFrom::Current(offset) => (offset, SEEK_CUR),
+            io::SeekFrom::End(offset) => (offset, SEEK_END),
+        };
+        Ok(seek(self.fd, offset, whence)?)
+    }
+}
+
+/// Starts the program at `path` as a child of the calling process and
+/// returns the child's process ID. The child inherits the caller's working
+/// directory and file descriptors, so a pipe end installed as the caller's
+/// standard input or output with `dup2` before the call becomes the child's.
+pub fn spawn(path: &str) -> OsResult<u64> {
+    let mut ecode: u64;
+    let mut pid: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc $4
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(pid), "=r"(ecode)
+             : "r"(path.as_ptr()), "r"(path.len()), "i"(NR_SPAWN)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, pid)
+}
+
+/// Moves the program break to `addr` and returns the new break. Passing `0`
+/// returns the current break without changing it.
+pub fn brk(addr: usize) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut brk: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              svc $3
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(brk), "=r"(ecode)
+             : "r"(addr), "i"(NR_BRK)
+             : "x0", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, brk as usize)
+}
+
+/// Grows (or shrinks, if `incr` is negative) the heap by `incr` bytes and
+/// returns the previous program break, which is the start of the new memory.
+pub fn sbrk(incr: isize) -> OsResult<usize> {
+    let old = brk(0)?;
+    if incr == 0 {
+        return Ok(old);
+    }
+
+    let new = if incr > 0 {
+        old.checked_add(incr as usize)
+    } else {
+        old.checked_sub(incr.wrapping_neg() as usize)
+    };
+    brk(new.ok_or(OsError::InvalidArgument)?)?;
+    Ok(old)
+}
+
+/// Maps `len` bytes of memory with protection `prot` and returns the address
+/// of the mapping. `addr` is a hint unless `MAP_FIXED` is given. Unless
+/// `flags` contains `MAP_ANONYMOUS`, the mapping is a private copy of the file
+/// open as `fd`, starting at `offset`.
+pub fn mmap(addr: usize, len: usize, prot: u64, flags: u64, fd: u64, offset: u64) -> OsResult<usize> {
+    let mut ecode: u64;
+    let mut mapped: u64;
+
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              mov x3, $5
+              mov x4, $6
+              mov x5, $7
+              svc $8
+              mov $0, x0
+              mov $1, x7"
+             : "=r"(mapped), "=r"(ecode)
+             : "r"(addr), "r"(len), "r"(prot), "r"(flags), "r"(fd), "r"(offset), "i"(NR_MMAP)
+             : "x0", "x1", "x2", "x3", "x4", "x5", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, mapped as usize)
+}
+
+/// Unmaps the pages in `[addr, addr + len)`.
+pub fn munmap(addr: usize, len: usize) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc $3
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(addr), "r"(len), "i"(NR_MUNMAP)
+             : "x0", "x1", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
+}
+
+/// Changes the protection of the mapped pages in `[addr, addr + len)`.
+pub fn mprotect(addr: usize, len: usize, prot: u64) -> OsResult<()> {
+    let mut ecode: u64;
+
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc $4
+              mov $0, x7"
+             : "=r"(ecode)
+             : "r"(addr), "r"(len), "r"(prot), "i"(NR_MPROTECT)
+             : "x0", "x1", "x2", "x7"
+             : "volatile");
+    }
+
+    err_or!(ecode, ())
 }
 
 pub fn getpid() -> OsResult<u64> {
@@ -100,8 +815,12 @@ struct Console;
 
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for b in s.bytes() {
-            write(b);
+        let mut buf = s.as_bytes();
+        while !buf.is_empty() {
+            match write(1, buf) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(n) => buf = &buf[n..],
+            }
         }
         Ok(())
     }
@@ -1,6 +1,10 @@
 /// The address where I/O peripherals are mapped to.
 pub const IO_BASE: usize = 0x3F000000;
-pub const IO_BASE_END: usize = 0x40000000;
+/// The end of the I/O peripherals, including the local peripherals.
+pub const IO_BASE_END: usize = LOCAL_IO_BASE + 0x10000;
+
+/// The address where the per-core (BCM2836 local) peripherals are mapped to.
+pub const LOCAL_IO_BASE: usize = 0x40000000;
 
 /// The base address of the `GPIO` registers
 pub const GPIO_BASE: usize = IO_BASE + 0x200000;
@@ -0,0 +1,116 @@
+use crate::common::{LOCAL_IO_BASE, NCORES};
+
+use volatile::prelude::*;
+use volatile::{ReadVolatile, Reserved, Volatile, WriteVolatile};
+
+/// The per-core interrupt sources of the BCM2836 local peripherals, in the
+/// order of the bits of the core IRQ source registers.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LocalInterrupt {
+    CntPsIrq = 0,
+    CntPnsIrq = 1,
+    CntHpIrq = 2,
+    CntVIrq = 3,
+    Mailbox0 = 4,
+    Mailbox1 = 5,
+    Mailbox2 = 6,
+    Mailbox3 = 7,
+    Gpu = 8,
+    Pmu = 9,
+    AxiOutstanding = 10,
+    LocalTimer = 11,
+}
+
+impl LocalInterrupt {
+    pub const MAX: usize = 12;
+
+    pub fn iter() -> core::slice::Iter<'static, LocalInterrupt> {
+        use LocalInterrupt::*;
+        [
+            CntPsIrq, CntPnsIrq, CntHpIrq, CntVIrq, Mailbox0, Mailbox1, Mailbox2, Mailbox3, Gpu, Pmu,
+            AxiOutstanding, LocalTimer,
+        ]
+        .into_iter()
+    }
+
+    pub fn to_index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// ref: QA7 "ARM Quad A7 core", rev 3.4, section 4
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    CONTROL: Volatile<u32>,
+    _r0: Reserved<u32>,
+    CORE_TIMER_PRESCALER: Volatile<u32>,
+    GPU_INT_ROUTING: Volatile<u32>,
+    PMU_INT_ROUTING_SET: Volatile<u32>,
+    PMU_INT_ROUTING_CLR: Volatile<u32>,
+    _r1: Reserved<u32>,
+    CORE_TIMER_LS: ReadVolatile<u32>,
+    CORE_TIMER_MS: ReadVolatile<u32>,
+    LOCAL_INT_ROUTING: Volatile<u32>,
+    _r2: Reserved<u32>,
+    AXI_OUTSTANDING_COUNTERS: Volatile<u32>,
+    AXI_OUTSTANDING_IRQ: Volatile<u32>,
+    LOCAL_TIMER_CONTROL: Volatile<u32>,
+    LOCAL_TIMER_FLAGS: Volatile<u32>,
+    _r3: Reserved<u32>,
+    CORE_TIMER_INT_CONTROL: [Volatile<u32>; NCORES],
+    CORE_MAILBOX_INT_CONTROL: [Volatile<u32>; NCORES],
+    CORE_IRQ_SOURCE: [ReadVolatile<u32>; NCORES],
+    CORE_FIQ_SOURCE: [ReadVolatile<u32>; NCORES],
+    CORE_MAILBOX_SET: [[WriteVolatile<u32>; 4]; NCORES],
+    CORE_MAILBOX_CLEAR: [[Volatile<u32>; 4]; NCORES],
+}
+
+/// The interrupt controller of one core. Used to route the core's generic
+/// timer and mailboxes to its IRQ line, to check which local interrupts are
+/// pending and to send inter-processor interrupts.
+pub struct LocalController {
+    core: usize,
+    registers: &'static mut Registers,
+}
+
+impl LocalController {
+    /// Returns a new handle to the interrupt controller of `core`.
+    pub fn new(core: usize) -> LocalController {
+        assert!(core < NCORES, "no such core: {}", core);
+        LocalController {
+            core,
+            registers: unsafe { &mut *(LOCAL_IO_BASE as *mut Registers) },
+        }
+    }
+
+    /// Routes the non-secure physical timer (`CNTP_*_EL0`) of this core to
+    /// its IRQ line.
+    pub fn enable_local_timer(&mut self) {
+        self.registers.CORE_TIMER_INT_CONTROL[self.core].or_mask(1 << LocalInterrupt::CntPnsIrq as u32);
+    }
+
+    /// Routes `mailbox` of this core to its IRQ line.
+    pub fn enable_mailbox(&mut self, mailbox: usize) {
+        self.registers.CORE_MAILBOX_INT_CONTROL[self.core].or_mask(1 << mailbox);
+    }
+
+    /// Returns `true` if `int` is pending on this core.
+    pub fn is_pending(&self, int: LocalInterrupt) -> bool {
+        self.registers.CORE_IRQ_SOURCE[self.core].has_mask(1 << int as u32)
+    }
+
+    /// Sets `bits` in `mailbox` of `core`, raising an interrupt on `core` if
+    /// it enabled the mailbox.
+    pub fn send(&mut self, core: usize, mailbox: usize, bits: u32) {
+        self.registers.CORE_MAILBOX_SET[core][mailbox].write(bits);
+    }
+
+    /// Clears every bit in `mailbox` of this core and returns the bits that
+    /// were set.
+    pub fn take_mailbox(&mut self, mailbox: usize) -> u32 {
+        let bits = self.registers.CORE_MAILBOX_CLEAR[self.core][mailbox].read();
+        self.registers.CORE_MAILBOX_CLEAR[self.core][mailbox].write(bits);
+        bits
+    }
+}
@@ -27,6 +27,7 @@ sudo mount $LOP1 $MNT
 
 trap "sudo umount $MNT; rmdir $MNT; sudo losetup -d $LO" EXIT
 
+sudo mkdir -p $MNT/bin
 for d in ${PROGS[@]}; do
-    sudo cp $d/build/$d.bin $MNT/$d
+    sudo cp $d/build/$d.elf $MNT/bin/$d
 done
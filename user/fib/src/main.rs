@@ -4,8 +4,11 @@
 
 mod cr0;
 
+extern crate alloc;
+
+use alloc::vec::Vec;
 use kernel_api::println;
-use kernel_api::syscall::{getpid, time, write};
+use kernel_api::syscall::{getpid, time};
 
 fn fib(n: u64) -> u64 {
     match n {
@@ -35,6 +38,12 @@ fn main() {
     // println!("Started...");
     let v = fib(40);
     println!("v = {}", v);
+
+    let mut seq: Vec<u64> = Vec::new();
+    for n in 0..20 {
+        seq.push(fib(n));
+    }
+    println!("fib(0..20) = {:?}", seq);
     // let rtn = fib(40);
     // println!("Ended: Result = {}", rtn);
 }